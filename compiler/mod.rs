@@ -1,14 +1,102 @@
-mod scanner;
+pub mod scanner;
 pub mod vm;
 
-use self::scanner::{Token, TokenType};
-use self::vm::{Chunk, OpCode, Value, VM};
+use std::borrow::Cow;
+use std::path::Path;
+use std::sync::Arc;
+
+use self::scanner::{Embedded, Line, Token, TokenType};
+use self::vm::{Capture, Chunk, LabelId, OpCode, Value, VM};
+use self::vm::bigint::BigInt;
+use self::vm::intern::intern;
+use self::vm::natives;
+use self::vm::ordered_map::OrderedMap;
 
 pub type SourceCode = Vec<char>;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompilerMode {
+    // Every top-level expression is dropped after it is evaluated
+    Program,
+    // The value of the last top-level expression is kept and returned
+    Repl,
+}
+
+#[derive(Clone, Debug)]
+pub struct CompilerConfig {
+    pub debug: bool,
+    pub optimise: bool,
+    // Applies to the top-level chunk and every function in it
+    pub max_constants: Option<usize>,
+    // Recorded in the functions it defines, for error messages
+    pub file: Option<String>,
+    // Otherwise symbols and keywords are read in lowercase
+    pub case_sensitive: bool,
+    pub mode: CompilerMode,
+}
+
+impl Default for CompilerConfig {
+    fn default() -> CompilerConfig {
+        CompilerConfig{
+            debug: false,
+            optimise: false,
+            max_constants: None,
+            file: None,
+            case_sensitive: true,
+            mode: CompilerMode::Program,
+        }
+    }
+}
+
+impl CompilerConfig {
+    pub fn new() -> CompilerConfig {
+        CompilerConfig::default()
+    }
+
+    pub fn with_debug(mut self, debug: bool) -> CompilerConfig {
+        self.debug = debug;
+        self
+    }
+
+    pub fn with_optimisation(mut self, optimise: bool) -> CompilerConfig {
+        self.optimise = optimise;
+        self
+    }
+
+    pub fn with_max_constants(mut self, max_constants: usize) -> CompilerConfig {
+        self.max_constants = Some(max_constants);
+        self
+    }
+
+    pub fn with_file(mut self, file: &str) -> CompilerConfig {
+        self.file = Some(String::from(file));
+        self
+    }
+
+    pub fn with_case_sensitivity(mut self, case_sensitive: bool) -> CompilerConfig {
+        self.case_sensitive = case_sensitive;
+        self
+    }
+
+    pub fn with_mode(mut self, mode: CompilerMode) -> CompilerConfig {
+        self.mode = mode;
+        self
+    }
+}
+
 pub struct LocalVar {
     name: String,
     depth: usize,
+    // Stack slot relative to the function in slot 0
+    slot: usize,
+}
+
+// The innermost `while` being compiled, for `break` and `continue`
+struct LoopContext {
+    start: LabelId,
+    end: LabelId,
+    // Stack depth at the start of the body
+    stack_depth: usize,
 }
 
 pub struct Compiler {
@@ -17,6 +105,20 @@ pub struct Compiler {
     scope_depth: usize,
     sexp_depth: usize,
     is_main: bool,
+    // Values on the stack above the function in slot 0
+    stack_depth: usize,
+    module: Option<String>,
+    // Names defined in the current module so far
+    module_names: Vec<String>,
+    // The file being compiled, changes while compiling required modules
+    file: Option<String>,
+    loop_stack: Vec<LoopContext>,
+    // Locals of the functions around this one, which it can capture
+    enclosing: Vec<String>,
+    // Names captured so far, indexed like the closure's upvalues
+    upvalues: Vec<String>,
+    // Required modules are read like the source requiring them
+    case_sensitive: bool,
 }
 
 fn advance(tokens: &Vec<Token>, offset: &mut usize) -> Result<(), String> {
@@ -28,6 +130,107 @@ fn advance(tokens: &Vec<Token>, offset: &mut usize) -> Result<(), String> {
     }
 }
 
+// Writes an op and keeps track of how it changes the stack
+fn emit(compiler: &mut Compiler, op: OpCode, line: Line) {
+    let depth = compiler.stack_depth as isize + op.stack_effect();
+    compiler.stack_depth = depth as usize;
+    compiler.chunk.write_code(op, line);
+}
+
+// Returns the offset just past the datum starting at offset
+fn datum_end(tokens: &Vec<Token>, offset: usize) -> usize {
+    match tokens[offset].token_type {
+        TokenType::EOF => offset,
+        // The commented datum is skipped, then the actual one
+        TokenType::DatumComment => datum_end(tokens, datum_end(tokens, offset + 1)),
+        TokenType::Quote
+            | TokenType::Quasiquote
+            | TokenType::Unquote
+            | TokenType::UnquoteSplicing => datum_end(tokens, offset + 1),
+        TokenType::OpenParenthesis | TokenType::OpenBracket | TokenType::OpenBrace => {
+            let close = match tokens[offset].token_type {
+                TokenType::OpenBracket => TokenType::CloseBracket,
+                TokenType::OpenBrace => TokenType::CloseBrace,
+                _ => TokenType::CloseParenthesis,
+            };
+            let mut end = offset + 1;
+            while tokens[end].token_type != close
+                && tokens[end].token_type != TokenType::EOF {
+                end = datum_end(tokens, end);
+            }
+            if tokens[end].token_type == TokenType::EOF {
+                end
+            } else {
+                end + 1
+            }
+        }
+        _ => offset + 1,
+    }
+}
+
+// Drops `#_` datum comments together with the datum they comment out, so the
+// compiler never sees them
+fn strip_datum_comments(tokens: Vec<Token>) -> Vec<Token> {
+    let mut keep = vec![true; tokens.len()];
+    let mut offset = 0;
+    while offset < tokens.len() {
+        if tokens[offset].token_type == TokenType::DatumComment {
+            let end = datum_end(&tokens, offset + 1);
+            for i in offset..end {
+                keep[i] = false;
+            }
+            offset = end;
+        } else {
+            offset += 1;
+        }
+    }
+    tokens.into_iter()
+        .zip(keep)
+        .filter(|(_, k)| *k)
+        .map(|(t, _)| t)
+        .collect()
+}
+
+fn read_tokens(source: &SourceCode, debug: bool) -> Vec<Token> {
+    strip_datum_comments(scanner::scan(source, debug))
+}
+
+// Without case sensitivity the symbols and keywords in the source are
+// lowercased before reading it, which copies borrowed source. Symbols in
+// pipes are kept as they are written. Each character
+// is only replaced when its lowercase form is a single character, so tokens
+// stay where they are.
+fn read_tokens_with_case(source: &mut Cow<SourceCode>, debug: bool, case_sensitive: bool) -> Vec<Token> {
+    if case_sensitive {
+        return read_tokens(source, debug)
+    }
+    for token in scanner::scan(source, false) {
+        if token.token_type != TokenType::Symbol && token.token_type != TokenType::Keyword
+            || source[token.start] == '|' {
+            continue
+        }
+        for c in &mut source.to_mut()[token.start..token.start + token.length] {
+            let mut lower = c.to_lowercase();
+            if lower.len() == 1 {
+                *c = lower.next().unwrap();
+            }
+        }
+    }
+    // `NIL` and `TRUE` are only literals once lowercased
+    read_tokens(source, debug)
+}
+
+// Prefixes a definition with the current module, if any
+fn qualify_name(compiler: &mut Compiler, name: String) -> String {
+    match &compiler.module {
+        Some(module) => {
+            compiler.module_names.append(&mut vec![name.clone()]);
+            format!("{}/{}", module, name)
+        }
+        None => name,
+    }
+}
+
 fn do_expressions(compiler: &mut Compiler,
                   tokens: &Vec<Token>,
                   offset: &mut usize,
@@ -39,7 +242,7 @@ fn do_expressions(compiler: &mut Compiler,
         while tokens[*offset].token_type != TokenType::CloseParenthesis {
             // Pop all but the last value off the stack again
             let token = &tokens[*offset];
-            compiler.chunk.write_code(OpCode::Pop, token.line);
+            emit(compiler, OpCode::Pop, token.line);
             try!(expression(compiler, tokens, offset, source));
         }
     }
@@ -55,14 +258,91 @@ fn compile_def(compiler: &mut Compiler,
     // `def` needs to read ahead because the first arg is a raw symbol
     try!(advance(tokens, offset));
     let next_token = &tokens[*offset];
-    if next_token.token_type != TokenType::Symbol {
-        return Err(String::from("Expected symbol for def"));
+    match next_token.token_type {
+        TokenType::OpenBracket | TokenType::OpenBrace =>
+            return compile_destructuring_def(compiler, tokens, offset, source, token.line),
+        TokenType::Symbol => (),
+        _ => return Err(String::from("Expected symbol for def")),
+    }
+    let sym = qualify_name(compiler, next_token.get_token(source));
+    try!(advance(tokens, offset));
+    try!(expression(compiler, tokens, offset, source));
+    let idx = compiler.chunk.write_constant(Value::Symbol(intern(&sym)));
+    emit(compiler, OpCode::DefineGlobal(idx), token.line);
+    Ok(())
+}
+
+// `(def [a b] [1 2])` defines a global for every position of a vector, and
+// `(def {:keys [a b]} {:a 1 :b 2})` one for every key of a map, evaluating to
+// the destructured value
+fn compile_destructuring_def(compiler: &mut Compiler,
+                             tokens: &Vec<Token>,
+                             offset: &mut usize,
+                             source: &SourceCode,
+                             line: Line)
+                             -> Result<(), String> {
+    // The names to define with the keys to look them up by
+    let mut bindings = vec![];
+    if tokens[*offset].token_type == TokenType::OpenBrace {
+        try!(advance(tokens, offset));
+        let keys_token = &tokens[*offset];
+        if keys_token.token_type != TokenType::Keyword || keys_token.get_token(source) != ":keys" {
+            return Err(format!("Map destructuring expects :keys in line {}", keys_token.line))
+        }
+        try!(advance(tokens, offset));
+        try!(consume_token(tokens, offset, &TokenType::OpenBracket));
+        while tokens[*offset].token_type != TokenType::CloseBracket {
+            let name = try!(symbol_token(tokens, *offset, source, "Destructured name"));
+            bindings.push((name.clone(), Value::Keyword(name)));
+            try!(advance(tokens, offset));
+        }
+        try!(consume_token(tokens, offset, &TokenType::CloseBracket));
+        try!(consume_token(tokens, offset, &TokenType::CloseBrace));
+    } else {
+        try!(consume_token(tokens, offset, &TokenType::OpenBracket));
+        while tokens[*offset].token_type != TokenType::CloseBracket {
+            let name = try!(symbol_token(tokens, *offset, source, "Destructured name"));
+            bindings.push((name, Value::Int(bindings.len() as i64)));
+            try!(advance(tokens, offset));
+        }
+        try!(consume_token(tokens, offset, &TokenType::CloseBracket));
+    }
+    // Evaluate the value once and look every name up in it
+    try!(expression(compiler, tokens, offset, source));
+    let slot = compiler.stack_depth - 1;
+    let get = natives::find_native("get").unwrap();
+    for (name, key) in bindings {
+        emit(compiler, OpCode::GetLocal(slot), line);
+        let key_idx = compiler.chunk.write_constant(key);
+        emit(compiler, OpCode::Constant(key_idx), line);
+        emit(compiler, OpCode::CallNative(get, 2), line);
+        let name = qualify_name(compiler, name);
+        let name_idx = compiler.chunk.write_constant(Value::Symbol(intern(&name)));
+        emit(compiler, OpCode::DefineGlobal(name_idx), line);
+        emit(compiler, OpCode::Pop, line);
+    }
+    Ok(())
+}
+
+// `(def-alias new existing)` defines new as another name for existing, which
+// may also be a builtin like `map` or `+`
+fn compile_def_alias(compiler: &mut Compiler,
+                     tokens: &Vec<Token>,
+                     offset: &mut usize,
+                     source: &SourceCode)
+                     -> Result<(), String> {
+    let token = &tokens[*offset];
+    try!(advance(tokens, offset));
+    let name_token = &tokens[*offset];
+    if name_token.token_type != TokenType::Symbol {
+        return Err(String::from("Expected symbol for def-alias"));
     }
-    let sym = next_token.get_token(source);
+    let name = name_token.get_token(source);
     try!(advance(tokens, offset));
     try!(expression(compiler, tokens, offset, source));
-    let idx = compiler.chunk.write_constant(Value::Symbol(sym));
-    compiler.chunk.write_code(OpCode::DefineGlobal(idx), token.line);
+    let sym = qualify_name(compiler, name);
+    let idx = compiler.chunk.write_constant(Value::Symbol(intern(&sym)));
+    emit(compiler, OpCode::DefineGlobal(idx), token.line);
     Ok(())
 }
 
@@ -88,25 +368,82 @@ fn compile_let(compiler: &mut Compiler,
         compiler.locals.append(&mut vec![LocalVar{
             name: name.to_string(),
             depth: compiler.scope_depth,
+            slot: compiler.stack_depth - 1,
         }]);
         try!(consume_token(tokens, offset, &TokenType::CloseParenthesis));
     }
     try!(consume_token(tokens, offset, &TokenType::CloseParenthesis));
     // Eval the inner expressions
     try!(do_expressions(compiler, tokens, offset, source));
-    // Zap the local scope off the stack when it ends
+    end_scope(compiler, token.line);
+    Ok(())
+}
+
+// Zaps the locals of the current scope off the stack, keeping the value on
+// top of the stack
+fn end_scope(compiler: &mut Compiler, line: Line) {
     compiler.scope_depth -= 1;
-    let local_count = compiler.locals.len();
-    for i in 0..local_count {
-        let idx = local_count - i - 1;
-        let l = &compiler.locals[idx];
+    let mut first_slot = None;
+    while let Some(l) = compiler.locals.last() {
         if compiler.scope_depth < l.depth {
+            first_slot = Some(l.slot);
             compiler.locals.pop();
-            compiler.chunk.write_code(OpCode::Zap(idx), token.line);
         } else {
             break
         }
     }
+    if let Some(slot) = first_slot {
+        // Move the result into the slot of the first local, then drop the rest
+        emit(compiler, OpCode::SetLocal(slot), line);
+        let rest = compiler.stack_depth - slot - 1;
+        if 0 < rest {
+            emit(compiler, OpCode::PopN(rest), line);
+        }
+    }
+}
+
+fn compile_with_string_builder(compiler: &mut Compiler,
+                               tokens: &Vec<Token>,
+                               offset: &mut usize,
+                               source: &SourceCode)
+                               -> Result<(), String> {
+    let token = &tokens[*offset];
+    try!(advance(tokens, offset));
+    // The binding can be written as [sb] or (sb)
+    let close_type = match tokens[*offset].token_type {
+        TokenType::OpenBracket => TokenType::CloseBracket,
+        _ => TokenType::CloseParenthesis,
+    };
+    if close_type == TokenType::CloseBracket {
+        try!(advance(tokens, offset));
+    } else {
+        try!(consume_token(tokens, offset, &TokenType::OpenParenthesis));
+    }
+    let binding_token = &tokens[*offset];
+    if binding_token.token_type != TokenType::Symbol {
+        return Err(format!("String builder binding must be a symbol, got {}", binding_token.token_type));
+    }
+    try!(advance(tokens, offset));
+    try!(consume_token(tokens, offset, &close_type));
+    // Bind a fresh builder as a local
+    compiler.scope_depth += 1;
+    let make_idx = natives::find_native("make-string-builder").unwrap();
+    emit(compiler, OpCode::CallNative(make_idx, 0), token.line);
+    let sb_slot = compiler.stack_depth - 1;
+    compiler.locals.append(&mut vec![LocalVar{
+        name: binding_token.get_token(source),
+        depth: compiler.scope_depth,
+        slot: sb_slot,
+    }]);
+    // Eval the body for its side effects only
+    if tokens[*offset].token_type != TokenType::CloseParenthesis {
+        try!(do_expressions(compiler, tokens, offset, source));
+        emit(compiler, OpCode::Pop, token.line);
+    }
+    let build_idx = natives::find_native("sb-build").unwrap();
+    emit(compiler, OpCode::GetLocal(sb_slot), token.line);
+    emit(compiler, OpCode::CallNative(build_idx, 1), token.line);
+    end_scope(compiler, token.line);
     Ok(())
 }
 
@@ -117,17 +454,22 @@ fn compile_when(compiler: &mut Compiler,
                 -> Result<(), String> {
     let token = &tokens[*offset];
     try!(advance(tokens, offset));
+    let sad_path = compiler.chunk.make_label();
+    let end = compiler.chunk.make_label();
     // Eval the condition onto the stack
     try!(expression(compiler, tokens, offset, source));
-    // Write a provisional JMP instruction and note the position
-    compiler.chunk.write_code(OpCode::JumpIfFalse(0), token.line);
-    let jmp_idx = compiler.chunk.code.len() - 1;
-    // Pop the conditional value
-    compiler.chunk.write_code(OpCode::Pop, token.line);
+    compiler.chunk.emit_jump_to(OpCode::JumpIfFalse, sad_path, token.line);
+    // Pop the conditional value on the happy path
+    emit(compiler, OpCode::Pop, token.line);
     // Eval the body
     try!(do_expressions(compiler, tokens, offset, source));
-    // Backpatch the end of the body into the JMP instruction
-    compiler.chunk.backpatch_jump(jmp_idx);
+    compiler.chunk.emit_jump_to(OpCode::Jump, end, token.line);
+    // Replace the conditional value with nil on the sad path
+    compiler.chunk.define_label(sad_path);
+    emit(compiler, OpCode::Pop, token.line);
+    let nil_idx = compiler.chunk.write_constant(Value::Nil);
+    emit(compiler, OpCode::Constant(nil_idx), token.line);
+    compiler.chunk.define_label(end);
     Ok(())
 }
 
@@ -138,29 +480,51 @@ fn compile_if(compiler: &mut Compiler,
               -> Result<(), String> {
     let token = &tokens[*offset];
     try!(advance(tokens, offset));
+    let sad_path = compiler.chunk.make_label();
+    let end = compiler.chunk.make_label();
     // Eval the condition onto the stack
     try!(expression(compiler, tokens, offset, source));
-    // Write a provisional JMP instruction and note the position
-    compiler.chunk.write_code(OpCode::JumpIfFalse(0), token.line);
-    let sad_jmp_idx = compiler.chunk.code.len() - 1;
+    // Two literal branches pick one of two constants without jumping. An
+    // incomplete if goes the long way, to get the usual end of file error.
+    let branches = (tokens.get(*offset).and_then(|t| literal_value(t, source)),
+                    tokens.get(*offset + 1).and_then(|t| literal_value(t, source)),
+                    tokens.get(*offset + 2).map(|t| &t.token_type));
+    if let (Some(yes), Some(no), Some(TokenType::CloseParenthesis)) = branches {
+        let yes_idx = compiler.chunk.write_constant(yes);
+        let no_idx = compiler.chunk.write_constant(no);
+        emit(compiler, OpCode::Ternary(yes_idx, no_idx), token.line);
+        *offset += 2;
+        return Ok(())
+    }
+    compiler.chunk.emit_jump_to(OpCode::JumpIfFalse, sad_path, token.line);
     // Pop the conditional value on the happy path
-    compiler.chunk.write_code(OpCode::Pop, token.line);
+    emit(compiler, OpCode::Pop, token.line);
     // Eval the happy path body
     try!(expression(compiler, tokens, offset, source));
-    // Write a provisional JMP instruction to pass the sad path
-    compiler.chunk.write_code(OpCode::Jump(0), token.line);
-    let happy_jmp_idx = compiler.chunk.code.len() - 1;
+    compiler.chunk.emit_jump_to(OpCode::Jump, end, token.line);
     // Pop the conditional value on the sad path
-    compiler.chunk.write_code(OpCode::Pop, token.line);
-    // Backpatch the end of the happy path body into the first JMP instruction
-    compiler.chunk.backpatch_jump(sad_jmp_idx);
+    compiler.chunk.define_label(sad_path);
+    emit(compiler, OpCode::Pop, token.line);
     // Eval the sad path body
     try!(expression(compiler, tokens, offset, source));
-    // Backpatch the end of the sad path body into the second JMP instruction
-    compiler.chunk.backpatch_jump(happy_jmp_idx);
+    compiler.chunk.define_label(end);
     Ok(())
 }
 
+// The value of a token that compiles to a single constant
+fn literal_value(token: &Token, source: &SourceCode) -> Option<Value> {
+    match token.token_type {
+        TokenType::Nil
+            | TokenType::Bool
+            | TokenType::Int
+            | TokenType::Float
+            | TokenType::String
+            | TokenType::Char => parse_literal(token, source).ok(),
+        TokenType::Keyword => Some(Value::Keyword(token.get_token(source)[1..].to_string())),
+        _ => None,
+    }
+}
+
 fn compile_and(compiler: &mut Compiler,
                tokens: &Vec<Token>,
                offset: &mut usize,
@@ -168,18 +532,24 @@ fn compile_and(compiler: &mut Compiler,
                -> Result<(), String> {
     let token = &tokens[*offset];
     try!(advance(tokens, offset));
-    // TODO implement n-arity
+    // (and) is true
+    if tokens[*offset].token_type == TokenType::CloseParenthesis {
+        let idx = compiler.chunk.write_constant(Value::Bool(true));
+        emit(compiler, OpCode::Constant(idx), token.line);
+        return Ok(())
+    }
+    let end = compiler.chunk.make_label();
     // Eval the first argument
     try!(expression(compiler, tokens, offset, source));
-    // Write a provisional JMP instruction and note the position
-    compiler.chunk.write_code(OpCode::JumpIfFalse(0), token.line);
-    let jmp_idx = compiler.chunk.code.len() - 1;
-    compiler.chunk.write_code(OpCode::Pop, token.line);
-    // Eval the second argument
-    try!(expression(compiler, tokens, offset, source));
-    // Backpatch the JMP instruction to skip eval of the second argument
-    // if the first one is falsy
-    compiler.chunk.backpatch_jump(jmp_idx);
+    while tokens[*offset].token_type != TokenType::CloseParenthesis {
+        // Skip the remaining arguments if this one is falsy, leaving it as
+        // the result
+        compiler.chunk.emit_jump_to(OpCode::JumpIfFalse, end, token.line);
+        emit(compiler, OpCode::Pop, token.line);
+        // Eval the next argument
+        try!(expression(compiler, tokens, offset, source));
+    }
+    compiler.chunk.define_label(end);
     Ok(())
 }
 
@@ -190,22 +560,28 @@ fn compile_or(compiler: &mut Compiler,
               -> Result<(), String> {
     let token = &tokens[*offset];
     try!(advance(tokens, offset));
-    // TODO implement n-arity
+    // (or) is nil
+    if tokens[*offset].token_type == TokenType::CloseParenthesis {
+        let idx = compiler.chunk.write_constant(Value::Nil);
+        emit(compiler, OpCode::Constant(idx), token.line);
+        return Ok(())
+    }
+    let end = compiler.chunk.make_label();
     // Eval the first argument
     try!(expression(compiler, tokens, offset, source));
-    // Jump past the next jump if the first arg is falsy
-    compiler.chunk.write_code(OpCode::JumpIfFalse(0), token.line);
-    let happy_jmp_idx = compiler.chunk.code.len() - 1;
-    // Jump past the second arg otherwise
-    compiler.chunk.write_code(OpCode::Jump(0), token.line);
-    let sad_jmp_idx = compiler.chunk.code.len() - 1;
-    // The first JMP goes here
-    compiler.chunk.backpatch_jump(happy_jmp_idx);
-    compiler.chunk.write_code(OpCode::Pop, token.line);
-    // Eval the second argument
-    try!(expression(compiler, tokens, offset, source));
-    // The second JMP goes here
-    compiler.chunk.backpatch_jump(sad_jmp_idx);
+    while tokens[*offset].token_type != TokenType::CloseParenthesis {
+        let next = compiler.chunk.make_label();
+        // Jump past the next jump if this argument is falsy
+        compiler.chunk.emit_jump_to(OpCode::JumpIfFalse, next, token.line);
+        // Skip the remaining arguments otherwise, leaving this one as the
+        // result
+        compiler.chunk.emit_jump_to(OpCode::Jump, end, token.line);
+        compiler.chunk.define_label(next);
+        emit(compiler, OpCode::Pop, token.line);
+        // Eval the next argument
+        try!(expression(compiler, tokens, offset, source));
+    }
+    compiler.chunk.define_label(end);
     Ok(())
 }
 
@@ -216,194 +592,1214 @@ fn compile_while(compiler: &mut Compiler,
                  -> Result<(), String> {
     let token = &tokens[*offset];
     try!(advance(tokens, offset));
-    // Set the loop starting point
-    let loop_start_idx = compiler.chunk.code.len() - 1;
+    let loop_start = compiler.chunk.make_label();
+    let loop_end = compiler.chunk.make_label();
+    compiler.chunk.define_label(loop_start);
     // Eval the condition
     try!(expression(compiler, tokens, offset, source));
     // This JMP termiates the loop
-    compiler.chunk.write_code(OpCode::JumpIfFalse(0), token.line);
-    let loop_end_jmp_idx = compiler.chunk.code.len() - 1;
-    compiler.chunk.write_code(OpCode::Pop, token.line);
+    compiler.chunk.emit_jump_to(OpCode::JumpIfFalse, loop_end, token.line);
+    emit(compiler, OpCode::Pop, token.line);
     // Eval the body
-    try!(do_expressions(compiler, tokens, offset, source));
+    compiler.loop_stack.push(LoopContext{
+        start: loop_start,
+        end: loop_end,
+        stack_depth: compiler.stack_depth,
+    });
+    let body = do_expressions(compiler, tokens, offset, source);
+    compiler.loop_stack.pop();
+    try!(body);
     // Discard the last value
-    compiler.chunk.write_code(OpCode::Pop, token.line);
+    emit(compiler, OpCode::Pop, token.line);
     // Jump back to the condition
-    compiler.chunk.write_code(OpCode::Jump(loop_start_idx), token.line);
+    compiler.chunk.emit_jump_to(OpCode::Jump, loop_start, token.line);
+    // The condition is still on the stack when we jump out of the loop
+    compiler.stack_depth += 1;
     // Jump to here if we're done looping
-    compiler.chunk.backpatch_jump(loop_end_jmp_idx);
-    compiler.chunk.write_code(OpCode::Pop, token.line);
+    compiler.chunk.define_label(loop_end);
+    emit(compiler, OpCode::Pop, token.line);
+    // The loop itself evaluates to nil
+    let idx = compiler.chunk.write_constant(Value::Nil);
+    emit(compiler, OpCode::Constant(idx), token.line);
     Ok(())
 }
 
-fn compile_defn(compiler: &mut Compiler,
-                tokens: &Vec<Token>,
-                offset: &mut usize,
-                source: &SourceCode)
-                -> Result<(), String> {
-    let start_token = &tokens[*offset];
-    // Name
+// `(do-while cond body...)` runs the body before checking the condition, so
+// at least once
+fn compile_do_while(compiler: &mut Compiler,
+                    tokens: &Vec<Token>,
+                    offset: &mut usize,
+                    source: &SourceCode)
+                    -> Result<(), String> {
+    let token = &tokens[*offset];
     try!(advance(tokens, offset));
-    let name_token = &tokens[*offset];
-    if name_token.token_type != TokenType::Symbol {
-        return Err(format!("Function name needs to be a symbol, got {}", name_token.token_type))
-    }
-    let fn_name = name_token.get_token(source);
-    // Parameters
-    let mut argc = 0;
-    let inner_chunk = Chunk{
-        code: vec![],
-        constants: vec![],
-        lines: vec![],
-        chunks: vec![],
-    };
-    let mut inner_compiler = Compiler{
-        chunk: inner_chunk,
-        locals: vec![],
-        scope_depth: 0,
-        sexp_depth: 0,
-        is_main: false,
-    };
+    let loop_start = compiler.chunk.make_label();
+    let loop_condition = compiler.chunk.make_label();
+    let loop_end = compiler.chunk.make_label();
+    // The condition comes first but is compiled after the body
+    let mut condition_offset = *offset;
+    try!(read_datum(tokens, offset, source));
+    compiler.chunk.define_label(loop_start);
+    // `continue` goes on to the condition, like it does in `while`
+    compiler.loop_stack.push(LoopContext{
+        start: loop_condition,
+        end: loop_end,
+        stack_depth: compiler.stack_depth,
+    });
+    let body = do_expressions(compiler, tokens, offset, source);
+    compiler.loop_stack.pop();
+    try!(body);
+    // Discard the last value
+    emit(compiler, OpCode::Pop, token.line);
+    compiler.chunk.define_label(loop_condition);
+    try!(expression(compiler, tokens, &mut condition_offset, source));
+    compiler.chunk.emit_jump_to(OpCode::JumpIfFalse, loop_end, token.line);
+    emit(compiler, OpCode::Pop, token.line);
+    compiler.chunk.emit_jump_to(OpCode::Jump, loop_start, token.line);
+    // The condition is still on the stack when we jump out of the loop
+    compiler.stack_depth += 1;
+    compiler.chunk.define_label(loop_end);
+    emit(compiler, OpCode::Pop, token.line);
+    // The loop itself evaluates to nil
+    let idx = compiler.chunk.write_constant(Value::Nil);
+    emit(compiler, OpCode::Constant(idx), token.line);
+    Ok(())
+}
+
+// `(local-bindings)` evaluates to a map of the locals in scope, keyed by
+// symbol. The names are only known while compiling, so this builds the map
+// from the local slots.
+fn compile_local_bindings(compiler: &mut Compiler,
+                          tokens: &Vec<Token>,
+                          offset: &mut usize)
+                          -> Result<(), String> {
+    let token = &tokens[*offset];
     try!(advance(tokens, offset));
-    try!(consume_token(tokens, offset, &TokenType::OpenParenthesis));
-    while &tokens[*offset].token_type != &TokenType::CloseParenthesis {
-        argc += 1;
-        let binding_token = &tokens[*offset];
-        if binding_token.token_type != TokenType::Symbol {
-            return Err(format!("Function binding must be a symbol, got {}", binding_token.token_type));
+    // Inner locals come later and shadow outer ones of the same name
+    let mut visible: Vec<(String, usize)> = vec![];
+    for local in &compiler.locals {
+        // Hidden locals have names that can't be symbols
+        if local.name.contains(' ') {
+            continue
         }
-        inner_compiler.locals.append(&mut vec![LocalVar{
-            name: binding_token.get_token(source).to_string(),
-            depth: inner_compiler.scope_depth,
-        }]);
-        try!(advance(tokens, offset));
+        visible.retain(|(name, _)| name != &local.name);
+        visible.push((local.name.clone(), local.slot));
     }
-    try!(consume_token(tokens, offset, &TokenType::CloseParenthesis));
-    // Body
-    // TODO reuse this code between this and compile()
-    while &tokens[*offset].token_type != &TokenType::CloseParenthesis {
-        let token = &tokens[*offset];
-        if token.is_error() {
-            return Err(format!("Lexing error: {}", token.token_type));
-        } else {
-            let exp = expression(&mut inner_compiler, &tokens, offset, &source);
-            if exp.is_err() {
-                return Err(exp.err().unwrap());
-            }
-        }
+    for (name, slot) in &visible {
+        let idx = compiler.chunk.write_constant(Value::Symbol(intern(name)));
+        emit(compiler, OpCode::Constant(idx), token.line);
+        emit(compiler, OpCode::GetLocal(*slot), token.line);
     }
-    inner_compiler.chunk.write_code(OpCode::Return, 99);
-    // Write function
-    compiler.chunk.chunks.append(&mut vec![inner_compiler.chunk]);
-    let chunk_idx = compiler.chunk.chunks.len();
-    let fn_idx = compiler.chunk.write_constant(Value::Function(fn_name, argc, chunk_idx));
-    compiler.chunk.write_code(OpCode::Constant(fn_idx), start_token.line);
-    compiler.chunk.write_code(OpCode::DefineGlobal(fn_idx), start_token.line);
+    emit(compiler, OpCode::MakeMap(visible.len()), token.line);
     Ok(())
 }
 
-fn compile_fn_call(compiler: &mut Compiler,
-                   tokens: &Vec<Token>,
-                   offset: &mut usize,
-                   source: &SourceCode)
-                   -> Result<(), String> {
-    let token = &tokens[*offset];
-    let fn_name = token.get_token(source);
-    let mut custom = false;
-    let mut ops = match fn_name.as_str() {
-        "+" => vec![OpCode::Add],
-        "-" => vec![OpCode::Subtract],
-        "*" => vec![OpCode::Multiply],
-        "/" => vec![OpCode::Divide],
-        "not" => vec![OpCode::Not],
-        "=" => vec![OpCode::Equal],
-        ">" => vec![OpCode::GreaterThan],
-        ">=" => vec![OpCode::LessThan, OpCode::Not],
-        "<" => vec![OpCode::LessThan],
-        "<=" => vec![OpCode::GreaterThan, OpCode::Not],
-        "print" => vec![OpCode::Print],
-        _ => {
-            custom = true;
-            // Gets filled in later
-            vec![]
-        }
+// Drops everything the loop body has pushed so far, for leaving it early
+fn unwind_loop(compiler: &mut Compiler, name: &str, line: Line) -> Result<LoopContext, String> {
+    let context = match compiler.loop_stack.last() {
+        Some(c) => LoopContext{start: c.start, end: c.end, stack_depth: c.stack_depth},
+        None => return Err(format!("`{}` outside of a loop in line {}", name, line)),
     };
-    if custom {
-        // Custom functions get pushed to the stack first.
-        try!(expression(compiler, tokens, offset, source));
-    } else {
-        try!(advance(tokens, offset));
-    }
-    let mut argc = 0;
-    while tokens[*offset].token_type != TokenType::CloseParenthesis {
-        argc += 1;
-        try!(expression(compiler, tokens, offset, source));
-    }
-    if custom {
-        ops = vec![OpCode::Call(argc)];
-    }
-    for op in ops {
-        compiler.chunk.write_code(op, token.line);
+    let extra = compiler.stack_depth - context.stack_depth;
+    if 0 < extra {
+        emit(compiler, OpCode::PopN(extra), line);
     }
+    Ok(context)
+}
+
+// `(break)` leaves the innermost `while`
+fn compile_break(compiler: &mut Compiler,
+                 tokens: &Vec<Token>,
+                 offset: &mut usize)
+                 -> Result<(), String> {
+    let token = &tokens[*offset];
+    try!(advance(tokens, offset));
+    let depth = compiler.stack_depth;
+    let context = try!(unwind_loop(compiler, "break", token.line));
+    // The loop end pops the condition, which isn't there yet
+    let idx = compiler.chunk.write_constant(Value::Nil);
+    emit(compiler, OpCode::Constant(idx), token.line);
+    compiler.chunk.emit_jump_to(OpCode::Jump, context.end, token.line);
+    // Never reached, but compiles like any other expression
+    compiler.stack_depth = depth + 1;
     Ok(())
 }
 
-fn compile_sexp(compiler: &mut Compiler,
-                tokens: &Vec<Token>,
-                offset: &mut usize,
-                source: &SourceCode)
-                -> Result<(), String> {
-    compiler.sexp_depth += 1;
+// `(continue)` skips the rest of the body of the innermost `while`
+fn compile_continue(compiler: &mut Compiler,
+                    tokens: &Vec<Token>,
+                    offset: &mut usize)
+                    -> Result<(), String> {
+    let token = &tokens[*offset];
     try!(advance(tokens, offset));
+    let depth = compiler.stack_depth;
+    let context = try!(unwind_loop(compiler, "continue", token.line));
+    compiler.chunk.emit_jump_to(OpCode::Jump, context.start, token.line);
+    compiler.stack_depth = depth + 1;
+    Ok(())
+}
+
+// `(try body... (catch e handler...))` evaluates the handler with the raised
+// value bound to `e` if the body raises
+fn compile_try(compiler: &mut Compiler,
+               tokens: &Vec<Token>,
+               offset: &mut usize,
+               source: &SourceCode)
+               -> Result<(), String> {
     let token = &tokens[*offset];
-    if token.token_type != TokenType::Symbol {
-        return Err(format!("Function name must be a symbol, got {}", token.token_type));
-    }
-    let fn_name = token.get_token(source);
-    match fn_name.as_str() {
-        "def" => try!(compile_def(compiler, tokens, offset, source)),
-        "let" => try!(compile_let(compiler, tokens, offset, source)),
-        "when" => try!(compile_when(compiler, tokens, offset, source)),
-        "if" => try!(compile_if(compiler, tokens, offset, source)),
-        "and" => try!(compile_and(compiler, tokens, offset, source)),
-        "or" => try!(compile_or(compiler, tokens, offset, source)),
-        "while" => try!(compile_while(compiler, tokens, offset, source)),
-        "defn" => try!(compile_defn(compiler, tokens, offset, source)),
-        "do" => {
-            try!(advance(tokens, offset));
-            try!(do_expressions(compiler, tokens, offset, source));
-        }
-        _ => try!(compile_fn_call(compiler, tokens, offset, source)),
+    try!(advance(tokens, offset));
+    let is_catch = |offset: usize| {
+        tokens[offset].token_type == TokenType::OpenParenthesis
+            && tokens[offset + 1].token_type == TokenType::Symbol
+            && tokens[offset + 1].get_token(source) == "catch"
+    };
+    let end = try!(compile_guarded_body(compiler, tokens, offset, source, token.line, &is_catch, "try"));
+    try!(advance(tokens, offset));
+    try!(advance(tokens, offset));
+    let binding_token = &tokens[*offset];
+    if binding_token.token_type != TokenType::Symbol {
+        return Err(format!("catch binding must be a symbol, got {}", binding_token.token_type));
     }
+    try!(advance(tokens, offset));
+    compiler.scope_depth += 1;
+    push_local(compiler, &binding_token.get_token(source));
+    try!(do_expressions(compiler, tokens, offset, source));
+    end_scope(compiler, token.line);
     try!(consume_token(tokens, offset, &TokenType::CloseParenthesis));
-    compiler.sexp_depth -= 1;
+    compiler.chunk.define_label(end);
     Ok(())
 }
 
-fn expression(compiler: &mut Compiler,
-              tokens: &Vec<Token>,
+// Compiles the forms up to the catch clause with a handler installed, leaving
+// the offset at the catch clause. The raised value is on the stack where the
+// catch clause is compiled, which should jump to the returned label after.
+fn compile_guarded_body(compiler: &mut Compiler,
+                        tokens: &Vec<Token>,
+                        offset: &mut usize,
+                        source: &SourceCode,
+                        line: Line,
+                        is_catch: &dyn Fn(usize) -> bool,
+                        form: &str)
+                        -> Result<LabelId, String> {
+    let handler = compiler.chunk.make_label();
+    let end = compiler.chunk.make_label();
+    compiler.chunk.emit_jump_to(OpCode::PushHandler, handler, line);
+    // Leaving the body with `break` or `continue` would skip popping the
+    // handler, so it isn't part of any loop
+    let loops = std::mem::replace(&mut compiler.loop_stack, vec![]);
+    let mut forms = 0;
+    while !is_catch(*offset) {
+        if tokens[*offset].token_type == TokenType::CloseParenthesis
+            || tokens[*offset].token_type == TokenType::EOF {
+            return Err(format!("{} needs a catch clause", form))
+        }
+        if 0 < forms {
+            emit(compiler, OpCode::Pop, line);
+        }
+        try!(expression(compiler, tokens, offset, source));
+        forms += 1;
+    }
+    if forms == 0 {
+        let idx = compiler.chunk.write_constant(Value::Nil);
+        emit(compiler, OpCode::Constant(idx), line);
+    }
+    compiler.loop_stack = loops;
+    emit(compiler, OpCode::PopHandler, line);
+    compiler.chunk.emit_jump_to(OpCode::Jump, end, line);
+    // The raised value takes the place of the body's value
+    compiler.chunk.define_label(handler);
+    Ok(end)
+}
+
+// `(defn f (x) body... (:catch [e] handler...))` runs the handler with the
+// raised value bound to `e` if the body raises, `(:catch [] ...)` ignores it
+fn compile_defn_catch(compiler: &mut Compiler,
+                      tokens: &Vec<Token>,
+                      offset: &mut usize,
+                      source: &SourceCode,
+                      line: Line)
+                      -> Result<(), String> {
+    let is_catch = |offset: usize| {
+        tokens[offset].token_type == TokenType::OpenParenthesis
+            && tokens[offset + 1].token_type == TokenType::Keyword
+            && tokens[offset + 1].get_token(source) == ":catch"
+    };
+    let end = try!(compile_guarded_body(compiler, tokens, offset, source, line, &is_catch, "defn"));
+    try!(advance(tokens, offset));
+    try!(advance(tokens, offset));
+    try!(consume_token(tokens, offset, &TokenType::OpenBracket));
+    compiler.scope_depth += 1;
+    if tokens[*offset].token_type == TokenType::CloseBracket {
+        emit(compiler, OpCode::Pop, line);
+    } else {
+        let name = try!(symbol_token(tokens, *offset, source, ":catch binding"));
+        try!(advance(tokens, offset));
+        push_local(compiler, &name);
+    }
+    try!(consume_token(tokens, offset, &TokenType::CloseBracket));
+    if tokens[*offset].token_type == TokenType::CloseParenthesis {
+        let idx = compiler.chunk.write_constant(Value::Nil);
+        emit(compiler, OpCode::Constant(idx), line);
+    } else {
+        try!(do_expressions(compiler, tokens, offset, source));
+    }
+    end_scope(compiler, line);
+    try!(consume_token(tokens, offset, &TokenType::CloseParenthesis));
+    if tokens[*offset].token_type != TokenType::CloseParenthesis {
+        return Err(format!("The :catch clause has to come last in line {}", line))
+    }
+    compiler.chunk.define_label(end);
+    Ok(())
+}
+
+// Whether the forms from offset up to the closing parenthesis include a
+// `(:catch ...)` clause
+fn has_defn_catch(tokens: &Vec<Token>, offset: usize, source: &SourceCode) -> bool {
+    let mut depth = 0;
+    let mut i = offset;
+    loop {
+        match tokens[i].token_type {
+            TokenType::OpenParenthesis | TokenType::OpenBracket | TokenType::OpenBrace => {
+                if depth == 0
+                    && tokens[i + 1].token_type == TokenType::Keyword
+                    && tokens[i + 1].get_token(source) == ":catch" {
+                    return true
+                }
+                depth += 1;
+            }
+            TokenType::CloseParenthesis | TokenType::CloseBracket | TokenType::CloseBrace => {
+                if depth == 0 {
+                    return false
+                }
+                depth -= 1;
+            }
+            TokenType::EOF => return false,
+            _ => (),
+        }
+        i += 1;
+    }
+}
+
+// Declares the value on top of the stack as a local in the current scope
+fn push_local(compiler: &mut Compiler, name: &str) -> usize {
+    let slot = compiler.stack_depth - 1;
+    compiler.locals.append(&mut vec![LocalVar{
+        name: String::from(name),
+        depth: compiler.scope_depth,
+        slot: slot,
+    }]);
+    slot
+}
+
+// `(for [x xs :when (> x 2)] (* x x))` collects the body for every element
+// into a list, further bindings loop over the Cartesian product
+fn compile_for(compiler: &mut Compiler,
+               tokens: &Vec<Token>,
+               offset: &mut usize,
+               source: &SourceCode)
+               -> Result<(), String> {
+    compile_for_form(compiler, tokens, offset, source, false)
+}
+
+// `(for-map [x xs] key value)` is `for` with a key and value for each
+// element, collected into a map
+fn compile_for_map(compiler: &mut Compiler,
+                   tokens: &Vec<Token>,
+                   offset: &mut usize,
+                   source: &SourceCode)
+                   -> Result<(), String> {
+    compile_for_form(compiler, tokens, offset, source, true)
+}
+
+fn compile_for_form(compiler: &mut Compiler,
+                    tokens: &Vec<Token>,
+                    offset: &mut usize,
+                    source: &SourceCode,
+                    pairs: bool)
+                    -> Result<(), String> {
+    let token = &tokens[*offset];
+    try!(advance(tokens, offset));
+    compiler.scope_depth += 1;
+    // The results so far, collected in a vector that is only turned into a
+    // list or map at the end. The names of hidden locals can't clash with
+    // symbols.
+    emit(compiler, OpCode::MakeVector(0), token.line);
+    let results_slot = push_local(compiler, " results");
+    try!(consume_token(tokens, offset, &TokenType::OpenBracket));
+    try!(compile_for_binding(compiler, tokens, offset, source, results_slot, pairs));
+    // The results are already on top of the stack
+    compiler.scope_depth -= 1;
+    compiler.locals.pop();
+    let collect = if pairs { "into-map" } else { "seq" };
+    emit(compiler, OpCode::CallNative(natives::find_native(collect).unwrap(), 1), token.line);
+    Ok(())
+}
+
+fn compile_for_binding(compiler: &mut Compiler,
+                       tokens: &Vec<Token>,
+                       offset: &mut usize,
+                       source: &SourceCode,
+                       results_slot: usize,
+                       pairs: bool)
+                       -> Result<(), String> {
+    let token = &tokens[*offset];
+    if token.token_type != TokenType::Symbol {
+        return Err(format!("for binding must be a symbol, got {}", token.token_type));
+    }
+    let name = token.get_token(source);
+    try!(advance(tokens, offset));
+    let line = token.line;
+    let local_count = compiler.locals.len();
+    try!(expression(compiler, tokens, offset, source));
+    let seq_slot = push_local(compiler, " seq");
+    let idx = compiler.chunk.write_constant(Value::Int(0));
+    emit(compiler, OpCode::Constant(idx), line);
+    let index_slot = push_local(compiler, " index");
+    // Loop while the index is in bounds
+    let loop_start = compiler.chunk.make_label();
+    let loop_end = compiler.chunk.make_label();
+    compiler.chunk.define_label(loop_start);
+    emit(compiler, OpCode::GetLocal(index_slot), line);
+    emit(compiler, OpCode::GetLocal(seq_slot), line);
+    emit(compiler, OpCode::CallNative(natives::find_native("count").unwrap(), 1), line);
+    emit(compiler, OpCode::LessThan, line);
+    compiler.chunk.emit_jump_to(OpCode::JumpIfFalse, loop_end, line);
+    emit(compiler, OpCode::Pop, line);
+    // Bind the element
+    emit(compiler, OpCode::GetLocal(seq_slot), line);
+    emit(compiler, OpCode::GetLocal(index_slot), line);
+    emit(compiler, OpCode::CallNative(natives::find_native("nth").unwrap(), 2), line);
+    push_local(compiler, &name);
+    // Skip the rest if the filter fails, leaving its value to be popped
+    let mut skip = None;
+    let next = &tokens[*offset];
+    if next.token_type == TokenType::Keyword && next.get_token(source) == ":when" {
+        try!(advance(tokens, offset));
+        try!(expression(compiler, tokens, offset, source));
+        let label = compiler.chunk.make_label();
+        compiler.chunk.emit_jump_to(OpCode::JumpIfFalse, label, line);
+        skip = Some(label);
+        emit(compiler, OpCode::Pop, line);
+    }
+    if tokens[*offset].token_type == TokenType::CloseBracket {
+        try!(advance(tokens, offset));
+        // Append the body to the results
+        if pairs {
+            try!(expression(compiler, tokens, offset, source));
+            try!(expression(compiler, tokens, offset, source));
+            if tokens[*offset].token_type != TokenType::CloseParenthesis {
+                return Err(format!("for-map expects a key and a value in line {}", line));
+            }
+            emit(compiler, OpCode::MakeList(2), line);
+        } else {
+            try!(do_expressions(compiler, tokens, offset, source));
+        }
+        emit(compiler, OpCode::AppendLocal(results_slot), line);
+    } else {
+        try!(compile_for_binding(compiler, tokens, offset, source, results_slot, pairs));
+    }
+    if let Some(label) = skip {
+        // Match the filter value left on the stack when skipping
+        let idx = compiler.chunk.write_constant(Value::Nil);
+        emit(compiler, OpCode::Constant(idx), line);
+        compiler.chunk.define_label(label);
+        emit(compiler, OpCode::Pop, line);
+    }
+    // Drop the element and move on to the next one
+    emit(compiler, OpCode::Pop, line);
+    compiler.locals.pop();
+    emit(compiler, OpCode::GetLocal(index_slot), line);
+    let idx = compiler.chunk.write_constant(Value::Int(1));
+    emit(compiler, OpCode::Constant(idx), line);
+    emit(compiler, OpCode::Add, line);
+    emit(compiler, OpCode::SetLocal(index_slot), line);
+    compiler.chunk.emit_jump_to(OpCode::Jump, loop_start, line);
+    // The condition is still on the stack when we jump out of the loop
+    compiler.stack_depth += 1;
+    compiler.chunk.define_label(loop_end);
+    emit(compiler, OpCode::Pop, line);
+    emit(compiler, OpCode::PopN(2), line);
+    compiler.locals.truncate(local_count);
+    Ok(())
+}
+
+// Compiles a parameter list and body into a function constant and loads it,
+// capturing the locals around it that it uses
+fn compile_function(compiler: &mut Compiler,
+                    tokens: &Vec<Token>,
+                    offset: &mut usize,
+                    source: &SourceCode,
+                    fn_name: String,
+                    line: Line)
+                    -> Result<usize, String> {
+    // Parameters
+    let mut params = vec![];
+    let mut inner_compiler = function_compiler(compiler);
+    // Parameters can be in a list or a vector
+    let close = if tokens[*offset].token_type == TokenType::OpenBracket {
+        TokenType::CloseBracket
+    } else {
+        TokenType::CloseParenthesis
+    };
+    if close == TokenType::CloseBracket {
+        try!(advance(tokens, offset));
+    } else {
+        try!(consume_token(tokens, offset, &TokenType::OpenParenthesis));
+    }
+    // `:key default` pairs, with the offset of the default to compile later
+    let mut keywords = vec![];
+    let mut rest = None;
+    while &tokens[*offset].token_type != &close {
+        let binding_token = &tokens[*offset];
+        if rest.is_some() {
+            return Err(format!("The &rest parameter has to come last in line {}", binding_token.line));
+        }
+        if binding_token.token_type == TokenType::Symbol && binding_token.get_token(source) == "&rest" {
+            try!(advance(tokens, offset));
+            let name = try!(symbol_token(tokens, *offset, source, "&rest parameter"));
+            if !keywords.is_empty() {
+                return Err(format!("A function can't have both keyword and &rest parameters in line {}",
+                                   binding_token.line));
+            }
+            inner_compiler.stack_depth += 1;
+            push_local(&mut inner_compiler, &name);
+            rest = Some(name);
+            try!(advance(tokens, offset));
+            continue
+        }
+        if binding_token.token_type == TokenType::Keyword {
+            keywords.push((binding_token.get_token(source)[1..].to_string(), *offset + 1));
+            try!(advance(tokens, offset));
+            try!(read_datum(tokens, offset, source));
+            continue
+        }
+        if !keywords.is_empty() {
+            return Err(format!("Positional parameters have to come before keyword parameters in line {}",
+                               binding_token.line));
+        }
+        if binding_token.token_type != TokenType::Symbol {
+            return Err(format!("Function binding must be a symbol, got {}", binding_token.token_type));
+        }
+        let param = binding_token.get_token(source);
+        params.append(&mut vec![param.clone()]);
+        inner_compiler.locals.append(&mut vec![LocalVar{
+            name: param,
+            depth: inner_compiler.scope_depth,
+            slot: inner_compiler.stack_depth,
+        }]);
+        inner_compiler.stack_depth += 1;
+        try!(advance(tokens, offset));
+    }
+    try!(consume_token(tokens, offset, &close));
+    finish_function(compiler, inner_compiler, tokens, offset, source, fn_name, params, keywords, rest, line)
+}
+
+// Compiles the rest of the current form as the body of a function without
+// parameters
+fn compile_thunk(compiler: &mut Compiler,
+                 tokens: &Vec<Token>,
+                 offset: &mut usize,
+                 source: &SourceCode,
+                 fn_name: String,
+                 line: Line)
+                 -> Result<usize, String> {
+    let inner_compiler = function_compiler(compiler);
+    finish_function(compiler, inner_compiler, tokens, offset, source, fn_name, vec![], vec![], None, line)
+}
+
+// A compiler for a function defined inside the one being compiled
+fn function_compiler(compiler: &Compiler) -> Compiler {
+    let inner_chunk = Chunk{
+        code: vec![],
+        constants: vec![],
+        lines: vec![],
+        chunks: vec![],
+        shared_constants: None,
+        label_map: vec![],
+        file: compiler.file.clone(),
+        repl_last_value: false,
+    };
+    Compiler{
+        chunk: inner_chunk,
+        locals: vec![],
+        scope_depth: 0,
+        sexp_depth: 0,
+        is_main: false,
+        stack_depth: 0,
+        module: compiler.module.clone(),
+        module_names: compiler.module_names.clone(),
+        file: compiler.file.clone(),
+        loop_stack: vec![],
+        enclosing: visible_names(compiler),
+        upvalues: vec![],
+        case_sensitive: compiler.case_sensitive,
+    }
+}
+
+// Compiles the body of a function whose parameters are already bound in the
+// inner compiler, and loads the function
+fn finish_function(compiler: &mut Compiler,
+                   mut inner_compiler: Compiler,
+                   tokens: &Vec<Token>,
+                   offset: &mut usize,
+                   source: &SourceCode,
+                   fn_name: String,
+                   params: Vec<String>,
+                   keywords: Vec<(String, usize)>,
+                   rest: Option<String>,
+                   line: Line)
+                   -> Result<usize, String> {
+    // Keyword arguments arrive as a map after the positional ones, each
+    // keyword parameter is a local looked up in it
+    if !keywords.is_empty() {
+        inner_compiler.stack_depth += 1;
+        let map_slot = push_local(&mut inner_compiler, " keyword-args");
+        let get = natives::find_native("get").unwrap();
+        for &(ref keyword, default_offset) in &keywords {
+            emit(&mut inner_compiler, OpCode::GetLocal(map_slot), line);
+            let idx = inner_compiler.chunk.write_constant(Value::Keyword(keyword.clone()));
+            emit(&mut inner_compiler, OpCode::Constant(idx), line);
+            try!(expression(&mut inner_compiler, tokens, &mut default_offset.clone(), source));
+            emit(&mut inner_compiler, OpCode::CallNative(get, 3), line);
+            push_local(&mut inner_compiler, keyword);
+        }
+    }
+    let (pre, post) = try!(read_conditions(tokens, offset, source));
+    for &(ref condition, condition_offset) in &pre {
+        try!(compile_condition(&mut inner_compiler, tokens, condition_offset, source, "Pre", condition, line));
+    }
+    // Body
+    if has_defn_catch(tokens, *offset, source) {
+        try!(compile_defn_catch(&mut inner_compiler, tokens, offset, source, line));
+    }
+    // TODO reuse this code between this and compile_source()
+    while &tokens[*offset].token_type != &TokenType::CloseParenthesis {
+        let token = &tokens[*offset];
+        if token.is_error() {
+            return Err(format!("Lexing error: {}", token.token_type));
+        } else {
+            let exp = expression(&mut inner_compiler, &tokens, offset, &source);
+            if exp.is_err() {
+                return Err(exp.err().unwrap());
+            }
+        }
+    }
+    // Post-conditions see the return value as `%`
+    if !post.is_empty() {
+        push_local(&mut inner_compiler, "%");
+        for &(ref condition, condition_offset) in &post {
+            try!(compile_condition(&mut inner_compiler, tokens, condition_offset, source, "Post", condition, line));
+        }
+    }
+    inner_compiler.chunk.write_code(OpCode::Return, 99);
+    // Write function
+    compiler.chunk.chunks.append(&mut vec![inner_compiler.chunk]);
+    let chunk_idx = compiler.chunk.chunks.len();
+    let file = compiler.file.clone();
+    let fn_idx = compiler.chunk.write_constant(Value::Function{
+        name: fn_name,
+        params: params,
+        keywords: keywords.into_iter().map(|(keyword, _)| keyword).collect(),
+        rest: rest,
+        chunk_idx: chunk_idx,
+        file: file,
+        line: line,
+        upvalues: Arc::new(vec![]),
+    });
+    if inner_compiler.upvalues.is_empty() {
+        emit(compiler, OpCode::Constant(fn_idx), line);
+    } else {
+        let mut captures = vec![];
+        for name in &inner_compiler.upvalues {
+            captures.push(match resolve_local(compiler, name) {
+                Some(slot) => Capture::Local(slot),
+                // Must be in our own enclosing names then
+                None => Capture::Upvalue(resolve_upvalue(compiler, name).unwrap()),
+            });
+        }
+        emit(compiler, OpCode::LoadClosure(fn_idx, captures), line);
+    }
+    Ok(fn_idx)
+}
+
+// A map of `:pre` and `:post` vectors of conditions in front of a function
+// body, `{:pre [(> x 0)] :post [(> % 0)]}`, is skipped and returns each
+// condition as data, with its offset to compile it from. A map that is the
+// whole body is its value instead.
+fn read_conditions(tokens: &Vec<Token>,
+                   offset: &mut usize,
+                   source: &SourceCode)
+                   -> Result<(Vec<(Value, usize)>, Vec<(Value, usize)>), String> {
+    let mut pre = vec![];
+    let mut post = vec![];
+    if tokens[*offset].token_type != TokenType::OpenBrace {
+        return Ok((pre, post))
+    }
+    let mut i = *offset + 1;
+    while tokens[i].token_type != TokenType::CloseBrace {
+        let key = tokens[i].get_token(source);
+        if tokens[i].token_type != TokenType::Keyword
+            || (key != ":pre" && key != ":post")
+            || tokens[i + 1].token_type != TokenType::OpenBracket {
+            return Ok((vec![], vec![]))
+        }
+        i += 2;
+        while tokens[i].token_type != TokenType::CloseBracket {
+            let condition_offset = i;
+            let condition = try!(read_datum(tokens, &mut i, source));
+            if key == ":pre" {
+                pre.push((condition, condition_offset));
+            } else {
+                post.push((condition, condition_offset));
+            }
+        }
+        i += 1;
+    }
+    if tokens[i + 1].token_type == TokenType::CloseParenthesis {
+        return Ok((vec![], vec![]))
+    }
+    *offset = i + 1;
+    Ok((pre, post))
+}
+
+// Raises "Pre-condition failed: (> x 0)" unless the condition holds
+fn compile_condition(compiler: &mut Compiler,
+                     tokens: &Vec<Token>,
+                     offset: usize,
+                     source: &SourceCode,
+                     kind: &str,
+                     condition: &Value,
+                     line: Line)
+                     -> Result<(), String> {
+    let holds = compiler.chunk.make_label();
+    try!(expression(compiler, tokens, &mut offset.clone(), source));
+    emit(compiler, OpCode::Not, line);
+    compiler.chunk.emit_jump_to(OpCode::JumpIfFalse, holds, line);
+    emit(compiler, OpCode::Pop, line);
+    let idx = compiler.chunk.write_constant(Value::String(format!("{}-condition failed: {:?}", kind, condition)));
+    emit(compiler, OpCode::Constant(idx), line);
+    emit(compiler, OpCode::CallNative(natives::find_native("condition-failed").unwrap(), 1), line);
+    compiler.chunk.define_label(holds);
+    emit(compiler, OpCode::Pop, line);
+    Ok(())
+}
+
+// The slot of the innermost local with this name
+fn resolve_local(compiler: &Compiler, name: &str) -> Option<usize> {
+    compiler.locals.iter().rev().find(|l| l.name == name).map(|l| l.slot)
+}
+
+// The index of an enclosing function's local among this function's upvalues,
+// capturing it if it isn't yet
+fn resolve_upvalue(compiler: &mut Compiler, name: &str) -> Option<usize> {
+    if let Some(idx) = compiler.upvalues.iter().position(|n| n == name) {
+        return Some(idx)
+    }
+    if !compiler.enclosing.iter().any(|n| n == name) {
+        return None
+    }
+    compiler.upvalues.push(String::from(name));
+    Some(compiler.upvalues.len() - 1)
+}
+
+// Whether a local, or a definition in the current module, has the name of a
+// builtin. Other globals replacing natives are looked up when called.
+fn is_shadowed(compiler: &Compiler, name: &str) -> bool {
+    resolve_local(compiler, name).is_some()
+        || compiler.enclosing.iter().any(|n| n == name)
+        || (compiler.module.is_some() && compiler.module_names.iter().any(|n| n == name))
+}
+
+// Everything a function defined here could capture
+fn visible_names(compiler: &Compiler) -> Vec<String> {
+    let mut names = compiler.enclosing.clone();
+    for local in &compiler.locals {
+        // Hidden locals have names that can't be symbols
+        if !local.name.contains(' ') {
+            names.push(local.name.clone());
+        }
+    }
+    names
+}
+
+fn compile_defn(compiler: &mut Compiler,
+                tokens: &Vec<Token>,
+                offset: &mut usize,
+                source: &SourceCode)
+                -> Result<(), String> {
+    let start_token = &tokens[*offset];
+    // Name
+    try!(advance(tokens, offset));
+    let name_token = &tokens[*offset];
+    if name_token.token_type != TokenType::Symbol {
+        return Err(format!("Function name needs to be a symbol, got {}", name_token.token_type))
+    }
+    // Qualify before compiling the body so recursive calls resolve
+    let fn_name = qualify_name(compiler, name_token.get_token(source));
+    try!(advance(tokens, offset));
+    let docstring = if tokens[*offset].token_type == TokenType::String {
+        let doc = tokens[*offset].get_token(source);
+        try!(advance(tokens, offset));
+        Some(doc)
+    } else {
+        None
+    };
+    let line = start_token.line;
+    let is_multi_arity = tokens[*offset].token_type == TokenType::OpenParenthesis
+        && match tokens.get(*offset + 1).map(|t| &t.token_type) {
+            Some(TokenType::OpenParenthesis) | Some(TokenType::OpenBracket) => true,
+            _ => false,
+        };
+    let name_idx = compiler.chunk.write_constant(Value::Symbol(intern(&fn_name)));
+    if is_multi_arity {
+        try!(compile_multi_arity(compiler, tokens, offset, source, fn_name, name_idx, line));
+    } else {
+        try!(compile_function(compiler, tokens, offset, source, fn_name, line));
+    }
+    // The docstring goes into the function's metadata, see `doc`
+    if let Some(doc) = docstring {
+        let key = Value::Keyword(String::from("doc"));
+        let mut meta = OrderedMap::new();
+        meta.insert(key.map_key(), (key, Value::String(doc)));
+        let meta_idx = compiler.chunk.write_constant(Value::Map(meta));
+        emit(compiler, OpCode::Constant(meta_idx), line);
+        emit(compiler, OpCode::CallNative(natives::find_native("with-meta").unwrap(), 2), line);
+    }
+    emit(compiler, OpCode::DefineGlobal(name_idx), line);
+    Ok(())
+}
+
+// `(defn name ([] 0) ([x] x))` compiles every clause as its own function,
+// the call picks the one taking as many arguments as it was given
+fn compile_multi_arity(compiler: &mut Compiler,
+                       tokens: &Vec<Token>,
+                       offset: &mut usize,
+                       source: &SourceCode,
+                       fn_name: String,
+                       name_idx: usize,
+                       line: Line)
+                       -> Result<(), String> {
+    emit(compiler, OpCode::Constant(name_idx), line);
+    let mut arities = vec![];
+    while tokens[*offset].token_type != TokenType::CloseParenthesis {
+        try!(consume_token(tokens, offset, &TokenType::OpenParenthesis));
+        let fn_idx = try!(compile_function(compiler, tokens, offset, source, fn_name.clone(), line));
+        let (arity, variadic) = match &compiler.chunk.constants[fn_idx] {
+            Value::Function{params, rest, ..} => (params.len(), rest.is_some()),
+            _ => unreachable!(),
+        };
+        if variadic && arities.iter().any(|&(_, v)| v) {
+            return Err(format!("{} has more than one &rest clause in line {}", fn_name, line))
+        }
+        if arities.contains(&(arity, variadic)) {
+            return Err(format!("{} has more than one clause taking {} arguments in line {}",
+                               fn_name, arity, line))
+        }
+        arities.push((arity, variadic));
+        try!(consume_token(tokens, offset, &TokenType::CloseParenthesis));
+    }
+    let native = natives::find_native("make-multi-arity").unwrap();
+    emit(compiler, OpCode::CallNative(native, arities.len() + 1), line);
+    Ok(())
+}
+
+fn symbol_token(tokens: &Vec<Token>, offset: usize, source: &SourceCode, what: &str) -> Result<String, String> {
+    let token = &tokens[offset];
+    if token.token_type != TokenType::Symbol {
+        return Err(format!("{} must be a symbol, got {}", what, token.token_type))
+    }
+    Ok(token.get_token(source))
+}
+
+// `(defprotocol Printable (show (self)))` defines `show` as a function
+// dispatching on the type of its first argument
+fn compile_defprotocol(compiler: &mut Compiler,
+                       tokens: &Vec<Token>,
+                       offset: &mut usize,
+                       source: &SourceCode)
+                       -> Result<(), String> {
+    let token = &tokens[*offset];
+    try!(advance(tokens, offset));
+    let protocol = try!(symbol_token(tokens, *offset, source, "Protocol name"));
+    try!(advance(tokens, offset));
+    while tokens[*offset].token_type == TokenType::OpenParenthesis {
+        try!(advance(tokens, offset));
+        let method = try!(symbol_token(tokens, *offset, source, "Protocol method name"));
+        try!(advance(tokens, offset));
+        let mut params = vec![];
+        try!(consume_token(tokens, offset, &TokenType::OpenParenthesis));
+        while tokens[*offset].token_type != TokenType::CloseParenthesis {
+            params.push(try!(symbol_token(tokens, *offset, source, "Protocol method parameter")));
+            try!(advance(tokens, offset));
+        }
+        try!(consume_token(tokens, offset, &TokenType::CloseParenthesis));
+        try!(consume_token(tokens, offset, &TokenType::CloseParenthesis));
+        if params.is_empty() {
+            return Err(format!("Protocol method {} needs at least one parameter", method))
+        }
+        // The body passes the protocol, the method and all arguments on
+        let mut chunk = Chunk{
+            code: vec![],
+            constants: vec![],
+            lines: vec![],
+            chunks: vec![],
+            shared_constants: None,
+            label_map: vec![],
+            file: compiler.file.clone(),
+            repl_last_value: false,
+        };
+        let protocol_idx = chunk.write_constant(Value::Symbol(intern(&protocol)));
+        chunk.write_code(OpCode::Constant(protocol_idx), token.line);
+        let method_idx = chunk.write_constant(Value::Symbol(intern(&method)));
+        chunk.write_code(OpCode::Constant(method_idx), token.line);
+        for slot in 0..params.len() {
+            chunk.write_code(OpCode::GetLocal(slot), token.line);
+        }
+        let dispatch = natives::find_native("protocol-dispatch").unwrap();
+        chunk.write_code(OpCode::CallNative(dispatch, params.len() + 2), token.line);
+        chunk.write_code(OpCode::Return, 99);
+        compiler.chunk.chunks.append(&mut vec![chunk]);
+        let chunk_idx = compiler.chunk.chunks.len();
+        let fn_name = qualify_name(compiler, method);
+        let file = compiler.file.clone();
+        let fn_idx = compiler.chunk.write_constant(Value::Function{
+            name: fn_name,
+            params: params,
+            keywords: vec![],
+            rest: None,
+            chunk_idx: chunk_idx,
+            file: file,
+            line: token.line,
+            upvalues: Arc::new(vec![]),
+        });
+        emit(compiler, OpCode::Constant(fn_idx), token.line);
+        emit(compiler, OpCode::DefineGlobal(fn_idx), token.line);
+        emit(compiler, OpCode::Pop, token.line);
+    }
+    let idx = compiler.chunk.write_constant(Value::Symbol(intern(&protocol)));
+    emit(compiler, OpCode::Constant(idx), token.line);
+    Ok(())
+}
+
+// Implementations are globals named after the method and the type, like
+// `show:Int`, which is where protocol-dispatch looks for them
+fn compile_extend_type(compiler: &mut Compiler,
+                       tokens: &Vec<Token>,
+                       offset: &mut usize,
+                       source: &SourceCode)
+                       -> Result<(), String> {
+    let token = &tokens[*offset];
+    try!(advance(tokens, offset));
+    let type_name = try!(symbol_token(tokens, *offset, source, "Type name"));
+    try!(advance(tokens, offset));
+    // The protocol name is for the reader, methods are looked up by name
+    try!(symbol_token(tokens, *offset, source, "Protocol name"));
+    try!(advance(tokens, offset));
+    while tokens[*offset].token_type == TokenType::OpenParenthesis {
+        try!(advance(tokens, offset));
+        let method_line = tokens[*offset].line;
+        let method = try!(symbol_token(tokens, *offset, source, "Protocol method name"));
+        try!(advance(tokens, offset));
+        let fn_name = format!("{}:{}", method, type_name);
+        let fn_idx = try!(compile_function(compiler, tokens, offset, source, fn_name, method_line));
+        try!(consume_token(tokens, offset, &TokenType::CloseParenthesis));
+        emit(compiler, OpCode::DefineGlobal(fn_idx), token.line);
+        emit(compiler, OpCode::Pop, token.line);
+    }
+    let idx = compiler.chunk.write_constant(Value::Symbol(intern(&type_name)));
+    emit(compiler, OpCode::Constant(idx), token.line);
+    Ok(())
+}
+
+// `(defmulti name dispatch-fn)` defines a function taking the same arguments
+// as the dispatch function, which calls the method registered for whatever
+// the dispatch function returns
+fn compile_defmulti(compiler: &mut Compiler,
+                    tokens: &Vec<Token>,
+                    offset: &mut usize,
+                    source: &SourceCode)
+                    -> Result<(), String> {
+    let token = &tokens[*offset];
+    try!(advance(tokens, offset));
+    let name = try!(symbol_token(tokens, *offset, source, "Multimethod name"));
+    try!(advance(tokens, offset));
+    let name = qualify_name(compiler, name);
+    let idx = compiler.chunk.write_constant(Value::Symbol(intern(&name)));
+    emit(compiler, OpCode::Constant(idx), token.line);
+    try!(expression(compiler, tokens, offset, source));
+    emit(compiler, OpCode::CallNative(natives::find_native("make-multimethod").unwrap(), 2), token.line);
+    emit(compiler, OpCode::DefineGlobal(idx), token.line);
+    Ok(())
+}
+
+// `(defmethod name dispatch-value (params...) body...)` adds a method to a
+// multimethod and evaluates to it
+fn compile_defmethod(compiler: &mut Compiler,
+                     tokens: &Vec<Token>,
+                     offset: &mut usize,
+                     source: &SourceCode)
+                     -> Result<(), String> {
+    let token = &tokens[*offset];
+    try!(advance(tokens, offset));
+    let name = try!(symbol_token(tokens, *offset, source, "Multimethod name"));
+    try!(advance(tokens, offset));
+    let name = match &compiler.module {
+        Some(module) if compiler.module_names.contains(&name) => format!("{}/{}", module, name),
+        _ => name,
+    };
+    let idx = compiler.chunk.write_constant(Value::Symbol(intern(&name)));
+    emit(compiler, OpCode::Constant(idx), token.line);
+    try!(expression(compiler, tokens, offset, source));
+    let method_line = tokens[*offset].line;
+    try!(compile_function(compiler, tokens, offset, source, name, method_line));
+    emit(compiler, OpCode::CallNative(natives::find_native("add-method").unwrap(), 3), token.line);
+    Ok(())
+}
+
+// Anonymous functions, `(fn (a b) body...)`, evaluate to the function itself
+fn compile_fn(compiler: &mut Compiler,
+              tokens: &Vec<Token>,
               offset: &mut usize,
               source: &SourceCode)
               -> Result<(), String> {
+    let start_token = &tokens[*offset];
+    try!(advance(tokens, offset));
+    try!(compile_function(compiler, tokens, offset, source, String::from("fn"), start_token.line));
+    Ok(())
+}
+
+// `(bench n body...)` runs the body n times as a function and prints timing
+// statistics
+fn compile_bench(compiler: &mut Compiler,
+                 tokens: &Vec<Token>,
+                 offset: &mut usize,
+                 source: &SourceCode)
+                 -> Result<(), String> {
+    let start_token = &tokens[*offset];
+    try!(advance(tokens, offset));
+    try!(expression(compiler, tokens, offset, source));
+    try!(compile_thunk(compiler, tokens, offset, source, String::from("bench"), start_token.line));
+    let bench_idx = natives::find_native("run-bench").unwrap();
+    emit(compiler, OpCode::CallNative(bench_idx, 2), start_token.line);
+    Ok(())
+}
+
+fn compile_module(compiler: &mut Compiler,
+                  tokens: &Vec<Token>,
+                  offset: &mut usize,
+                  source: &SourceCode)
+                  -> Result<(), String> {
+    try!(advance(tokens, offset));
+    let name_token = &tokens[*offset];
+    if name_token.token_type != TokenType::Symbol {
+        return Err(format!("Module name needs to be a symbol, got {}", name_token.token_type))
+    }
+    let outer_module = compiler.module.take();
+    let outer_names = std::mem::replace(&mut compiler.module_names, vec![]);
+    compiler.module = Some(name_token.get_token(source));
+    try!(advance(tokens, offset));
+    let result = do_expressions(compiler, tokens, offset, source);
+    compiler.module = outer_module;
+    compiler.module_names = outer_names;
+    result
+}
+
+// Finds `<name>.losp` in the directories listed in LOSP_PATH, or the current
+// directory if it is not set
+fn find_module(name: &str) -> Result<std::path::PathBuf, String> {
+    let load_path = match std::env::var_os("LOSP_PATH") {
+        Some(paths) => std::env::split_paths(&paths).collect(),
+        None => vec![std::path::PathBuf::from(".")],
+    };
+    for dir in load_path {
+        let path = dir.join(format!("{}.losp", name));
+        if path.is_file() {
+            return Ok(path)
+        }
+    }
+    Err(format!("Module {} not found", name))
+}
+
+fn compile_require(compiler: &mut Compiler,
+                   tokens: &Vec<Token>,
+                   offset: &mut usize,
+                   source: &SourceCode)
+                   -> Result<(), String> {
+    let token = &tokens[*offset];
+    try!(advance(tokens, offset));
+    let name_token = &tokens[*offset];
+    if name_token.token_type != TokenType::Symbol {
+        return Err(format!("Module name needs to be a symbol, got {}", name_token.token_type))
+    }
+    let name = name_token.get_token(source);
+    try!(advance(tokens, offset));
+    let path = try!(find_module(&name));
+    let mut module_source: Cow<SourceCode> = match std::fs::read_to_string(&path) {
+        Ok(s) => Cow::Owned(s.chars().collect()),
+        Err(e) => return Err(format!("Cannot read module {}: {}", name, e)),
+    };
+    let module_tokens = read_tokens_with_case(&mut module_source, false, compiler.case_sensitive);
+    // Compile the module inline, in a fresh module context
+    let outer_module = compiler.module.replace(name.clone());
+    let outer_names = std::mem::replace(&mut compiler.module_names, vec![]);
+    let outer_locals = std::mem::replace(&mut compiler.locals, vec![]);
+    let outer_file = compiler.file.replace(path.display().to_string());
+    let mut module_offset = 0;
+    let mut result = Ok(());
+    while module_offset < module_tokens.len() - 1 {
+        let module_token = &module_tokens[module_offset];
+        if module_token.token_type == TokenType::EOF {
+            break
+        }
+        if module_token.is_error() {
+            result = Err(format!("Lexing error: {}", module_token.token_type));
+            break
+        }
+        result = expression(compiler, &module_tokens, &mut module_offset, &module_source);
+        if result.is_err() {
+            break
+        }
+        emit(compiler, OpCode::Pop, token.line);
+    }
+    compiler.module = outer_module;
+    compiler.module_names = outer_names;
+    compiler.locals = outer_locals;
+    compiler.file = outer_file;
+    if let Err(msg) = result {
+        return Err(format!("In module {}: {}", name, msg))
+    }
+    let idx = compiler.chunk.write_constant(Value::Symbol(intern(&name)));
+    emit(compiler, OpCode::Constant(idx), token.line);
+    Ok(())
+}
+
+fn compile_fn_call(compiler: &mut Compiler,
+                   tokens: &Vec<Token>,
+                   offset: &mut usize,
+                   source: &SourceCode)
+                   -> Result<(), String> {
+    let token = &tokens[*offset];
+    let fn_name = token.get_token(source);
+    let mut custom = is_shadowed(compiler, &fn_name);
+    let mut native = None;
+    let mut ops = match fn_name.as_str() {
+        _ if custom => vec![],
+        "+" => vec![OpCode::Add],
+        "-" => vec![OpCode::Subtract],
+        "*" => vec![OpCode::Multiply],
+        "/" => vec![OpCode::Divide],
+        "not" => vec![OpCode::Not],
+        "=" => vec![OpCode::Equal],
+        ">" => vec![OpCode::GreaterThan],
+        ">=" => vec![OpCode::LessThan, OpCode::Not],
+        "<" => vec![OpCode::LessThan],
+        "<=" => vec![OpCode::GreaterThan, OpCode::Not],
+        "print" | "println" => vec![OpCode::Print],
+        _ => {
+            native = natives::find_native(&fn_name);
+            custom = native.is_none();
+            // Gets filled in later
+            vec![]
+        }
+    };
+    if custom {
+        // Custom functions get pushed to the stack first.
+        try!(expression(compiler, tokens, offset, source));
+    } else {
+        try!(advance(tokens, offset));
+    }
+    let mut argc = 0;
+    while tokens[*offset].token_type != TokenType::CloseParenthesis {
+        argc += 1;
+        try!(expression(compiler, tokens, offset, source));
+    }
+    if (fn_name == "print" || fn_name == "println") && argc == 2 {
+        // Printing to a port goes through the native instead
+        native = natives::find_native("write-line");
+    }
+    if custom {
+        ops = vec![OpCode::Call(argc)];
+    } else if let Some(idx) = native {
+        ops = vec![OpCode::CallNative(idx, argc)];
+    }
+    for op in ops {
+        emit(compiler, op, token.line);
+    }
+    Ok(())
+}
+
+fn compile_sexp(compiler: &mut Compiler,
+                tokens: &Vec<Token>,
+                offset: &mut usize,
+                source: &SourceCode)
+                -> Result<(), String> {
+    compiler.sexp_depth += 1;
+    try!(advance(tokens, offset));
     let token = &tokens[*offset];
+    // Keywords and quoted symbols are called to look themselves up in a map,
+    // and a nested expression or a function in an evaluated form is called
+    // with whatever it evaluates to
     match token.token_type {
-        TokenType::OpenParenthesis => try!(compile_sexp(compiler, tokens, offset, source)),
-        TokenType::Nil => {
-            let idx = compiler.chunk.write_constant(Value::Nil);
-            compiler.chunk.write_code(OpCode::Constant(idx), token.line);
+        TokenType::Symbol | TokenType::Keyword | TokenType::Quote | TokenType::OpenParenthesis
+            | TokenType::Value(_) => (),
+        _ => return Err(format!("Function name must be a symbol, got {}", token.token_type)),
+    }
+    let fn_name = token.get_token(source);
+    match fn_name.as_str() {
+        "def" => try!(compile_def(compiler, tokens, offset, source)),
+        "def-alias" => try!(compile_def_alias(compiler, tokens, offset, source)),
+        "let" => try!(compile_let(compiler, tokens, offset, source)),
+        "when" => try!(compile_when(compiler, tokens, offset, source)),
+        "if" => try!(compile_if(compiler, tokens, offset, source)),
+        "and" => try!(compile_and(compiler, tokens, offset, source)),
+        "or" => try!(compile_or(compiler, tokens, offset, source)),
+        "while" => try!(compile_while(compiler, tokens, offset, source)),
+        "do-while" => try!(compile_do_while(compiler, tokens, offset, source)),
+        "local-bindings" => try!(compile_local_bindings(compiler, tokens, offset)),
+        "break" => try!(compile_break(compiler, tokens, offset)),
+        "continue" => try!(compile_continue(compiler, tokens, offset)),
+        "defn" => try!(compile_defn(compiler, tokens, offset, source)),
+        "fn" => try!(compile_fn(compiler, tokens, offset, source)),
+        "for" => try!(compile_for(compiler, tokens, offset, source)),
+        "for-map" => try!(compile_for_map(compiler, tokens, offset, source)),
+        "try" => try!(compile_try(compiler, tokens, offset, source)),
+        "defprotocol" => try!(compile_defprotocol(compiler, tokens, offset, source)),
+        "extend-type" => try!(compile_extend_type(compiler, tokens, offset, source)),
+        "defmulti" => try!(compile_defmulti(compiler, tokens, offset, source)),
+        "defmethod" => try!(compile_defmethod(compiler, tokens, offset, source)),
+        "with-string-builder" => try!(compile_with_string_builder(compiler, tokens, offset, source)),
+        "bench" => try!(compile_bench(compiler, tokens, offset, source)),
+        "module" => try!(compile_module(compiler, tokens, offset, source)),
+        "require" => try!(compile_require(compiler, tokens, offset, source)),
+        "quote" => {
             try!(advance(tokens, offset));
+            try!(compile_quote(compiler, tokens, offset, source));
         }
-        TokenType::Bool => {
-            let val: bool = token.get_token(source) == "true";
-            let idx = compiler.chunk.write_constant(Value::Bool(val));
-            compiler.chunk.write_code(OpCode::Constant(idx), token.line);
+        "do" => {
             try!(advance(tokens, offset));
+            try!(do_expressions(compiler, tokens, offset, source));
         }
+        _ => try!(compile_fn_call(compiler, tokens, offset, source)),
+    }
+    try!(consume_token(tokens, offset, &TokenType::CloseParenthesis));
+    compiler.sexp_depth -= 1;
+    Ok(())
+}
+
+fn parse_literal(token: &Token, source: &SourceCode) -> Result<Value, String> {
+    match token.token_type {
+        TokenType::Nil => Ok(Value::Nil),
+        TokenType::Bool => Ok(Value::Bool(token.get_token(source) == "true")),
         TokenType::Int => {
-            let val: i64 = token.get_token(source).parse().unwrap();
-            let idx = compiler.chunk.write_constant(Value::Int(val));
-            compiler.chunk.write_code(OpCode::Constant(idx), token.line);
-            try!(advance(tokens, offset));
+            let raw_val = token.get_token(source);
+            match raw_val.parse() {
+                Ok(n) => Ok(Value::Int(n)),
+                // Too large for an i64
+                Err(_) => match BigInt::parse(&raw_val) {
+                    Some(n) => Ok(Value::BigInt(n)),
+                    None => Err(format!("Invalid integer {}", raw_val)),
+                },
+            }
         }
         TokenType::Float => {
             let mut raw_val = token.get_token(source);
@@ -411,46 +1807,289 @@ fn expression(compiler: &mut Compiler,
                 // Parse ".3" as 0.3
                 raw_val.insert_str(0, "0");
             }
-            let val: f64 = raw_val.parse().unwrap();
-            let idx = compiler.chunk.write_constant(Value::Float(val));
-            compiler.chunk.write_code(OpCode::Constant(idx), token.line);
+            match raw_val.parse() {
+                Ok(f) => Ok(Value::Float(f)),
+                Err(_) => Err(format!("Invalid float {}", raw_val)),
+            }
+        }
+        TokenType::String => Ok(Value::String(token.get_token(source))),
+        TokenType::Char => {
+            let name = token.get_token(source);
+            let mut chars = name.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Ok(Value::Char(c)),
+                _ => match name.as_str() {
+                    "space" => Ok(Value::Char(' ')),
+                    "newline" => Ok(Value::Char('\n')),
+                    "tab" => Ok(Value::Char('\t')),
+                    "return" => Ok(Value::Char('\r')),
+                    _ => Err(format!("Unknown character \\{} in line {}", name, token.line)),
+                },
+            }
+        }
+        _ => Err(format!("Expected a literal, got {}", token.token_type)),
+    }
+}
+
+// Reads the next form as data instead of compiling it
+fn read_datum(tokens: &Vec<Token>,
+              offset: &mut usize,
+              source: &SourceCode)
+              -> Result<Value, String> {
+    let token = &tokens[*offset];
+    let val = match token.token_type {
+        TokenType::Nil
+            | TokenType::Bool
+            | TokenType::Int
+            | TokenType::Float
+            | TokenType::String
+            | TokenType::Char => try!(parse_literal(token, source)),
+        TokenType::Symbol => Value::Symbol(intern(&token.get_token(source))),
+        TokenType::Keyword => Value::Keyword(token.get_token(source)[1..].to_string()),
+        TokenType::Value(Embedded(ref v)) => v.clone(),
+        TokenType::Quote
+            | TokenType::Quasiquote
+            | TokenType::Unquote
+            | TokenType::UnquoteSplicing => {
+            let name = match token.token_type {
+                TokenType::Quote => "quote",
+                TokenType::Quasiquote => "quasiquote",
+                TokenType::Unquote => "unquote",
+                _ => "unquote-splicing",
+            };
             try!(advance(tokens, offset));
+            let quoted = try!(read_datum(tokens, offset, source));
+            return Ok(Value::List(vec![Value::Symbol(intern(name)), quoted]))
         }
-        TokenType::Keyword => {
-            println!("parsed a keyword: {}", token.get_token(source));
+        TokenType::OpenParenthesis => {
             try!(advance(tokens, offset));
+            let mut items = vec![];
+            while tokens[*offset].token_type != TokenType::CloseParenthesis {
+                items.push(try!(read_datum(tokens, offset, source)));
+            }
+            if items.is_empty() {
+                // '() is nil
+                Value::Nil
+            } else {
+                Value::List(items)
+            }
         }
-        TokenType::String => {
-            let val = token.get_token(source);
-            let idx = compiler.chunk.write_constant(Value::String(val));
-            compiler.chunk.write_code(OpCode::Constant(idx), token.line);
+        TokenType::OpenBracket => {
             try!(advance(tokens, offset));
+            let mut items = vec![];
+            while tokens[*offset].token_type != TokenType::CloseBracket {
+                items.push(try!(read_datum(tokens, offset, source)));
+            }
+            Value::Vector(items)
         }
-        TokenType::Symbol => {
-            let val = token.get_token(source);
-            let local_count = compiler.locals.len();
-            let mut is_local = false;
-            for i in 0..local_count {
-                let idx = local_count - i - 1;
-                if compiler.locals[idx].name == val {
-                    compiler.chunk.write_code(OpCode::GetLocal(idx), token.line);
-                    is_local = true;
-                    break
+        TokenType::OpenBrace => {
+            try!(advance(tokens, offset));
+            let mut map = OrderedMap::new();
+            while tokens[*offset].token_type != TokenType::CloseBrace {
+                let key = try!(read_datum(tokens, offset, source));
+                if tokens[*offset].token_type == TokenType::CloseBrace {
+                    return Err(String::from("Map literal needs an even number of forms"))
+                }
+                let value = try!(read_datum(tokens, offset, source));
+                map.insert(key.map_key(), (key, value));
+            }
+            Value::Map(map)
+        }
+        _ => return Err(format!("Cannot read {}", token.token_type)),
+    };
+    try!(advance(tokens, offset));
+    Ok(val)
+}
+
+// Vector literals evaluate their elements, `[a b]` is `(vector a b)`
+fn compile_vector(compiler: &mut Compiler,
+                  tokens: &Vec<Token>,
+                  offset: &mut usize,
+                  source: &SourceCode)
+                  -> Result<(), String> {
+    let token = &tokens[*offset];
+    try!(advance(tokens, offset));
+    compiler.sexp_depth += 1;
+    let mut argc = 0;
+    while tokens[*offset].token_type != TokenType::CloseBracket {
+        if tokens[*offset].token_type == TokenType::EOF {
+            return Err(String::from("Unterminated vector"))
+        }
+        try!(expression(compiler, tokens, offset, source));
+        argc += 1;
+    }
+    try!(consume_token(tokens, offset, &TokenType::CloseBracket));
+    compiler.sexp_depth -= 1;
+    emit(compiler, OpCode::MakeVector(argc), token.line);
+    Ok(())
+}
+
+// Map literals evaluate their keys and values, `{a b}` is like `(hash-map a b)`
+fn compile_map(compiler: &mut Compiler,
+               tokens: &Vec<Token>,
+               offset: &mut usize,
+               source: &SourceCode)
+               -> Result<(), String> {
+    let token = &tokens[*offset];
+    try!(advance(tokens, offset));
+    compiler.sexp_depth += 1;
+    let mut argc = 0;
+    while tokens[*offset].token_type != TokenType::CloseBrace {
+        if tokens[*offset].token_type == TokenType::EOF {
+            return Err(String::from("Unterminated map"))
+        }
+        try!(expression(compiler, tokens, offset, source));
+        argc += 1;
+    }
+    if argc % 2 != 0 {
+        return Err(String::from("Map literal needs an even number of forms"))
+    }
+    try!(consume_token(tokens, offset, &TokenType::CloseBrace));
+    compiler.sexp_depth -= 1;
+    emit(compiler, OpCode::MakeMap(argc / 2), token.line);
+    Ok(())
+}
+
+fn compile_quote(compiler: &mut Compiler,
+                 tokens: &Vec<Token>,
+                 offset: &mut usize,
+                 source: &SourceCode)
+                 -> Result<(), String> {
+    let token = &tokens[*offset];
+    let val = try!(read_datum(tokens, offset, source));
+    emit_value(compiler, val, token.line);
+    Ok(())
+}
+
+// Function constants are renumbered when their chunk is loaded, as they are
+// compiled along with it. Functions from the running program can end up in
+// forms passed to eval, so those are wrapped in a vector and taken out again.
+fn emit_value(compiler: &mut Compiler, val: Value, line: Line) {
+    if let Value::Function{..} = val {
+        let idx = compiler.chunk.write_constant(Value::Vector(vec![val]));
+        emit(compiler, OpCode::Constant(idx), line);
+        emit(compiler, OpCode::CallNative(natives::find_native("first").unwrap(), 1), line);
+    } else {
+        let idx = compiler.chunk.write_constant(val);
+        emit(compiler, OpCode::Constant(idx), line);
+    }
+}
+
+// Templates are desugared into calls to `list` and `concat`, so
+// `(a ~b ~@c) becomes (concat (list 'a) (list b) c)
+fn compile_quasiquote(compiler: &mut Compiler,
+                      tokens: &Vec<Token>,
+                      offset: &mut usize,
+                      source: &SourceCode)
+                      -> Result<(), String> {
+    let token = &tokens[*offset];
+    let concat_idx = natives::find_native("concat").unwrap();
+    match token.token_type {
+        TokenType::OpenParenthesis => {
+            try!(advance(tokens, offset));
+            let mut parts = 0;
+            while tokens[*offset].token_type != TokenType::CloseParenthesis {
+                let part_token = &tokens[*offset];
+                match part_token.token_type {
+                    TokenType::Unquote => {
+                        try!(advance(tokens, offset));
+                        try!(expression(compiler, tokens, offset, source));
+                        emit(compiler, OpCode::MakeList(1), part_token.line);
+                    }
+                    TokenType::UnquoteSplicing => {
+                        try!(advance(tokens, offset));
+                        try!(expression(compiler, tokens, offset, source));
+                    }
+                    _ => {
+                        try!(compile_quasiquote(compiler, tokens, offset, source));
+                        emit(compiler, OpCode::MakeList(1), part_token.line);
+                    }
                 }
+                parts += 1;
             }
-            if !is_local {
-                let idx = compiler.chunk.write_constant(Value::Symbol(val));
-                compiler.chunk.write_code(OpCode::GetGlobal(idx), token.line);
+            try!(advance(tokens, offset));
+            emit(compiler, OpCode::CallNative(concat_idx, parts), token.line);
+            Ok(())
+        }
+        TokenType::Unquote => {
+            try!(advance(tokens, offset));
+            expression(compiler, tokens, offset, source)
+        }
+        TokenType::UnquoteSplicing => Err(String::from("Can only splice inside a list")),
+        _ => compile_quote(compiler, tokens, offset, source),
+    }
+}
+
+fn expression(compiler: &mut Compiler,
+              tokens: &Vec<Token>,
+              offset: &mut usize,
+              source: &SourceCode)
+              -> Result<(), String> {
+    let token = &tokens[*offset];
+    match token.token_type {
+        TokenType::OpenParenthesis => try!(compile_sexp(compiler, tokens, offset, source)),
+        TokenType::OpenBracket => try!(compile_vector(compiler, tokens, offset, source)),
+        TokenType::OpenBrace => try!(compile_map(compiler, tokens, offset, source)),
+        TokenType::Nil
+            | TokenType::Bool
+            | TokenType::Int
+            | TokenType::Float
+            | TokenType::String
+            | TokenType::Char => {
+            let val = try!(parse_literal(token, source));
+            let idx = compiler.chunk.write_constant(val);
+            emit(compiler, OpCode::Constant(idx), token.line);
+            try!(advance(tokens, offset));
+        }
+        TokenType::Value(Embedded(ref val)) => {
+            emit_value(compiler, val.clone(), token.line);
+            try!(advance(tokens, offset));
+        }
+        TokenType::Quote => {
+            try!(advance(tokens, offset));
+            try!(compile_quote(compiler, tokens, offset, source));
+        }
+        TokenType::Quasiquote => {
+            try!(advance(tokens, offset));
+            // Unquoted expressions are not top-level expressions
+            compiler.sexp_depth += 1;
+            try!(compile_quasiquote(compiler, tokens, offset, source));
+            compiler.sexp_depth -= 1;
+        }
+        TokenType::Unquote | TokenType::UnquoteSplicing => {
+            return Err(String::from("Unquote outside of quasiquote"))
+        }
+        TokenType::Keyword => {
+            let val = Value::Keyword(token.get_token(source)[1..].to_string());
+            let idx = compiler.chunk.write_constant(val);
+            emit(compiler, OpCode::Constant(idx), token.line);
+            try!(advance(tokens, offset));
+        }
+        TokenType::Symbol => {
+            let val = token.get_token(source);
+            if let Some(slot) = resolve_local(compiler, &val) {
+                emit(compiler, OpCode::GetLocal(slot), token.line);
+            } else if let Some(idx) = resolve_upvalue(compiler, &val) {
+                emit(compiler, OpCode::GetUpvalue(idx), token.line);
+            } else {
+                let name = match &compiler.module {
+                    Some(module) if compiler.module_names.contains(&val) =>
+                        format!("{}/{}", module, val),
+                    _ => val,
+                };
+                let idx = compiler.chunk.write_constant(Value::Symbol(intern(&name)));
+                emit(compiler, OpCode::GetGlobal(idx), token.line);
             }
             try!(advance(tokens, offset));
         }
         TokenType::EOF => {
             try!(advance(tokens, offset));
         }
+        TokenType::Error(_) => return Err(format!("Lexing error: {}", token.token_type)),
         _ => panic!("Token type not implemented: {}", token.token_type),
     };
     if compiler.is_main && compiler.sexp_depth == 0 {
-        compiler.chunk.write_code(OpCode::Pop, token.line);
+        emit(compiler, OpCode::Pop, token.line);
     }
     Ok(())
 }
@@ -466,40 +2105,304 @@ fn consume_token(tokens: &Vec<Token>, offset: &mut usize, expected_type: &TokenT
     }
 }
 
-fn compile(source: &SourceCode, debug: bool) -> Result<Chunk, String> {
+// Reads all forms in source as data, without compiling them
+pub fn read_all(source: &str) -> Result<Vec<Value>, String> {
+    // The scanner expects a trailing character after the last token
+    let source_chars: SourceCode = format!("{}\n", source).chars().collect();
+    let tokens = read_tokens(&source_chars, false);
+    let mut offset = 0;
+    let mut forms = vec![];
+    while tokens[offset].token_type != TokenType::EOF {
+        let token = &tokens[offset];
+        if token.is_error() {
+            return Err(format!("Lexing error: {}", token.token_type));
+        }
+        forms.push(try!(read_datum(&tokens, &mut offset, &source_chars)));
+    }
+    Ok(forms)
+}
+
+// Compiles source so that it evaluates to the value of its last form
+pub fn compile_eval(source: &str, config: &CompilerConfig) -> Result<Chunk, String> {
+    // The scanner expects a trailing character after the last token
+    let mut source_chars: Cow<SourceCode> = Cow::Owned(format!("{}\n", source).chars().collect());
+    let tokens = read_tokens_with_case(&mut source_chars, config.debug, config.case_sensitive);
+    compile_eval_tokens(&tokens, &source_chars, config)
+}
+
+// Compiles a form that is already data, like a quoted list, so that it
+// evaluates to its value. Values that can't be read back in, like functions,
+// are compiled as they are.
+pub fn compile_form(form: &Value, config: &CompilerConfig) -> Result<Chunk, String> {
+    let mut tokens = vec![];
+    let mut source = vec![];
+    form_tokens(form, &mut tokens, &mut source);
+    push_token(&mut tokens, &mut source, TokenType::EOF, "");
+    compile_eval_tokens(&tokens, &source, config)
+}
+
+// Only delimiters, symbols and keywords get source text
+fn form_tokens(form: &Value, tokens: &mut Vec<Token>, source: &mut SourceCode) {
+    match form {
+        Value::WithMeta(v, _) => form_tokens(v, tokens, source),
+        Value::List(items) if !items.is_empty() => {
+            // Undoes what read_datum does with quotes
+            let prefix = match (&items[0], items.len()) {
+                (Value::Symbol(s), 2) => match &**s {
+                    "quote" => Some((TokenType::Quote, "'")),
+                    "quasiquote" => Some((TokenType::Quasiquote, "`")),
+                    "unquote" => Some((TokenType::Unquote, "~")),
+                    "unquote-splicing" => Some((TokenType::UnquoteSplicing, "~@")),
+                    _ => None,
+                },
+                _ => None,
+            };
+            if let Some((token_type, text)) = prefix {
+                push_token(tokens, source, token_type, text);
+                return form_tokens(&items[1], tokens, source)
+            }
+            push_token(tokens, source, TokenType::OpenParenthesis, "(");
+            for item in items {
+                form_tokens(item, tokens, source);
+            }
+            push_token(tokens, source, TokenType::CloseParenthesis, ")");
+        }
+        Value::Vector(items) => {
+            push_token(tokens, source, TokenType::OpenBracket, "[");
+            for item in items {
+                form_tokens(item, tokens, source);
+            }
+            push_token(tokens, source, TokenType::CloseBracket, "]");
+        }
+        Value::Map(map) => {
+            push_token(tokens, source, TokenType::OpenBrace, "{");
+            for (key, value) in map.values() {
+                form_tokens(key, tokens, source);
+                form_tokens(value, tokens, source);
+            }
+            push_token(tokens, source, TokenType::CloseBrace, "}");
+        }
+        Value::Symbol(s) => push_token(tokens, source, TokenType::Symbol, s),
+        Value::Keyword(k) => push_token(tokens, source, TokenType::Keyword, &format!(":{}", k)),
+        v => push_token(tokens, source, TokenType::Value(Embedded(v.clone())), ""),
+    }
+}
+
+fn push_token(tokens: &mut Vec<Token>, source: &mut SourceCode, token_type: TokenType, text: &str) {
+    let start = source.len();
+    source.extend(text.chars());
+    tokens.push(Token{token_type: token_type, line: 1, start: start, length: source.len() - start});
+    source.push(' ');
+}
+
+fn compile_eval_tokens(tokens: &Vec<Token>, source: &SourceCode, config: &CompilerConfig) -> Result<Chunk, String> {
+    let mut compiler = Compiler{
+        chunk: Chunk{
+            code: vec![],
+            constants: vec![],
+            lines: vec![],
+            chunks: vec![],
+            shared_constants: None,
+            label_map: vec![],
+            file: config.file.clone(),
+            repl_last_value: false,
+        },
+        locals: vec![],
+        scope_depth: 0,
+        sexp_depth: 0,
+        is_main: false,
+        stack_depth: 0,
+        module: None,
+        module_names: vec![],
+        file: config.file.clone(),
+        loop_stack: vec![],
+        enclosing: vec![],
+        upvalues: vec![],
+        case_sensitive: config.case_sensitive,
+    };
+    let mut offset = 0;
+    let mut forms = 0;
+    while tokens[offset].token_type != TokenType::EOF {
+        let token = &tokens[offset];
+        if token.is_error() {
+            return Err(format!("Lexing error: {}", token.token_type));
+        }
+        if 0 < forms {
+            emit(&mut compiler, OpCode::Pop, token.line);
+        }
+        try!(expression(&mut compiler, tokens, &mut offset, source));
+        forms += 1;
+    }
+    if forms == 0 {
+        let idx = compiler.chunk.write_constant(Value::Nil);
+        emit(&mut compiler, OpCode::Constant(idx), 1);
+    }
+    compiler.chunk.write_code(OpCode::Return, 99);
+    if let Some(max) = config.max_constants {
+        try!(check_constants(&compiler.chunk, max));
+    }
+    if config.optimise {
+        compiler.chunk.constant_fold();
+    }
+    Ok(compiler.chunk)
+}
+
+// Checks the constant pool of a chunk and of all functions defined in it
+fn check_constants(chunk: &Chunk, max: usize) -> Result<(), String> {
+    if max < chunk.constants.len() {
+        return Err(format!("Too many constants: {}, maximum is {}", chunk.constants.len(), max));
+    }
+    for inner in chunk.chunks.iter() {
+        try!(check_constants(inner, max));
+    }
+    Ok(())
+}
+
+// Compiles a whole program, which evaluates to nil, or in REPL mode to the
+// value of its last expression
+pub fn compile_source(source: &SourceCode, config: &CompilerConfig) -> Result<Chunk, String> {
+    let repl = config.mode == CompilerMode::Repl;
     let chunk = Chunk{
         code: vec![],
         constants: vec![],
         lines: vec![],
         chunks: vec![],
+        shared_constants: None,
+        label_map: vec![],
+        file: config.file.clone(),
+        repl_last_value: repl,
     };
     let mut compiler = Compiler{
         chunk: chunk,
         locals: vec![],
         scope_depth: 0,
         sexp_depth: 0,
-        is_main: true,
+        // In REPL mode values are dropped between expressions here, so
+        // the last one stays on the stack
+        is_main: !repl,
+        stack_depth: 0,
+        module: None,
+        module_names: vec![],
+        file: config.file.clone(),
+        loop_stack: vec![],
+        enclosing: vec![],
+        upvalues: vec![],
+        case_sensitive: config.case_sensitive,
     };
-    let tokens = scanner::scan(&source, debug);
+    let mut source = Cow::Borrowed(source);
+    let tokens = read_tokens_with_case(&mut source, config.debug, config.case_sensitive);
     let mut offset = 0;
+    let mut forms = 0;
     let token_count = tokens.len();
     while offset < token_count - 1 {
         let token = &tokens[offset];
         if token.is_error() {
             return Err(format!("Lexing error: {}", token.token_type));
         } else {
+            if repl && 0 < forms {
+                emit(&mut compiler, OpCode::Pop, token.line);
+            }
             let exp = expression(&mut compiler, &tokens, &mut offset, &source);
             if exp.is_err() {
                 return Err(exp.err().unwrap());
             }
+            forms += 1;
         }
     }
+    if repl && forms == 0 {
+        let idx = compiler.chunk.write_constant(Value::Nil);
+        emit(&mut compiler, OpCode::Constant(idx), 1);
+    }
     compiler.chunk.write_code(OpCode::Return, 99);
+    if let Some(max) = config.max_constants {
+        try!(check_constants(&compiler.chunk, max));
+    }
+    if config.optimise {
+        compiler.chunk.constant_fold();
+    }
     Ok(compiler.chunk)
 }
 
-pub fn interpret<'a>(vm: &mut VM, source: String, debug: bool) -> Result<(), String> {
+pub fn compile_str(source: &str, config: &CompilerConfig) -> Result<Chunk, String> {
     let source_chars: SourceCode = source.chars().collect();
-    let chunk = try!(compile(&source_chars, debug));
-    vm.interpret(chunk, debug)
+    compile_source(&source_chars, config)
+}
+
+#[allow(dead_code)]
+pub fn compile_file(path: &Path, config: &CompilerConfig) -> Result<Chunk, String> {
+    match std::fs::read_to_string(path) {
+        Ok(source) => compile_str(&source, &config.clone().with_file(&path.display().to_string())),
+        Err(e) => Err(format!("Cannot read {}: {}", path.display(), e)),
+    }
+}
+
+pub fn interpret<'a>(vm: &mut VM, source: String, config: &CompilerConfig) -> Result<Option<Value>, String> {
+    let chunk = try!(compile_str(&source, config));
+    vm.interpret(chunk, config.debug)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compiler::vm::init_vm;
+
+    fn run(source: &str, config: CompilerConfig) -> Result<Option<Value>, String> {
+        let mut vm = init_vm();
+        interpret(&mut vm, format!("{}\n", source), &config.with_mode(CompilerMode::Repl))
+    }
+
+    #[test]
+    fn symbols_are_lowercased_without_case_sensitivity() {
+        let config = CompilerConfig::new().with_case_sensitivity(false);
+        match run("(def FOO 42) foo", config) {
+            Ok(Some(Value::Int(42))) => (),
+            result => panic!("expected 42, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn symbols_keep_their_case_with_case_sensitivity() {
+        let error = run("(def FOO 42) foo", CompilerConfig::new()).unwrap_err();
+        assert!(error.starts_with("Symbol foo not found"), "{}", error);
+    }
+
+    #[test]
+    fn vector_literals_compile_to_make_vector() {
+        let chunk = compile_str("[1 2 3]\n", &CompilerConfig::new()).unwrap();
+        match &chunk.code[..4] {
+            [OpCode::Constant(a), OpCode::Constant(b), OpCode::Constant(c), OpCode::MakeVector(3)] => {
+                let values: Vec<&Value> = [a, b, c].iter().map(|idx| &chunk.constants[**idx]).collect();
+                match values[..] {
+                    [Value::Int(1), Value::Int(2), Value::Int(3)] => (),
+                    _ => panic!("unexpected constants {:?}", values),
+                }
+            }
+            code => panic!("unexpected code {:?}", code),
+        }
+    }
+
+    #[test]
+    fn require_finds_modules_in_losp_path() {
+        // Other tests don't touch LOSP_PATH, so changing it here is safe.
+        // Like test.losp, this runs from the repository root.
+        std::env::remove_var("LOSP_PATH");
+        let error = run("(require shapes)", CompilerConfig::new()).unwrap_err();
+        assert_eq!(error, "Module shapes not found");
+        std::env::set_var("LOSP_PATH", "tests/missing:tests/modules");
+        match run("(require shapes) (+ (shapes/area 2 3) (shapes/triangle-area 4 5))", CompilerConfig::new()) {
+            Ok(Some(Value::Int(16))) => (),
+            result => panic!("expected 16, got {:?}", result),
+        }
+        std::env::remove_var("LOSP_PATH");
+    }
+
+    #[test]
+    fn max_constants_applies_to_functions() {
+        let config = CompilerConfig::new().with_max_constants(2);
+        assert!(compile_str("(print 1 2)\n", &config).is_ok());
+        let error = compile_str("(defn f [] (print 1 2 3))\n", &config).unwrap_err();
+        assert!(error.starts_with("Too many constants: 3"), "{}", error);
+        let error = compile_eval("(fn [] (print 1 2 3))", &config).unwrap_err();
+        assert!(error.starts_with("Too many constants: 3"), "{}", error);
+    }
 }
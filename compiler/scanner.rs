@@ -1,7 +1,12 @@
+use super::vm::Value;
+
 #[derive(Debug,PartialEq)]
 pub enum ScanError {
     UnterminatedString,
+    UnterminatedBlockComment,
+    UnterminatedSymbol,
     EmptyKeyword,
+    UnknownDispatch,
     RanOff,
 }
 
@@ -12,13 +17,24 @@ pub enum TokenType {
     OpenBracket, CloseBracket,
     OpenBrace, CloseBrace,
     // literals
-    Nil, Bool, Int, Float, String,
+    Nil, Bool, Int, Float, String, Char,
     // special syntax
     Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
+    DatumComment,
+    // trivia, only kept by `scan_with_trivia`
+    Whitespace,
+    LineComment(String),
+    BlockComment(String),
     // keywords
     Keyword,
     // symbols
     Symbol,
+    // never scanned, stands for a value in a form that is compiled as it is,
+    // see compiler::compile_form
+    Value(Embedded),
     // we're done here
     EOF,
     // i am
@@ -29,21 +45,35 @@ impl std::fmt::Display for TokenType {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             TokenType::Error(ScanError::UnterminatedString) => write!(f, "Unterminated string"),
+            TokenType::Error(ScanError::UnterminatedBlockComment) => write!(f, "Unterminated block comment"),
+            TokenType::Error(ScanError::UnterminatedSymbol) => write!(f, "Unterminated symbol"),
             TokenType::Error(ScanError::EmptyKeyword) => write!(f, "Empty keyword"),
+            TokenType::Error(ScanError::UnknownDispatch) => write!(f, "Unknown dispatch macro"),
             TokenType::Error(ScanError::RanOff) => write!(f, "Ran off"),
-            _ => write!(f, "{}", self),
+            _ => write!(f, "{:?}", self),
         }
     }
 }
 
+// Values are compared by their printed form, which is how map keys work
+#[derive(Debug)]
+pub struct Embedded(pub Value);
+
+impl PartialEq for Embedded {
+    fn eq(&self, other: &Embedded) -> bool {
+        self.0.map_key() == other.0.map_key()
+    }
+}
+
 pub type Line = u32;
 
 #[derive(Debug)]
 pub struct Token {
     pub token_type: TokenType,
     pub line: Line,
-    start: usize,
-    length: usize,
+    // In chars, not bytes
+    pub start: usize,
+    pub length: usize,
 }
 
 impl Token {
@@ -54,11 +84,29 @@ impl Token {
         }
     }
 
+    // The start and end of the token in the source, for tooling
+    pub fn span(&self) -> (usize, usize) {
+        (self.start, self.start + self.length)
+    }
+
     pub fn get_token(&self, source: &Vec<char>) -> String {
         match self.token_type {
             TokenType::String => source[self.start+1..self.start+self.length - 1]
                 .into_iter()
                 .collect(),
+            // Characters without the leading backslash
+            TokenType::Char => source[self.start+1..self.start+self.length]
+                .into_iter()
+                .collect(),
+            // Symbols in pipes without the pipes and escapes
+            TokenType::Symbol if source[self.start] == '|' => {
+                let mut name = String::new();
+                let mut chars = source[self.start+1..self.start+self.length-1].iter();
+                while let Some(&c) = chars.next() {
+                    name.push(if c == '\\' { *chars.next().unwrap_or(&c) } else { c });
+                }
+                name
+            }
             _ => source[self.start..self.start+self.length]
                 .into_iter()
                 .collect(),
@@ -71,7 +119,7 @@ fn is_number(c: char) -> bool {
         || c == '.'
 }
 
-fn is_symbol(c: char) -> bool {
+pub fn is_symbol(c: char) -> bool {
     c.is_alphanumeric()
         || c == '-'
         || c == '_'
@@ -85,6 +133,8 @@ fn is_symbol(c: char) -> bool {
         || c == '/'
         || c == ':'
         || c == '='
+        || c == '&'
+        || c == '%'
 }
 
 fn advance(source: &Vec<char>, offset: &mut usize, line: &mut Line) -> bool {
@@ -106,8 +156,13 @@ fn peek(source: &Vec<char>, offset: usize) -> Option<char> {
     }
 }
 
+// Commas are whitespace, so maps read back the way they print
+fn is_whitespace(c: char) -> bool {
+    c.is_whitespace() || c == ','
+}
+
 fn skip_whitespace(source: &Vec<char>, start: &mut usize, line: &mut Line) {
-    while *start < source.len() - 1 && source[*start].is_whitespace() {
+    while *start < source.len() - 1 && is_whitespace(source[*start]) {
         advance(source, start, line);
     }
 }
@@ -121,13 +176,54 @@ fn skip_comments(source: &Vec<char>, start: &mut usize, line: &mut Line) {
     }
 }
 
-fn skip_non_code(source: &Vec<char>, start: &mut usize, line: &mut Line) {
+fn starts_with(source: &Vec<char>, offset: usize, prefix: &str) -> bool {
+    prefix.chars()
+        .enumerate()
+        .all(|(i, c)| offset + i < source.len() && source[offset + i] == c)
+}
+
+// Block comments nest, `#| a #| b |# c |#` is a single comment. Returns
+// false if the comment runs off the end of the source.
+fn skip_block_comments(source: &Vec<char>, start: &mut usize, line: &mut Line) -> bool {
+    if !starts_with(source, *start, "#|") {
+        return true
+    }
+    let mut depth = 0;
+    while *start < source.len() - 1 {
+        if starts_with(source, *start, "#|") {
+            depth += 1;
+            advance(source, start, line);
+            advance(source, start, line);
+        } else if starts_with(source, *start, "|#") {
+            depth -= 1;
+            advance(source, start, line);
+            advance(source, start, line);
+            if depth == 0 {
+                return true
+            }
+        } else {
+            advance(source, start, line);
+        }
+    }
+    false
+}
+
+// Returns false if a block comment runs off the end of the source, leaving
+// start at its opening
+fn skip_non_code(source: &Vec<char>, start: &mut usize, line: &mut Line) -> bool {
     loop {
         let started_at = *start;
         skip_whitespace(source, start, line);
         skip_comments(source, start, line);
+        let comment_start = *start;
+        let comment_line = *line;
+        if !skip_block_comments(source, start, line) {
+            *start = comment_start;
+            *line = comment_line;
+            return false
+        }
         if *start == started_at {
-            break
+            return true
         }
     }
 }
@@ -205,7 +301,7 @@ fn scan_dash(source: &Vec<char>, start: &mut usize, line: &mut Line) -> (TokenTy
     match next_char {
         None => (TokenType::Symbol, 1),
         Some(c) => {
-            if c.is_whitespace() {
+            if is_whitespace(c) {
                 (TokenType::Symbol, 1)
             } else if c.is_numeric() {
                 scan_number(source, start)
@@ -216,9 +312,61 @@ fn scan_dash(source: &Vec<char>, start: &mut usize, line: &mut Line) -> (TokenTy
     }
 }
 
+// `\a` or a named character like `\space`, up to whitespace or a delimiter.
+// The character right after the backslash always belongs to it, so `\(`
+// works.
+fn scan_char(source: &Vec<char>, start: &mut usize) -> (TokenType, usize) {
+    let mut length = 2;
+    while *start + length < source.len() - 1 {
+        let c = source[*start + length];
+        if is_whitespace(c) || "()[]{}".contains(c) {
+            break
+        }
+        length += 1;
+    }
+    (TokenType::Char, length)
+}
+
+// `|a b|`, for names with characters that would end a symbol, with `\|` and
+// `\\` for pipes and backslashes in the name
+fn scan_quoted_symbol(source: &Vec<char>, start: &mut usize, line: &mut Line) -> (TokenType, usize) {
+    let mut symbol_end = *start;
+    loop {
+        if !advance(source, &mut symbol_end, line) {
+            break (TokenType::Error(ScanError::UnterminatedSymbol), symbol_end - *start)
+        }
+        match source[symbol_end] {
+            '\\' => {
+                advance(source, &mut symbol_end, line);
+            }
+            '|' => break (TokenType::Symbol, symbol_end - *start + 1),
+            _ => (),
+        }
+    }
+}
+
+// Reader macros starting with `#`
+fn scan_dispatch_macro(source: &Vec<char>, start: &mut usize) -> (TokenType, usize) {
+    if source.len() <= *start + 1 {
+        return (TokenType::Error(ScanError::UnknownDispatch), 1)
+    }
+    match source[*start + 1] {
+        '_' => (TokenType::DatumComment, 2),
+        ';' => (TokenType::DatumComment, 2),
+        _ => (TokenType::Error(ScanError::UnknownDispatch), 2),
+    }
+}
+
 fn scan_token(source: &Vec<char>, offset: usize, line: &mut Line) -> Token {
     let mut start = offset;
-    skip_non_code(source, &mut start, line);
+    if !skip_non_code(source, &mut start, line) {
+        return Token {
+            token_type: TokenType::Error(ScanError::UnterminatedBlockComment),
+            line: *line,
+            start: start,
+            length: source.len() - start,
+        }
+    }
     let (token_type, length) = match source[start] {
         '(' => (TokenType::OpenParenthesis, 1),
         ')' => (TokenType::CloseParenthesis, 1),
@@ -227,7 +375,13 @@ fn scan_token(source: &Vec<char>, offset: usize, line: &mut Line) -> Token {
         '{' => (TokenType::OpenBrace, 1),
         '}' => (TokenType::CloseBrace, 1),
         '\'' => (TokenType::Quote, 1),
+        '`' => (TokenType::Quasiquote, 1),
+        '~' if starts_with(source, start, "~@") => (TokenType::UnquoteSplicing, 2),
+        '~' => (TokenType::Unquote, 1),
+        '#' => scan_dispatch_macro(source, &mut start),
+        '\\' if start + 1 < source.len() - 1 => scan_char(source, &mut start),
         '"' => scan_string(source, &mut start, line),
+        '|' => scan_quoted_symbol(source, &mut start, line),
         ':' => scan_keyword(source, &mut start, line),
         '-' => scan_dash(source, &mut start, line),
         _ if start == source.len() - 1 => (TokenType::EOF, 0),
@@ -280,3 +434,148 @@ pub fn scan(source: &Vec<char>, debug: bool) -> Vec<Token> {
         }
     }
 }
+
+// Scans like `scan`, but keeps whitespace and comments as tokens instead of
+// skipping them, for tools like formatters that have to reproduce them.
+// Comment tokens hold their text without the delimiters.
+pub fn scan_with_trivia(source: &Vec<char>) -> Vec<Token> {
+    let mut offset = 0;
+    let mut tokens: Vec<Token> = vec![];
+    let mut line: Line = 1;
+    loop {
+        let start = offset;
+        let start_line = line;
+        let trivia = if offset < source.len() - 1 && is_whitespace(source[offset]) {
+            skip_whitespace(source, &mut offset, &mut line);
+            Some(TokenType::Whitespace)
+        } else if offset < source.len() - 1 && source[offset] == ';' {
+            // The newline is whitespace
+            while offset < source.len() - 1 && source[offset] != '\n' {
+                advance(source, &mut offset, &mut line);
+            }
+            Some(TokenType::LineComment(source[start + 1..offset].iter().collect()))
+        } else if starts_with(source, offset, "#|") {
+            if !skip_block_comments(source, &mut offset, &mut line) {
+                tokens.push(Token{token_type: TokenType::Error(ScanError::UnterminatedBlockComment),
+                                  line: start_line, start: start, length: offset - start});
+                tokens.push(Token{token_type: TokenType::EOF, line: line, start: offset, length: 0});
+                break tokens
+            }
+            let end = if start + 4 <= offset && starts_with(source, offset - 2, "|#") {
+                offset - 2
+            } else {
+                offset
+            };
+            Some(TokenType::BlockComment(source[start + 2..end].iter().collect()))
+        } else {
+            None
+        };
+        if let Some(token_type) = trivia {
+            tokens.push(Token{token_type: token_type, line: start_line, start: start, length: offset - start});
+            continue
+        }
+        let token = scan_token(source, offset, &mut line);
+        offset = token.start + token.length;
+        let done = token.token_type == TokenType::EOF;
+        tokens.push(token);
+        if done {
+            break tokens
+        }
+        if source.len() <= offset {
+            tokens.push(Token{token_type: TokenType::EOF, line: line, start: offset, length: 0});
+            break tokens
+        }
+    }
+}
+
+// Scans a string, for tooling that wants the raw tokens. Comments are
+// skipped but `#_` still shows up as a token.
+pub fn tokenise(source: &str) -> Vec<Token> {
+    // The scanner expects a trailing character after the last token, which
+    // doesn't move any spans
+    scan(&format!("{}\n", source).chars().collect(), false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan_str(source: &str) -> Vec<Token> {
+        scan(&format!("{}\n", source).chars().collect(), false)
+    }
+
+    #[test]
+    fn lines_are_counted_across_block_comments() {
+        let tokens = scan_str("a\n#| one\ntwo #| three\n|# |#\nb\n#|\n|# c");
+        let lines: Vec<Line> = tokens.iter()
+            .filter(|t| t.token_type == TokenType::Symbol)
+            .map(|t| t.line)
+            .collect();
+        assert_eq!(lines, vec![1, 5, 7]);
+    }
+
+    #[test]
+    fn unterminated_block_comments_are_errors() {
+        let tokens = scan_str("(print 1)\n#| x\n(print 2)");
+        let error = tokens.iter().find(|t| t.is_error()).unwrap();
+        assert_eq!(error.token_type, TokenType::Error(ScanError::UnterminatedBlockComment));
+        assert_eq!(error.line, 2);
+        assert_eq!(tokens.last().unwrap().token_type, TokenType::EOF);
+        let tokens = scan_with_trivia(&"#| a #| b |#\n".chars().collect());
+        assert_eq!(tokens[0].token_type, TokenType::Error(ScanError::UnterminatedBlockComment));
+    }
+
+    #[test]
+    fn tokenise_gives_types_and_positions() {
+        let source = "(def x [1 \"two\"]) ; three\n#_ :four 'x";
+        let tokens = tokenise(source);
+        let expected = vec![
+            (TokenType::OpenParenthesis, 1, "("),
+            (TokenType::Symbol, 1, "def"),
+            (TokenType::Symbol, 1, "x"),
+            (TokenType::OpenBracket, 1, "["),
+            (TokenType::Int, 1, "1"),
+            (TokenType::String, 1, "\"two\""),
+            (TokenType::CloseBracket, 1, "]"),
+            (TokenType::CloseParenthesis, 1, ")"),
+            (TokenType::DatumComment, 2, "#_"),
+            (TokenType::Keyword, 2, ":four"),
+            (TokenType::Quote, 2, "'"),
+            (TokenType::Symbol, 2, "x"),
+        ];
+        let chars: Vec<char> = source.chars().collect();
+        let actual: Vec<(&TokenType, Line, String)> = tokens.iter()
+            .take_while(|t| t.token_type != TokenType::EOF)
+            .map(|t| {
+                let (start, end) = t.span();
+                (&t.token_type, t.line, chars[start..end].iter().collect())
+            })
+            .collect();
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for ((token_type, line, text), (expected_type, expected_line, expected_text)) in actual.iter().zip(expected.iter()) {
+            assert_eq!((*token_type, *line, text.as_str()), (expected_type, *expected_line, *expected_text));
+        }
+        assert_eq!(tokens.last().unwrap().token_type, TokenType::EOF);
+    }
+
+    #[test]
+    fn tokenise_counts_positions_in_chars() {
+        let tokens = tokenise("\"λx\" ü");
+        assert_eq!(tokens[0].span(), (0, 4));
+        assert_eq!(tokens[1].token_type, TokenType::Symbol);
+        assert_eq!(tokens[1].span(), (5, 6));
+    }
+
+    #[test]
+    fn symbols_between_bars_are_unescaped() {
+        let source: Vec<char> = "|a b| |x\\|y\\\\z| |a\n|\n".chars().collect();
+        let tokens = scan(&source, false);
+        let names: Vec<String> = tokens.iter()
+            .take_while(|t| t.token_type == TokenType::Symbol)
+            .map(|t| t.get_token(&source))
+            .collect();
+        assert_eq!(names, vec!["a b", "x|y\\z", "a\n"]);
+        let tokens = scan_str("|a\\|");
+        assert_eq!(tokens[0].token_type, TokenType::Error(ScanError::UnterminatedSymbol));
+    }
+}
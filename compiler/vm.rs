@@ -1,85 +1,495 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{self, AtomicBool};
+
+pub mod bigint;
+pub mod hamt;
+pub mod intern;
+pub mod nan_box;
+pub mod natives;
+pub mod ordered_map;
+pub mod regex;
+
+use compiler::scanner::is_symbol;
+use self::bigint::BigInt;
+use self::intern::{intern, InternedStr};
+use self::nan_box::NanBoxed;
+use self::ordered_map::OrderedMap;
+use self::regex::Regex;
 
 #[derive(Clone)]
 pub enum Value {
     Nil,
     Bool(bool),
     Int(i64),
+    BigInt(BigInt),
+    // Always in lowest terms, with a positive denominator other than 1
+    Rational(i64, i64),
     Float(f64),
+    // Real and imaginary part
+    Complex(f64, f64),
+    Char(char),
     String(String),
-    Symbol(String),
-    Function(String, usize, usize),
+    Symbol(InternedStr),
+    // Without the leading colon
+    Keyword(String),
+    Function {
+        name: String,
+        params: Vec<String>,
+        // Names of the `:key default` parameters after the positional ones,
+        // see keyword_args
+        keywords: Vec<String>,
+        // The `&rest` parameter collecting any further arguments into a list
+        rest: Option<String>,
+        chunk_idx: usize,
+        // Where it was defined, for error messages
+        file: Option<String>,
+        line: Line,
+        // Values captured from the enclosing functions when this was created,
+        // shared by all copies of the closure
+        upvalues: Arc<Vec<Value>>,
+    },
+    // A builtin, indexing natives::NATIVES
+    Native(usize),
+    // A `defn` with several arities, one function per clause
+    MultiArity {
+        name: String,
+        clauses: Vec<Value>,
+    },
+    List(Vec<Value>),
+    Vector(Vec<Value>),
+    // Keyed by the printed form of the key, see map_key, and holding the key
+    // itself next to the value. Entries stay in the order they were added in.
+    Map(OrderedMap<(Value, Value)>),
+    // A value with a metadata map, which does not take part in equality
+    WithMeta(Box<Value>, Box<Value>),
+    Regex(Regex),
+    StringBuilder(Arc<Mutex<String>>),
+    // A map that is changed in place by assoc! and dissoc!, for building up
+    // a map without copying it on every change, see transient
+    TransientMap(Arc<Mutex<OrderedMap<(Value, Value)>>>),
+    Port(InputPort),
+    OutPort(OutputPort),
+    Promise(Promise),
+    Stream(Stream),
+    Error {
+        kind: String,
+        message: String,
+        data: Box<Value>,
+        // The error this one was raised in response to, or nil
+        cause: Box<Value>,
+    },
+}
+
+// The condition variable is notified when the promise settles
+pub type Promise = Arc<(Mutex<PromiseState>, Condvar)>;
+
+pub enum PromiseState {
+    Pending,
+    Resolved(Value),
+    Rejected(RuntimeError),
+}
+
+// Splits `namespace/name` into its two parts, if the name is qualified
+pub fn split_qualified(name: &str) -> Option<(&str, &str)> {
+    let mut parts = name.split('/');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(namespace), Some(local), None) if !namespace.is_empty() && !local.is_empty() =>
+            Some((namespace, local)),
+        _ => None,
+    }
+}
+
+// Qualified names that are not globals themselves are resolved through a
+// global holding either a module's name, as in `(def g 'geometry) (g/area)`,
+// or a map of bindings keyed by symbols or keywords, as in
+// `(def math {'square (fn (x) (* x x))}) (math/square 4)`
+pub fn resolve_namespaced_symbol(name: &str, globals: &HashMap<String, Value>) -> Option<Value> {
+    if let Some(v) = globals.get(name) {
+        return Some(v.clone())
+    }
+    let (namespace, local) = match split_qualified(name) {
+        Some(parts) => parts,
+        None => return None,
+    };
+    let mut namespace = globals.get(namespace);
+    while let Some(Value::WithMeta(v, _)) = namespace {
+        namespace = Some(&**v);
+    }
+    match namespace {
+        Some(Value::Symbol(module)) => globals.get(&format!("{}/{}", module, local)).cloned(),
+        Some(Value::Map(bindings)) => bindings.get(&Value::Symbol(intern(local)).map_key())
+            .or_else(|| bindings.get(&Value::Keyword(String::from(local)).map_key()))
+            .map(|(_, v)| v.clone()),
+        _ => None,
+    }
+}
+
+// A single-pass lazy sequence, copies of a stream share its position
+pub type Stream = Arc<Mutex<StreamState>>;
+
+pub enum StreamState {
+    Items(std::vec::IntoIter<Value>),
+    // The function and the stream it is applied to
+    Map(Value, Stream),
+    Filter(Value, Stream),
+    // Everything from the first stream, then everything from the second
+    Concat(Stream, Stream),
 }
 
+// Ports are shared, reading or writing through any copy advances all of them
+pub type InputPort = Arc<Mutex<Box<dyn BufRead + Send>>>;
+pub type OutputPort = Arc<Mutex<Box<dyn Write + Send>>>;
+
 impl Value {
+    // A function without parameters for running a top-level chunk
+    fn function(name: &str, chunk_idx: usize) -> Value {
+        Value::Function{
+            name: String::from(name),
+            params: vec![],
+            keywords: vec![],
+            rest: None,
+            chunk_idx: chunk_idx,
+            file: None,
+            line: 0,
+            upvalues: Arc::new(vec![]),
+        }
+    }
+
+    // Demotes to a plain Int if the result fits again
+    fn from_bigint(b: BigInt) -> Value {
+        match b.to_i64() {
+            Some(n) => Value::Int(n),
+            None => Value::BigInt(b),
+        }
+    }
+
+    // Reduces a fraction, demoting whole numbers to an Int or a BigInt.
+    // Other fractions have to fit in an i64 numerator and denominator.
+    fn rational(n: i128, d: i128) -> Result<Value, RuntimeError> {
+        if d == 0 {
+            return Err(runtime_error(String::from("Division by zero")))
+        }
+        let (mut a, mut b) = (n.abs(), d.abs());
+        while b != 0 {
+            let t = a % b;
+            a = b;
+            b = t;
+        }
+        let sign = if d < 0 { -1 } else { 1 };
+        let (n, d) = (sign * n / a, sign * d / a);
+        if d == 1 {
+            Ok(Value::from_bigint(BigInt::from(n)))
+        } else if n < i64::min_value() as i128 || n > i64::max_value() as i128 || d > i64::max_value() as i128 {
+            Err(runtime_error(format!("Rational overflow in {}/{}", n, d)))
+        } else {
+            Ok(Value::Rational(n as i64, d as i64))
+        }
+    }
+
+    fn as_rational(&self) -> Option<(i128, i128)> {
+        match self {
+            Value::Int(n) => Some((*n as i128, 1)),
+            Value::Rational(n, d) => Some((*n as i128, *d as i128)),
+            _ => None,
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(n) => Some(*n as f64),
+            Value::BigInt(b) => Some(b.to_f64()),
+            Value::Rational(n, d) => Some(*n as f64 / *d as f64),
+            Value::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    // Any number, with reals having an imaginary part of zero
+    fn as_complex(&self) -> Option<(f64, f64)> {
+        match self {
+            Value::Complex(re, im) => Some((*re, *im)),
+            _ => self.as_f64().map(|re| (re, 0.0)),
+        }
+    }
+
+    // Keys are stored by their printed form, which the reader turns back into
+    // an equal value
+    pub fn map_key(&self) -> String {
+        match self {
+            Value::WithMeta(v, _) => v.map_key(),
+            // Equal maps can have their entries in a different order
+            Value::Map(m) => {
+                let mut items: Vec<String> = m.values()
+                    .map(|(k, v)| format!("{} {}", k.map_key(), v.map_key()))
+                    .collect();
+                items.sort();
+                format!("{{{}}}", items.join(", "))
+            }
+            Value::List(items) => format!("({})", Value::map_keys(items)),
+            Value::Vector(items) => format!("[{}]", Value::map_keys(items)),
+            // Functions don't read back in, their keys agree with equal
+            // instead: the chunk, and for closures the captured values
+            Value::Function{chunk_idx, upvalues, ..} if upvalues.is_empty() => format!("fn#{}", chunk_idx),
+            Value::Function{chunk_idx, upvalues, ..} => format!("fn#{}@{:p}", chunk_idx, Arc::as_ptr(upvalues)),
+            Value::MultiArity{clauses, ..} => format!("fn#[{}]", Value::map_keys(clauses)),
+            _ => format!("{:?}", self),
+        }
+    }
+
+    fn map_keys(items: &[Value]) -> String {
+        items.iter().map(|v| v.map_key()).collect::<Vec<String>>().join(" ")
+    }
+
+    // Values that are fully described by how they print
+    fn is_literal(&self) -> bool {
+        match self {
+            Value::Nil | Value::Bool(_) | Value::Int(_) | Value::BigInt(_)
+                | Value::Rational(_, _) | Value::Float(_) | Value::Char(_)
+                | Value::String(_) | Value::Symbol(_) | Value::Keyword(_) => true,
+            _ => false,
+        }
+    }
+
+    // The name used by type-of and to dispatch protocols
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "Nil",
+            Value::Bool(_) => "Bool",
+            Value::Int(_) => "Int",
+            Value::BigInt(_) => "BigInt",
+            Value::Rational(..) => "Rational",
+            Value::Float(_) => "Float",
+            Value::Complex(..) => "Complex",
+            Value::Char(_) => "Char",
+            Value::String(_) => "String",
+            Value::Symbol(_) => "Symbol",
+            Value::Keyword(_) => "Keyword",
+            Value::Function{..} => "Function",
+            Value::Native(_) | Value::MultiArity{..} => "Function",
+            Value::List(_) => "List",
+            Value::Vector(_) => "Vector",
+            Value::Map(_) => "Map",
+            Value::WithMeta(v, _) => v.type_name(),
+            Value::Regex(_) => "Regex",
+            Value::StringBuilder(_) => "StringBuilder",
+            Value::TransientMap(_) => "TransientMap",
+            Value::Port(_) => "Port",
+            Value::OutPort(_) => "OutPort",
+            Value::Promise(_) => "Promise",
+            Value::Stream(_) => "Stream",
+            Value::Error{..} => "Error",
+        }
+    }
+
+    fn is_nan(&self) -> bool {
+        match self {
+            Value::Float(f) => f.is_nan(),
+            Value::Complex(re, im) => re.is_nan() || im.is_nan(),
+            _ => false,
+        }
+    }
+
+    fn as_bigint(&self) -> Option<BigInt> {
+        match self {
+            Value::Int(n) => Some(BigInt::from(*n)),
+            Value::BigInt(b) => Some(b.clone()),
+            _ => None,
+        }
+    }
+
     fn truthy(&self) -> bool {
         match self {
             Value::Nil => false,
             Value::Bool(false) => false,
             Value::Int(0) => false,
             Value::Float(f) => *f == 0.0,
+            Value::Char('\0') => false,
             Value::String(s) => s.is_empty(),
+            // Empty collections are falsy like nil, the empty sequence
+            Value::List(l) | Value::Vector(l) => !l.is_empty(),
+            Value::Map(m) => m.len() != 0,
+            Value::WithMeta(v, _) => v.truthy(),
             _ => true,
         }
     }
 
-    fn negate(&self) -> Result<Value, String> {
+    fn negate(&self) -> Result<Value, RuntimeError> {
         match self {
-            Value::Int(x) => Ok(Value::Int(-x)),
+            Value::Int(x) => match x.checked_neg() {
+                Some(n) => Ok(Value::Int(n)),
+                None => Ok(Value::from_bigint(BigInt::from(*x).negate())),
+            },
+            Value::BigInt(x) => Ok(Value::from_bigint(x.negate())),
+            Value::Rational(n, d) => Value::rational(-(*n as i128), *d as i128),
             Value::Float(x) => Ok(Value::Float(-x)),
-            _ => Err(format!("Cannot negate {}", self)),
+            Value::Complex(re, im) => Ok(Value::Complex(-re, -im)),
+            Value::WithMeta(v, _) => v.negate(),
+            _ => Err(type_error(format!("Cannot negate {}", self))),
         }
     }
 
-    fn add(&self, other: &Value) -> Result<Value, String> {
+    fn add(&self, other: &Value) -> Result<Value, RuntimeError> {
         match (self, other) {
+            // metadata doesn't survive arithmetic
+            (Value::WithMeta(a, _), _) => a.add(other),
+            (_, Value::WithMeta(b, _)) => self.add(b),
+            // complex & any number -> complex
+            (Value::Complex(..), _) | (_, Value::Complex(..)) => match (self.as_complex(), other.as_complex()) {
+                (Some((a, b)), Some((c, d))) => Ok(Value::Complex(a + c, b + d)),
+                _ => Err(type_error(format!("Cannot add {} to {}", other, self))),
+            },
             // float & float -> float
             (Value::Float(a), Value::Float(b)) => Ok(Value::Float(*a + *b)),
             // float & int -> float
             (Value::Int(a), Value::Float(b)) => Ok(Value::Float(*a as f64 + *b)),
             (Value::Float(a), Value::Int(b)) => Ok(Value::Float(a + *b as f64)),
-            // int & int -> int
-            (Value::Int(a), Value::Int(b)) => Ok(Value::Int(*a + *b)),
-            _ => Err(format!("Cannot add {} to {}", other, self)),
+            (Value::BigInt(a), Value::Float(b)) => Ok(Value::Float(a.to_f64() + *b)),
+            (Value::Float(a), Value::BigInt(b)) => Ok(Value::Float(a + b.to_f64())),
+            // int & int -> int, promoted to bigint on overflow
+            (Value::Int(a), Value::Int(b)) => match a.checked_add(*b) {
+                Some(n) => Ok(Value::Int(n)),
+                None => Ok(Value::from_bigint(BigInt::from(*a).add(&BigInt::from(*b)))),
+            },
+            // rational & float or bigint -> float
+            (Value::Rational(..), Value::Float(_))
+                | (Value::Float(_), Value::Rational(..))
+                | (Value::Rational(..), Value::BigInt(_))
+                | (Value::BigInt(_), Value::Rational(..)) =>
+                Ok(Value::Float(self.as_f64().unwrap() + other.as_f64().unwrap())),
+            // rational & rational or int -> rational
+            (Value::Rational(..), _) | (_, Value::Rational(..)) => match (self.as_rational(), other.as_rational()) {
+                (Some((a, b)), Some((c, d))) => Value::rational(a * d + c * b, b * d),
+                _ => Err(type_error(format!("Cannot add {} to {}", other, self))),
+            },
+            _ => match (self.as_bigint(), other.as_bigint()) {
+                (Some(a), Some(b)) => Ok(Value::from_bigint(a.add(&b))),
+                _ => Err(type_error(format!("Cannot add {} to {}", other, self))),
+            },
         }
     }
 
-    fn subtract(&self, other: &Value) -> Result<Value, String> {
+    fn subtract(&self, other: &Value) -> Result<Value, RuntimeError> {
         match (self, other) {
+            // metadata doesn't survive arithmetic
+            (Value::WithMeta(a, _), _) => a.subtract(other),
+            (_, Value::WithMeta(b, _)) => self.subtract(b),
+            // complex & any number -> complex
+            (Value::Complex(..), _) | (_, Value::Complex(..)) => match (self.as_complex(), other.as_complex()) {
+                (Some((a, b)), Some((c, d))) => Ok(Value::Complex(a - c, b - d)),
+                _ => Err(type_error(format!("Cannot subtract {} from {}", other, self))),
+            },
             // float & float -> float
             (Value::Float(a), Value::Float(b)) => Ok(Value::Float(*a - *b)),
             // float & int -> float
             (Value::Int(a), Value::Float(b)) => Ok(Value::Float(*a as f64 - *b)),
             (Value::Float(a), Value::Int(b)) => Ok(Value::Float(*a - *b as f64)),
-            // int & int -> int
-            (Value::Int(a), Value::Int(b)) => Ok(Value::Int(*a - *b)),
-            _ => Err(format!("Cannot subtract {} from {}", other, self)),
+            (Value::BigInt(a), Value::Float(b)) => Ok(Value::Float(a.to_f64() - *b)),
+            (Value::Float(a), Value::BigInt(b)) => Ok(Value::Float(a - b.to_f64())),
+            // int & int -> int, promoted to bigint on overflow
+            (Value::Int(a), Value::Int(b)) => match a.checked_sub(*b) {
+                Some(n) => Ok(Value::Int(n)),
+                None => Ok(Value::from_bigint(BigInt::from(*a).subtract(&BigInt::from(*b)))),
+            },
+            // rational & float or bigint -> float
+            (Value::Rational(..), Value::Float(_))
+                | (Value::Float(_), Value::Rational(..))
+                | (Value::Rational(..), Value::BigInt(_))
+                | (Value::BigInt(_), Value::Rational(..)) =>
+                Ok(Value::Float(self.as_f64().unwrap() - other.as_f64().unwrap())),
+            // rational & rational or int -> rational
+            (Value::Rational(..), _) | (_, Value::Rational(..)) => match (self.as_rational(), other.as_rational()) {
+                (Some((a, b)), Some((c, d))) => Value::rational(a * d - c * b, b * d),
+                _ => Err(type_error(format!("Cannot subtract {} from {}", other, self))),
+            },
+            _ => match (self.as_bigint(), other.as_bigint()) {
+                (Some(a), Some(b)) => Ok(Value::from_bigint(a.subtract(&b))),
+                _ => Err(type_error(format!("Cannot subtract {} from {}", other, self))),
+            },
         }
     }
 
-    fn multiply(&self, other: &Value) -> Result<Value, String> {
+    fn multiply(&self, other: &Value) -> Result<Value, RuntimeError> {
         match (self, other) {
+            // metadata doesn't survive arithmetic
+            (Value::WithMeta(a, _), _) => a.multiply(other),
+            (_, Value::WithMeta(b, _)) => self.multiply(b),
+            // complex & any number -> complex
+            (Value::Complex(..), _) | (_, Value::Complex(..)) => match (self.as_complex(), other.as_complex()) {
+                (Some((a, b)), Some((c, d))) => Ok(Value::Complex(a * c - b * d, a * d + b * c)),
+                _ => Err(type_error(format!("Cannot multiply {} with {}", other, self))),
+            },
             // float & float -> float
             (Value::Float(a), Value::Float(b)) => Ok(Value::Float(*a * *b)),
             // float & int -> float
             (Value::Int(a), Value::Float(b)) => Ok(Value::Float(*a as f64 * *b)),
             (Value::Float(a), Value::Int(b)) => Ok(Value::Float(*a * *b as f64)),
-            // int & int -> int
-            (Value::Int(a), Value::Int(b)) => Ok(Value::Int(*a * *b)),
-            _ => Err(format!("Cannot multiply {} with {}", other, self)),
+            (Value::BigInt(a), Value::Float(b)) => Ok(Value::Float(a.to_f64() * *b)),
+            (Value::Float(a), Value::BigInt(b)) => Ok(Value::Float(a * b.to_f64())),
+            // int & int -> int, promoted to bigint on overflow
+            (Value::Int(a), Value::Int(b)) => match a.checked_mul(*b) {
+                Some(n) => Ok(Value::Int(n)),
+                None => Ok(Value::from_bigint(BigInt::from(*a).multiply(&BigInt::from(*b)))),
+            },
+            // rational & float or bigint -> float
+            (Value::Rational(..), Value::Float(_))
+                | (Value::Float(_), Value::Rational(..))
+                | (Value::Rational(..), Value::BigInt(_))
+                | (Value::BigInt(_), Value::Rational(..)) =>
+                Ok(Value::Float(self.as_f64().unwrap() * other.as_f64().unwrap())),
+            // rational & rational or int -> rational
+            (Value::Rational(..), _) | (_, Value::Rational(..)) => match (self.as_rational(), other.as_rational()) {
+                (Some((a, b)), Some((c, d))) => Value::rational(a * c, b * d),
+                _ => Err(type_error(format!("Cannot multiply {} with {}", other, self))),
+            },
+            _ => match (self.as_bigint(), other.as_bigint()) {
+                (Some(a), Some(b)) => Ok(Value::from_bigint(a.multiply(&b))),
+                _ => Err(type_error(format!("Cannot multiply {} with {}", other, self))),
+            },
         }
     }
 
-    fn divide(&self, other: &Value) -> Result<Value, String> {
+    fn divide(&self, other: &Value) -> Result<Value, RuntimeError> {
         match (self, other) {
+            // metadata doesn't survive arithmetic
+            (Value::WithMeta(a, _), _) => a.divide(other),
+            (_, Value::WithMeta(b, _)) => self.divide(b),
+            // complex & any number -> complex
+            (Value::Complex(..), _) | (_, Value::Complex(..)) => match (self.as_complex(), other.as_complex()) {
+                (Some((a, b)), Some((c, d))) => {
+                    let divisor = c * c + d * d;
+                    Ok(Value::Complex((a * c + b * d) / divisor, (b * c - a * d) / divisor))
+                }
+                _ => Err(type_error(format!("Cannot divide {} by {}", other, self))),
+            },
             // float & float -> float
             (Value::Float(a), Value::Float(b)) => Ok(Value::Float(*a / *b)),
             // float & int -> float
             (Value::Int(a), Value::Float(b)) => Ok(Value::Float(*a as f64 / *b)),
             (Value::Float(a), Value::Int(b)) => Ok(Value::Float(*a / *b as f64)),
-            // int & int -> also float
-            (Value::Int(a), Value::Int(b)) => Ok(Value::Float(*a as f64 / *b as f64)),
-            _ => Err(format!("Cannot divide {} by {}", other, self)),
+            // int & int -> rational, or int if it divides evenly
+            (Value::Int(a), Value::Int(b)) => Value::rational(*a as i128, *b as i128),
+            // rational & float or bigint -> float
+            (Value::Rational(..), Value::Float(_))
+                | (Value::Float(_), Value::Rational(..))
+                | (Value::Rational(..), Value::BigInt(_))
+                | (Value::BigInt(_), Value::Rational(..)) =>
+                Ok(Value::Float(self.as_f64().unwrap() / other.as_f64().unwrap())),
+            // rational & rational or int -> rational
+            (Value::Rational(..), _) | (_, Value::Rational(..)) => match (self.as_rational(), other.as_rational()) {
+                (Some((a, b)), Some((c, d))) => Value::rational(a * d, b * c),
+                _ => Err(type_error(format!("Cannot divide {} by {}", other, self))),
+            },
+            // anything & bigint -> float
+            (Value::BigInt(a), Value::Float(b)) => Ok(Value::Float(a.to_f64() / *b)),
+            (Value::Float(a), Value::BigInt(b)) => Ok(Value::Float(a / b.to_f64())),
+            _ => match (self.as_bigint(), other.as_bigint()) {
+                (Some(a), Some(b)) => Ok(Value::Float(a.to_f64() / b.to_f64())),
+                _ => Err(type_error(format!("Cannot divide {} by {}", other, self))),
+            },
         }
     }
 
@@ -89,35 +499,109 @@ impl Value {
 
     fn equal(&self, other: &Value) -> Value {
         let b = match (self, other) {
+            (Value::WithMeta(a, _), _) => return a.equal(other),
+            (_, Value::WithMeta(b, _)) => return self.equal(b),
             (Value::Nil, Value::Nil) => true,
             (Value::Bool(x), Value::Bool(y)) => x == y,
             (Value::Int(x), Value::Int(y)) => x == y,
+            (Value::BigInt(_), Value::Int(_))
+                | (Value::Int(_), Value::BigInt(_))
+                | (Value::BigInt(_), Value::BigInt(_)) =>
+                self.as_bigint() == other.as_bigint(),
+            (Value::Rational(a, b), Value::Rational(c, d)) => a == c && b == d,
             (Value::Float(x), Value::Float(y)) => x == y,
+            (Value::Complex(a, b), Value::Complex(c, d)) => a == c && b == d,
+            (Value::Char(x), Value::Char(y)) => x == y,
             (Value::String(x), Value::String(y)) => x == y,
             (Value::Symbol(x), Value::Symbol(y)) => x == y,
-            (Value::Function(x, _, _), Value::Function(y, _, _)) => x == y,
+            (Value::Keyword(x), Value::Keyword(y)) => x == y,
+            (Value::Map(x), Value::Map(y)) => {
+                x.len() == y.len()
+                    && x.iter().all(|(k, (_, a))| y.get(k).map_or(false, |(_, b)| a.equal(b).truthy()))
+            }
+            // Every compiled function gets its own chunk, so this is identity.
+            // Closures are only equal to copies of themselves, even if
+            // another one captured equal values.
+            (Value::Function{chunk_idx: x, upvalues: a, ..}, Value::Function{chunk_idx: y, upvalues: b, ..}) => {
+                x == y && (a.is_empty() && b.is_empty() || Arc::ptr_eq(a, b))
+            }
+            (Value::Native(x), Value::Native(y)) => x == y,
+            (Value::MultiArity{clauses: x, ..}, Value::MultiArity{clauses: y, ..}) => {
+                x.len() == y.len()
+                    && x.iter().zip(y).all(|(a, b)| a.equal(b).truthy())
+            }
+            (Value::List(x), Value::List(y)) | (Value::Vector(x), Value::Vector(y)) => {
+                x.len() == y.len()
+                    && x.iter().zip(y).all(|(a, b)| a.equal(b).truthy())
+            }
+            (Value::Regex(x), Value::Regex(y)) => x.source() == y.source(),
+            (Value::StringBuilder(x), Value::StringBuilder(y)) => Arc::ptr_eq(x, y),
+            (Value::TransientMap(x), Value::TransientMap(y)) => Arc::ptr_eq(x, y),
+            (Value::Port(x), Value::Port(y)) => Arc::ptr_eq(x, y),
+            (Value::OutPort(x), Value::OutPort(y)) => Arc::ptr_eq(x, y),
+            (Value::Promise(x), Value::Promise(y)) => Arc::ptr_eq(x, y),
+            (Value::Stream(x), Value::Stream(y)) => Arc::ptr_eq(x, y),
+            (Value::Error{kind: k1, message: m1, data: d1, cause: c1},
+             Value::Error{kind: k2, message: m2, data: d2, cause: c2}) =>
+                k1 == k2 && m1 == m2 && d1.equal(d2).truthy() && c1.equal(c2).truthy(),
             _ => false,
         };
         Value::Bool(b)
     }
 
-    fn greater_than(&self, other: &Value) -> Result<Value, String> {
+    fn greater_than(&self, other: &Value) -> Result<Value, RuntimeError> {
         match (self, other) {
+            // metadata doesn't survive arithmetic
+            (Value::WithMeta(a, _), _) => a.greater_than(other),
+            (_, Value::WithMeta(b, _)) => self.greater_than(b),
             (Value::Int(a), Value::Int(b)) => Ok(Value::Bool(*a > *b)),
             (Value::Int(a), Value::Float(b)) => Ok(Value::Bool((*a as f64) > *b)),
             (Value::Float(a), Value::Int(b)) => Ok(Value::Bool(*a > *b as f64)),
             (Value::Float(a), Value::Float(b)) => Ok(Value::Bool(*a > *b)),
-            _ => Err(format!("Cannot compare {} with {}", other, self)),
+            (Value::BigInt(a), Value::Float(b)) => Ok(Value::Bool(a.to_f64() > *b)),
+            (Value::Float(a), Value::BigInt(b)) => Ok(Value::Bool(*a > b.to_f64())),
+            (Value::Char(a), Value::Char(b)) => Ok(Value::Bool((*a as u32) > (*b as u32))),
+            (Value::Rational(..), Value::Float(_))
+                | (Value::Float(_), Value::Rational(..))
+                | (Value::Rational(..), Value::BigInt(_))
+                | (Value::BigInt(_), Value::Rational(..)) =>
+                Ok(Value::Bool(self.as_f64().unwrap() > other.as_f64().unwrap())),
+            (Value::Rational(..), _) | (_, Value::Rational(..)) => match (self.as_rational(), other.as_rational()) {
+                (Some((a, b)), Some((c, d))) => Ok(Value::Bool(a * d > c * b)),
+                _ => Err(type_error(format!("Cannot compare {} with {}", other, self))),
+            },
+            _ => match (self.as_bigint(), other.as_bigint()) {
+                (Some(a), Some(b)) => Ok(Value::Bool(a.compare(&b) == Ordering::Greater)),
+                _ => Err(type_error(format!("Cannot compare {} with {}", other, self))),
+            },
         }
     }
 
-    fn less_than(&self, other: &Value) -> Result<Value, String> {
+    fn less_than(&self, other: &Value) -> Result<Value, RuntimeError> {
         match (self, other) {
+            // metadata doesn't survive arithmetic
+            (Value::WithMeta(a, _), _) => a.less_than(other),
+            (_, Value::WithMeta(b, _)) => self.less_than(b),
             (Value::Int(a), Value::Int(b)) => Ok(Value::Bool(*a < *b)),
             (Value::Int(a), Value::Float(b)) => Ok(Value::Bool((*a as f64) < *b)),
             (Value::Float(a), Value::Int(b)) => Ok(Value::Bool(*a < *b as f64)),
             (Value::Float(a), Value::Float(b)) => Ok(Value::Bool(*a < *b)),
-            _ => Err(format!("Cannot compare {} with {}", other, self)),
+            (Value::BigInt(a), Value::Float(b)) => Ok(Value::Bool(a.to_f64() < *b)),
+            (Value::Float(a), Value::BigInt(b)) => Ok(Value::Bool(*a < b.to_f64())),
+            (Value::Char(a), Value::Char(b)) => Ok(Value::Bool((*a as u32) < (*b as u32))),
+            (Value::Rational(..), Value::Float(_))
+                | (Value::Float(_), Value::Rational(..))
+                | (Value::Rational(..), Value::BigInt(_))
+                | (Value::BigInt(_), Value::Rational(..)) =>
+                Ok(Value::Bool(self.as_f64().unwrap() < other.as_f64().unwrap())),
+            (Value::Rational(..), _) | (_, Value::Rational(..)) => match (self.as_rational(), other.as_rational()) {
+                (Some((a, b)), Some((c, d))) => Ok(Value::Bool(a * d < c * b)),
+                _ => Err(type_error(format!("Cannot compare {} with {}", other, self))),
+            },
+            _ => match (self.as_bigint(), other.as_bigint()) {
+                (Some(a), Some(b)) => Ok(Value::Bool(a.compare(&b) == Ordering::Less)),
+                _ => Err(type_error(format!("Cannot compare {} with {}", other, self))),
+            },
         }
     }
 }
@@ -128,10 +612,45 @@ impl std::fmt::Display for Value {
             Value::Nil => write!(f, "nil"),
             Value::Bool(b) => write!(f, "{}", b),
             Value::Int(x) => write!(f, "{}", x),
+            Value::BigInt(x) => write!(f, "{}", x),
+            Value::Rational(n, d) => write!(f, "{}/{}", n, d),
             Value::Float(x) => write!(f, "{:?}", x),
+            Value::Complex(re, im) if im.is_sign_negative() => write!(f, "{:?}-{:?}i", re, -im),
+            Value::Complex(re, im) => write!(f, "{:?}+{:?}i", re, im),
+            Value::Char(c) => write!(f, "{}", c),
             Value::String(s) => write!(f, "{}", s),
             Value::Symbol(s) => write!(f, "{}", s),
-            Value::Function(s, _, _) => write!(f, "{}", s),
+            Value::Keyword(s) => write!(f, ":{}", s),
+            Value::Function{name, ..} => write!(f, "{}", name),
+            Value::Native(idx) => write!(f, "{}", natives::NATIVES[*idx].0),
+            Value::MultiArity{name, ..} => write!(f, "{}", name),
+            Value::List(l) => {
+                let items: Vec<String> = l.iter().map(|v| format!("{:?}", v)).collect();
+                write!(f, "({})", items.join(" "))
+            }
+            Value::Vector(v) => {
+                let items: Vec<String> = v.iter().map(|v| format!("{:?}", v)).collect();
+                write!(f, "[{}]", items.join(" "))
+            }
+            Value::Map(m) => {
+                let items: Vec<String> = m.values()
+                    .map(|(k, v)| format!("{:?} {:?}", k, v))
+                    .collect();
+                write!(f, "{{{}}}", items.join(", "))
+            }
+            Value::WithMeta(v, _) => write!(f, "{}", v),
+            Value::Regex(r) => write!(f, "#\"{}\"", r.source()),
+            Value::StringBuilder(sb) => write!(f, "#<string-builder \"{}\">", sb.lock().unwrap()),
+            Value::TransientMap(m) => write!(f, "#<transient-map with {} entries>", m.lock().unwrap().len()),
+            Value::Port(_) => write!(f, "#<input-port>"),
+            Value::OutPort(_) => write!(f, "#<output-port>"),
+            Value::Promise(p) => match &*p.0.lock().unwrap() {
+                PromiseState::Pending => write!(f, "#<promise pending>"),
+                PromiseState::Resolved(v) => write!(f, "#<promise {:?}>", v),
+                PromiseState::Rejected(e) => write!(f, "#<promise rejected: {}>", e),
+            },
+            Value::Stream(_) => write!(f, "#<stream>"),
+            Value::Error{kind, message, ..} => write!(f, "#<error {}: {}>", kind, message),
         }
     }
 }
@@ -139,8 +658,27 @@ impl std::fmt::Display for Value {
 impl std::fmt::Debug for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         match self {
+            Value::Char(' ') => write!(f, "\\space"),
+            Value::Char('\n') => write!(f, "\\newline"),
+            Value::Char('\t') => write!(f, "\\tab"),
+            Value::Char('\r') => write!(f, "\\return"),
+            Value::Char(c) => write!(f, "\\{}", c),
             Value::String(s) => write!(f, "\"{}\"", s),
-            Value::Function(s, arity, _) => write!(f, "fn<{}:{}>", s, arity),
+            // Names the scanner would not read back as one symbol
+            Value::Symbol(s) if s.is_empty() || !s.chars().all(is_symbol) =>
+                write!(f, "|{}|", s.replace('\\', "\\\\").replace('|', "\\|")),
+            Value::Function{name, params, file: Some(file), line, ..} =>
+                write!(f, "fn<{}/{} @ {}:{}>", name, params.len(), file, line),
+            Value::Function{name, params, ..} => write!(f, "fn<{}/{}>", name, params.len()),
+            Value::Native(idx) => write!(f, "fn<{}>", natives::NATIVES[*idx].0),
+            Value::MultiArity{name, clauses} => {
+                let arities: Vec<String> = clauses.iter().map(|c| match c {
+                    Value::Function{params, ..} => params.len().to_string(),
+                    _ => String::from("?"),
+                }).collect();
+                write!(f, "fn<{}/{}>", name, arities.join(","))
+            }
+            Value::WithMeta(v, _) => write!(f, "{:?}", v),
             _ => write!(f, "{}", self),
         }
     }
@@ -148,6 +686,15 @@ impl std::fmt::Debug for Value {
 
 type ValueArray = Vec<Value>;
 
+// Where a closure gets a captured value from when it is created
+#[derive(Clone, Copy, Debug)]
+pub enum Capture {
+    // A local of the function creating the closure
+    Local(usize),
+    // A value the function creating the closure captured itself
+    Upvalue(usize),
+}
+
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub enum OpCode {
@@ -156,9 +703,17 @@ pub enum OpCode {
     GetGlobal(usize),
     DefineLocal(usize),
     GetLocal(usize),
+    SetLocal(usize),
+    // Pops a value and pushes it onto the vector in a local, without copying
+    // the vector
+    AppendLocal(usize),
+    GetUpvalue(usize),
+    // Loads a function constant, capturing values for it
+    LoadClosure(usize, Vec<Capture>),
     Jump(usize),
     JumpIfFalse(usize),
     Call(usize),
+    CallNative(usize, usize),
     Return,
     Negate,
     Add,
@@ -171,17 +726,166 @@ pub enum OpCode {
     LessThan,
     Print,
     Pop,
+    PopN(usize),
     Zap(usize),
+    // Installs an error handler starting at the given instruction
+    PushHandler(usize),
+    PopHandler,
+    // Collect the top n values, bottom-first, MakeMap takes n key-value pairs
+    MakeList(usize),
+    MakeVector(usize),
+    MakeMap(usize),
+    // Replaces the value on top with the first constant if it's truthy and
+    // with the second otherwise
+    Ternary(usize, usize),
+}
+
+// OpCode without its operands, for counting dispatches
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OpCodeKind {
+    Constant,
+    DefineGlobal,
+    GetGlobal,
+    DefineLocal,
+    GetLocal,
+    SetLocal,
+    AppendLocal,
+    GetUpvalue,
+    LoadClosure,
+    Jump,
+    JumpIfFalse,
+    Call,
+    CallNative,
+    Return,
+    Negate,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Equal,
+    GreaterThan,
+    LessThan,
+    Print,
+    Pop,
+    PopN,
+    Zap,
+    PushHandler,
+    PopHandler,
+    MakeList,
+    MakeVector,
+    MakeMap,
+    Ternary,
+}
+
+impl OpCode {
+    pub fn kind(&self) -> OpCodeKind {
+        match self {
+            OpCode::Constant(_) => OpCodeKind::Constant,
+            OpCode::DefineGlobal(_) => OpCodeKind::DefineGlobal,
+            OpCode::GetGlobal(_) => OpCodeKind::GetGlobal,
+            OpCode::DefineLocal(_) => OpCodeKind::DefineLocal,
+            OpCode::GetLocal(_) => OpCodeKind::GetLocal,
+            OpCode::SetLocal(_) => OpCodeKind::SetLocal,
+            OpCode::AppendLocal(_) => OpCodeKind::AppendLocal,
+            OpCode::GetUpvalue(_) => OpCodeKind::GetUpvalue,
+            OpCode::LoadClosure(_, _) => OpCodeKind::LoadClosure,
+            OpCode::Jump(_) => OpCodeKind::Jump,
+            OpCode::JumpIfFalse(_) => OpCodeKind::JumpIfFalse,
+            OpCode::Call(_) => OpCodeKind::Call,
+            OpCode::CallNative(_, _) => OpCodeKind::CallNative,
+            OpCode::Return => OpCodeKind::Return,
+            OpCode::Negate => OpCodeKind::Negate,
+            OpCode::Add => OpCodeKind::Add,
+            OpCode::Subtract => OpCodeKind::Subtract,
+            OpCode::Multiply => OpCodeKind::Multiply,
+            OpCode::Divide => OpCodeKind::Divide,
+            OpCode::Not => OpCodeKind::Not,
+            OpCode::Equal => OpCodeKind::Equal,
+            OpCode::GreaterThan => OpCodeKind::GreaterThan,
+            OpCode::LessThan => OpCodeKind::LessThan,
+            OpCode::Print => OpCodeKind::Print,
+            OpCode::Pop => OpCodeKind::Pop,
+            OpCode::PopN(_) => OpCodeKind::PopN,
+            OpCode::Zap(_) => OpCodeKind::Zap,
+            OpCode::PushHandler(_) => OpCodeKind::PushHandler,
+            OpCode::PopHandler => OpCodeKind::PopHandler,
+            OpCode::MakeList(_) => OpCodeKind::MakeList,
+            OpCode::MakeVector(_) => OpCodeKind::MakeVector,
+            OpCode::MakeMap(_) => OpCodeKind::MakeMap,
+            OpCode::Ternary(_, _) => OpCodeKind::Ternary,
+        }
+    }
+
+    // How many values this leaves on the stack, minus how many it takes
+    pub fn stack_effect(&self) -> isize {
+        match self {
+            OpCode::Constant(_) => 1,
+            OpCode::DefineGlobal(_) => 0,
+            OpCode::GetGlobal(_) => 1,
+            OpCode::DefineLocal(_) => 1,
+            OpCode::GetLocal(_) => 1,
+            OpCode::SetLocal(_) => -1,
+            OpCode::AppendLocal(_) => -1,
+            OpCode::GetUpvalue(_) => 1,
+            OpCode::LoadClosure(_, _) => 1,
+            OpCode::Jump(_) => 0,
+            OpCode::JumpIfFalse(_) => 0,
+            OpCode::Call(argc) => -(*argc as isize),
+            OpCode::CallNative(_, argc) => 1 - *argc as isize,
+            OpCode::Return => 0,
+            OpCode::Negate => 0,
+            OpCode::Add => -1,
+            OpCode::Subtract => -1,
+            OpCode::Multiply => -1,
+            OpCode::Divide => -1,
+            OpCode::Not => 0,
+            OpCode::Equal => -1,
+            OpCode::GreaterThan => -1,
+            OpCode::LessThan => -1,
+            OpCode::Print => 0,
+            OpCode::Pop => -1,
+            OpCode::PopN(n) => -(*n as isize),
+            OpCode::Zap(_) => -1,
+            OpCode::PushHandler(_) => 0,
+            OpCode::PopHandler => 0,
+            OpCode::MakeList(n) => 1 - *n as isize,
+            OpCode::MakeVector(n) => 1 - *n as isize,
+            OpCode::MakeMap(n) => 1 - 2 * *n as isize,
+            OpCode::Ternary(_, _) => 0,
+        }
+    }
 }
 
 pub type Line = u32;
 
+// A jump target that can be jumped to before its position is known
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LabelId(usize);
+
+#[derive(Clone, Debug)]
+pub struct Label {
+    target: Option<usize>,
+    // Jumps written before the label was defined, backpatched by define_label
+    forward_refs: Vec<usize>,
+}
+
 #[derive(Clone)]
 pub struct Chunk {
     pub code: Vec<OpCode>,
-    pub lines: Vec<Line>,
+    // Run-length encoded, (line, number of consecutive ops on that line)
+    pub lines: Vec<(Line, usize)>,
     pub constants: ValueArray,
     pub chunks: Vec<Chunk>,
+    // Replaces constants once the pool is shared with other chunks
+    pub shared_constants: Option<Arc<ValueArray>>,
+    // Indexed by LabelId, only used while compiling
+    pub label_map: Vec<Label>,
+    // The source file, if it was compiled from one
+    pub file: Option<String>,
+    // Set on a top-level chunk compiled in REPL mode, which returns the value
+    // of its last expression instead of dropping it
+    pub repl_last_value: bool,
 }
 
 impl Chunk {
@@ -192,28 +896,320 @@ impl Chunk {
         }
     }
 
+    // An empty chunk reading its constants from a pool shared with others
+    pub fn with_shared_constants(pool: Arc<ValueArray>) -> Chunk {
+        Chunk{
+            code: vec![],
+            lines: vec![],
+            constants: vec![],
+            chunks: vec![],
+            shared_constants: Some(pool),
+            label_map: vec![],
+            file: None,
+            repl_last_value: false,
+        }
+    }
+
+    fn constant_pool(&self) -> &ValueArray {
+        match &self.shared_constants {
+            Some(pool) => pool,
+            None => &self.constants,
+        }
+    }
+
+    // Shifts the chunk indices of the functions among this chunk's constants
+    fn relocate_functions(&mut self, by: usize) {
+        for constant in self.constants.iter_mut() {
+            if let Value::Function{chunk_idx, ..} = constant {
+                *chunk_idx += by;
+            }
+        }
+    }
+
+    // Functions defined inside functions have their chunks nested in the
+    // chunk of the function around them. This moves all of them into this
+    // chunk's list and renumbers the functions to match, counting this chunk
+    // as index 0.
+    fn flatten(&mut self) {
+        let children = std::mem::replace(&mut self.chunks, vec![]);
+        let mut positions = vec![];
+        for mut child in children {
+            child.flatten();
+            let position = self.chunks.len() + 1;
+            positions.push(position);
+            let descendants = std::mem::replace(&mut child.chunks, vec![]);
+            for mut chunk in std::iter::once(child).chain(descendants) {
+                chunk.relocate_functions(position);
+                self.chunks.push(chunk);
+            }
+        }
+        for constant in self.constants.iter_mut() {
+            if let Value::Function{chunk_idx, ..} = constant {
+                *chunk_idx = positions[*chunk_idx - 1];
+            }
+        }
+    }
+
+    // Evaluates operators on literal constants at compile time, and drops
+    // jumps on literal conditions, in this chunk and its function chunks.
+    // Needs to run before the constant pools are merged.
+    pub fn constant_fold(&mut self) {
+        for chunk in self.chunks.iter_mut() {
+            chunk.constant_fold();
+        }
+        while self.fold_once() {}
+    }
+
+    // Applies the first fold that fits, returning whether there was one
+    fn fold_once(&mut self) -> bool {
+        let mut targets = HashSet::new();
+        for op in &self.code {
+            match *op {
+                OpCode::Jump(t) | OpCode::JumpIfFalse(t) | OpCode::PushHandler(t) => {
+                    targets.insert(t);
+                }
+                _ => (),
+            }
+        }
+        let literal = |chunk: &Chunk, op: &OpCode| match *op {
+            OpCode::Constant(idx) if chunk.constants[idx].is_literal() => Some(chunk.constants[idx].clone()),
+            _ => None,
+        };
+        for i in 0..self.code.len() {
+            let a = match literal(self, &self.code[i]) {
+                Some(a) => a,
+                None => continue,
+            };
+            let next = self.code.get(i + 1).cloned();
+            if next.is_none() || targets.contains(&(i + 1)) {
+                continue
+            }
+            // Unary operators, and conditions known at compile time
+            let folded = match next.unwrap() {
+                OpCode::Not => Some(Ok(a.not())),
+                OpCode::Negate => Some(a.negate()),
+                OpCode::Ternary(yes, no) => Some(Ok(self.constants[if a.truthy() { yes } else { no }].clone())),
+                OpCode::Pop => {
+                    self.remove_code(i, i + 2);
+                    return true
+                }
+                OpCode::JumpIfFalse(_) if a.truthy() => {
+                    self.remove_code(i + 1, i + 2);
+                    return true
+                }
+                // Always jumps, so everything up to the target goes if
+                // nothing else jumps into it
+                OpCode::JumpIfFalse(t) if i + 1 < t && (i + 2..t).all(|j| !targets.contains(&j)) => {
+                    self.remove_code(i + 1, t);
+                    return true
+                }
+                _ => None,
+            };
+            if let Some(Ok(v)) = folded {
+                if !v.is_nan() {
+                    self.replace_with_constant(i, i + 2, v);
+                    return true
+                }
+                continue
+            }
+            let b = match self.code.get(i + 1).and_then(|op| literal(self, op)) {
+                Some(b) => b,
+                None => continue,
+            };
+            if self.code.len() <= i + 2 || targets.contains(&(i + 2)) {
+                continue
+            }
+            let folded = match self.code[i + 2] {
+                OpCode::Add => a.add(&b),
+                OpCode::Subtract => a.subtract(&b),
+                OpCode::Multiply => a.multiply(&b),
+                OpCode::Divide => a.divide(&b),
+                OpCode::Equal => Ok(a.equal(&b)),
+                OpCode::GreaterThan => a.greater_than(&b),
+                OpCode::LessThan => a.less_than(&b),
+                _ => continue,
+            };
+            // Errors like dividing by zero are left for run time
+            if let Ok(v) = folded {
+                if !v.is_nan() {
+                    self.replace_with_constant(i, i + 3, v);
+                    return true
+                }
+            }
+        }
+        false
+    }
+
+    fn replace_with_constant(&mut self, start: usize, end: usize, value: Value) {
+        let idx = self.write_constant(value);
+        self.code[start] = OpCode::Constant(idx);
+        self.remove_code(start + 1, end);
+    }
+
+    // Removes the instructions from start up to end, moving jumps into the
+    // removed range to what comes after it
+    fn remove_code(&mut self, start: usize, end: usize) {
+        let mut lines = vec![];
+        for &(line, count) in &self.lines {
+            lines.extend(std::iter::repeat(line).take(count));
+        }
+        self.code.drain(start..end);
+        lines.drain(start..end);
+        let moved = |t: usize| if end <= t { t - (end - start) } else if start <= t { start } else { t };
+        for op in self.code.iter_mut() {
+            *op = match *op {
+                OpCode::Jump(t) => OpCode::Jump(moved(t)),
+                OpCode::JumpIfFalse(t) => OpCode::JumpIfFalse(moved(t)),
+                OpCode::PushHandler(t) => OpCode::PushHandler(moved(t)),
+                ref other => other.clone(),
+            };
+        }
+        self.lines = vec![];
+        for line in lines {
+            self.write_line(line);
+        }
+    }
+
+    // Moves the constants of this chunk and its function chunks into one
+    // pool they all share, storing equal literals only once
+    pub fn constant_pool_merge(&mut self) {
+        let mut pool = vec![];
+        let mut seen = HashMap::new();
+        let mut chunks = std::mem::replace(&mut self.chunks, vec![]);
+        for chunk in std::iter::once(&mut *self).chain(chunks.iter_mut()) {
+            let mut index_map = vec![];
+            for constant in chunk.constant_pool().iter() {
+                // Only literals are merged, anything else compares by more
+                // than its printed form
+                let key = if constant.is_literal() {
+                    Some((constant.type_name(), constant.map_key()))
+                } else {
+                    None
+                };
+                let existing = key.as_ref().and_then(|k| seen.get(k).cloned());
+                let idx = match existing {
+                    Some(idx) => idx,
+                    None => {
+                        pool.push(constant.clone());
+                        if let Some(k) = key {
+                            seen.insert(k, pool.len() - 1);
+                        }
+                        pool.len() - 1
+                    }
+                };
+                index_map.push(idx);
+            }
+            for op in chunk.code.iter_mut() {
+                *op = match *op {
+                    OpCode::Constant(ptr) => OpCode::Constant(index_map[ptr]),
+                    OpCode::DefineGlobal(ptr) => OpCode::DefineGlobal(index_map[ptr]),
+                    OpCode::GetGlobal(ptr) => OpCode::GetGlobal(index_map[ptr]),
+                    OpCode::DefineLocal(ptr) => OpCode::DefineLocal(index_map[ptr]),
+                    OpCode::LoadClosure(ptr, ref captures) => OpCode::LoadClosure(index_map[ptr], captures.clone()),
+                    OpCode::Ternary(yes, no) => OpCode::Ternary(index_map[yes], index_map[no]),
+                    ref other => other.clone(),
+                };
+            }
+        }
+        let pool = Arc::new(pool);
+        for chunk in std::iter::once(&mut *self).chain(chunks.iter_mut()) {
+            let mut merged = Chunk::with_shared_constants(pool.clone());
+            merged.code = std::mem::replace(&mut chunk.code, vec![]);
+            merged.lines = std::mem::replace(&mut chunk.lines, vec![]);
+            merged.file = chunk.file.take();
+            *chunk = merged;
+        }
+        self.chunks = chunks;
+    }
+
     fn read_constant(&self, index: usize) -> Value {
-        match &self.constants[index] {
+        match &self.constant_pool()[index] {
             Value::Nil => Value::Nil,
             Value::Bool(b) => Value::Bool(*b),
             Value::Int(n) => Value::Int(*n),
+            Value::BigInt(n) => Value::BigInt(n.clone()),
+            Value::Rational(n, d) => Value::Rational(*n, *d),
             Value::Float(n) => Value::Float(*n),
+            Value::Complex(re, im) => Value::Complex(*re, *im),
+            Value::Char(c) => Value::Char(*c),
             Value::String(s) => Value::String(String::from(s)),
-            Value::Symbol(s) => Value::Symbol(String::from(s)),
-            Value::Function(s, a, c) => Value::Function(String::from(s), a.clone(), c.clone()),
+            Value::Symbol(s) => Value::Symbol(*s),
+            Value::Keyword(s) => Value::Keyword(String::from(s)),
+            Value::Function{name, params, keywords, rest, chunk_idx, file, line, upvalues} => Value::Function{
+                name: name.clone(),
+                params: params.clone(),
+                keywords: keywords.clone(),
+                rest: rest.clone(),
+                chunk_idx: *chunk_idx,
+                file: file.clone(),
+                line: *line,
+                upvalues: upvalues.clone(),
+            },
+            Value::Native(idx) => Value::Native(*idx),
+            Value::MultiArity{name, clauses} => Value::MultiArity{
+                name: name.clone(),
+                clauses: clauses.clone(),
+            },
+            Value::List(l) => Value::List(l.clone()),
+            Value::Vector(v) => Value::Vector(v.clone()),
+            Value::Map(m) => Value::Map(m.clone()),
+            Value::WithMeta(v, m) => Value::WithMeta(v.clone(), m.clone()),
+            Value::Regex(r) => Value::Regex(r.clone()),
+            Value::StringBuilder(sb) => Value::StringBuilder(sb.clone()),
+            Value::TransientMap(m) => Value::TransientMap(m.clone()),
+            Value::Port(p) => Value::Port(p.clone()),
+            Value::OutPort(p) => Value::OutPort(p.clone()),
+            Value::Promise(p) => Value::Promise(p.clone()),
+            Value::Stream(s) => Value::Stream(s.clone()),
+            Value::Error{kind, message, data, cause} => Value::Error{
+                kind: kind.clone(),
+                message: message.clone(),
+                data: data.clone(),
+                cause: cause.clone(),
+            },
         }
     }
 
     pub fn write_constant(&mut self, value: Value) -> usize {
+        assert!(self.shared_constants.is_none(), "Writing to a shared constant pool");
         self.constants.append(&mut vec![value]);
         self.constants.len() - 1
     }
 
-    pub fn backpatch_jump(&mut self, idx: usize) {
-        let target = self.code.len() - 1;
+    pub fn make_label(&mut self) -> LabelId {
+        self.label_map.push(Label{target: None, forward_refs: vec![]});
+        LabelId(self.label_map.len() - 1)
+    }
+
+    // Writes a jump built by `jump`, e.g. OpCode::Jump, to the label
+    pub fn emit_jump_to(&mut self, jump: fn(usize) -> OpCode, label: LabelId, line: Line) {
+        let idx = self.code.len();
+        let target = match self.label_map[label.0].target {
+            Some(target) => target,
+            None => {
+                self.label_map[label.0].forward_refs.push(idx);
+                0
+            }
+        };
+        self.write_code(jump(target), line);
+    }
+
+    // Places the label at the next instruction to be written
+    pub fn define_label(&mut self, label: LabelId) {
+        self.label_map[label.0].target = Some(self.code.len());
+        let forward_refs = std::mem::replace(&mut self.label_map[label.0].forward_refs, vec![]);
+        for idx in forward_refs {
+            self.backpatch_jump(idx);
+        }
+    }
+
+    // Points the jump at the next instruction to be written
+    fn backpatch_jump(&mut self, idx: usize) {
+        let target = self.code.len();
         match self.code[idx] {
             OpCode::Jump(_) => self.code[idx] = OpCode::Jump(target),
             OpCode::JumpIfFalse(_) => self.code[idx] = OpCode::JumpIfFalse(target),
+            OpCode::PushHandler(_) => self.code[idx] = OpCode::PushHandler(target),
             _ => panic!("This is not a jump"),
         }
     }
@@ -224,15 +1220,30 @@ impl Chunk {
     }
 
     fn write_line(&mut self, line: Line) {
-        self.lines.append(&mut vec![line]);
+        match self.lines.last_mut() {
+            Some((l, count)) if *l == line => *count += 1,
+            _ => self.lines.append(&mut vec![(line, 1)]),
+        }
+    }
+
+    pub fn line_for_instruction(&self, ip: usize) -> Line {
+        let mut seen = 0;
+        for (line, count) in &self.lines {
+            seen += count;
+            if ip < seen {
+                return *line
+            }
+        }
+        panic!("No line for instruction {}", ip)
     }
 
     fn disassemble_instruction(&self, index: usize) {
         let instruction: &OpCode = &self.code[index];
-        if index > 0 && &self.lines[index] == &self.lines[index-1] {
+        let line = self.line_for_instruction(index);
+        if index > 0 && line == self.line_for_instruction(index-1) {
             print!("{:04x} {:>5} ", index, "|");
         } else {
-            print!("{:04x} {:>5} ", index, &self.lines[index]);
+            print!("{:04x} {:>5} ", index, line);
         };
         match instruction {
             OpCode::Constant(ptr) => println!("CONSTANT\t[{:04}] =>\t{:?}", ptr, self.read_constant(*ptr)),
@@ -240,9 +1251,22 @@ impl Chunk {
             OpCode::GetGlobal(ptr) => println!("GET GLOBAL\t[{:04}] =>\t{:?}", ptr, self.read_constant(*ptr)),
             OpCode::DefineLocal(ptr) => println!("DEF LOCAL\t[{:04x}]", ptr),
             OpCode::GetLocal(ptr) => println!("GET LOCAL\t[{:04x}]", ptr),
+            OpCode::SetLocal(ptr) => println!("SET LOCAL\t[{:04x}]", ptr),
+            OpCode::AppendLocal(ptr) => println!("APPEND LOCAL\t[{:04x}]", ptr),
+            OpCode::GetUpvalue(idx) => println!("GET UPVALUE\t[{:04x}]", idx),
+            OpCode::LoadClosure(ptr, captures) => {
+                let captures: Vec<String> = captures.iter()
+                    .map(|c| match c {
+                        Capture::Local(slot) => format!("local {}", slot),
+                        Capture::Upvalue(idx) => format!("upvalue {}", idx),
+                    })
+                    .collect();
+                println!("CLOSURE\t\t[{:04}] =>\t{:?} [{}]", ptr, self.read_constant(*ptr), captures.join(", "))
+            }
             OpCode::Jump(ptr) => println!("JMP\t\t[{:04x}]", ptr),
             OpCode::JumpIfFalse(ptr) => println!("JMP IF F\t[{:04x}]", ptr),
             OpCode::Call(argc) => println!("CALL\t\t[{:4}]", argc),
+            OpCode::CallNative(idx, argc) => println!("CALL NATIVE\t[{:4}] {}", argc, natives::NATIVES[*idx].0),
             OpCode::Return => println!("RETURN"),
             OpCode::Negate => println!("NEGATE"),
             OpCode::Add => println!("ADD"),
@@ -255,7 +1279,15 @@ impl Chunk {
             OpCode::LessThan => println!("LT"),
             OpCode::Print => println!("PRINT"),
             OpCode::Pop => println!("POP"),
+            OpCode::PopN(n) => println!("POP N\t\t[{:4}]", n),
             OpCode::Zap(ptr) => println!("ZAP\t\t[{:04}]", ptr),
+            OpCode::PushHandler(ptr) => println!("PUSH HANDLER\t[{:04x}]", ptr),
+            OpCode::PopHandler => println!("POP HANDLER"),
+            OpCode::MakeList(n) => println!("MAKE LIST\t[{:4}]", n),
+            OpCode::MakeVector(n) => println!("MAKE VECTOR\t[{:4}]", n),
+            OpCode::MakeMap(n) => println!("MAKE MAP\t[{:4}]", n),
+            OpCode::Ternary(yes, no) => println!("TERNARY\t\t[{:04}] [{:04}] =>\t{:?} {:?}",
+                                                 yes, no, self.read_constant(*yes), self.read_constant(*no)),
         }
     }
 }
@@ -263,7 +1295,7 @@ impl Chunk {
 impl std::fmt::Debug for Chunk {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         write!(f, "code: {:?}\nconstants: {:?}\nlines: {:?}",
-               self.code, self.constants, self.lines)
+               self.code, self.constant_pool(), self.lines)
     }
 }
 
@@ -275,22 +1307,160 @@ pub struct CallFrame {
     chunk_idx: usize,
 }
 
+// Globals are shared with the VMs running promises on other threads
+pub type Globals = Arc<RwLock<HashMap<String, Value>>>;
+
+// Where to unwind to when an error is raised inside a `try`
+#[derive(Debug)]
+struct Handler {
+    frame_depth: usize,
+    stack_len: usize,
+    target: usize,
+}
+
 pub struct VM {
-    stack: ValueArray,
-    globals: HashMap<String, Value>,
+    stack: Vec<NanBoxed>,
+    globals: Globals,
     call_stack: Vec<CallFrame>,
-    chunks: Vec<Chunk>,
+    // Shared with the VMs running promises, so spawning one doesn't copy them
+    chunks: Vec<Arc<Chunk>>,
+    debug: bool,
+    input_port: InputPort,
+    output_port: OutputPort,
+    error_port: OutputPort,
+    handlers: Vec<Handler>,
+    // Set for natives a global of the same name has replaced, which calls
+    // compiled as CallNative go to instead
+    shadowed_natives: Arc<Vec<AtomicBool>>,
+    // Counts dispatched instructions while set, see print_profile
+    profile_mode: bool,
+    opcode_counts: HashMap<OpCodeKind, u64>,
+}
+
+// What went wrong in an error from the VM or a builtin
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    Arity,
+    Type,
+    KeyNotFound,
+    IndexOutOfBounds,
+    Runtime,
+}
+
+impl ErrorKind {
+    // The kind of the error value a handler receives, see error-kind
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::Arity => "arity-error",
+            ErrorKind::Type => "type-error",
+            ErrorKind::KeyNotFound => "key-not-found",
+            ErrorKind::IndexOutOfBounds => "index-out-of-bounds",
+            ErrorKind::Runtime => "runtime-error",
+        }
+    }
+}
+
+// Errors unwind to the innermost `try`, whose handler gets them as a value
+#[derive(Clone, Debug)]
+pub enum RuntimeError {
+    Error(ErrorKind, String),
+    // Anything passed to `raise`, which the handler gets unchanged
+    Raised(Value),
+}
+
+impl RuntimeError {
+    pub fn into_value(self) -> Value {
+        match self {
+            RuntimeError::Error(kind, message) => Value::Error{
+                kind: String::from(kind.name()),
+                message: message,
+                data: Box::new(Value::Nil),
+                cause: Box::new(Value::Nil),
+            },
+            RuntimeError::Raised(v) => v,
+        }
+    }
+}
+
+// What gets printed when nothing catches the error
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RuntimeError::Error(_, message) => write!(f, "{}", message),
+            RuntimeError::Raised(Value::Error{kind, message, ..}) => write!(f, "{}: {}", kind, message),
+            RuntimeError::Raised(v) => write!(f, "Raised {:?}", v),
+        }
+    }
+}
+
+fn arity_error(message: String) -> RuntimeError {
+    RuntimeError::Error(ErrorKind::Arity, message)
+}
+
+fn type_error(message: String) -> RuntimeError {
+    RuntimeError::Error(ErrorKind::Type, message)
+}
+
+fn key_error(message: String) -> RuntimeError {
+    RuntimeError::Error(ErrorKind::KeyNotFound, message)
+}
+
+fn index_error(message: String) -> RuntimeError {
+    RuntimeError::Error(ErrorKind::IndexOutOfBounds, message)
+}
+
+fn runtime_error(message: String) -> RuntimeError {
+    RuntimeError::Error(ErrorKind::Runtime, message)
 }
 
-fn runtime_error(msg: &str) -> Result<(), String> {
-    Err(String::from(msg))
+// Functions with keyword or `&rest` parameters get the arguments after the
+// positional ones as a single last argument, a map or a list (nil if empty)
+fn extra_args(name: &str, keywords: &[String], rest: &Option<String>, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    match rest {
+        Some(_) if args.is_empty() => Ok(Value::Nil),
+        Some(_) => Ok(Value::List(args)),
+        None => keyword_args(name, keywords, args),
+    }
+}
+
+// Folds the `:key value` pairs after the positional arguments into a map
+fn keyword_args(name: &str, keywords: &[String], args: Vec<Value>) -> Result<Value, RuntimeError> {
+    if args.len() % 2 != 0 {
+        return Err(arity_error(format!("Arity mismatch: {} expects keyword arguments in pairs, got {}", name, args.len())))
+    }
+    let mut map = OrderedMap::new();
+    for pair in args.chunks(2) {
+        match &pair[0] {
+            Value::Keyword(k) if keywords.contains(k) =>
+                map.insert(pair[0].map_key(), (pair[0].clone(), pair[1].clone())),
+            k => return Err(arity_error(format!("{} has no keyword parameter {:?}", name, k))),
+        }
+    }
+    Ok(Value::Map(map))
+}
+
+// The clause of a multi-arity function taking argc arguments
+fn select_arity(name: &str, clauses: Vec<Value>, argc: usize) -> Result<Value, RuntimeError> {
+    // A clause taking exactly argc arguments wins over a `&rest` one
+    let mut variadic = None;
+    for clause in clauses {
+        if let Value::Function{ref params, ref rest, ..} = clause {
+            if params.len() == argc && rest.is_none() {
+                return Ok(clause)
+            }
+            if rest.is_some() && params.len() <= argc {
+                variadic = Some(clause.clone());
+            }
+        }
+    }
+    variadic.ok_or(arity_error(format!("Arity mismatch: {} has no clause taking {} arguments", name, argc)))
 }
 
 impl VM {
     fn print_state(&self) {
         println!("== vm state ==");
         println!("stack: {:?}", self.stack);
-        println!("globals: {:?}", self.globals);
+        println!("globals: {:?}", self.globals.read().unwrap());
         println!("call_stack: {:?}", self.call_stack);
     }
 
@@ -298,39 +1468,343 @@ impl VM {
         self.call_stack.last().unwrap()
     }
 
+    // Captured values live in the closure being run, in slot 0 of its frame
+    fn upvalue(&self, idx: usize) -> Result<Value, RuntimeError> {
+        let function = self.stack[self.current_frame().stack_start].as_value();
+        let upvalues = match &*function {
+            Value::Function{upvalues, ..} => upvalues,
+            Value::WithMeta(f, _) => match &**f {
+                Value::Function{upvalues, ..} => upvalues,
+                _ => return Err(runtime_error(String::from("Upvalue outside of a function"))),
+            },
+            _ => return Err(runtime_error(String::from("Upvalue outside of a function"))),
+        };
+        match upvalues.get(idx) {
+            Some(v) => Ok(v.clone()),
+            None => Err(index_error(format!("Upvalue {} out of bounds", idx))),
+        }
+    }
+
     fn current_frame_mut(&mut self) -> &mut CallFrame {
         self.call_stack.last_mut().unwrap()
     }
 
-    fn pop(&mut self) -> Result<Value, String> {
+    fn pop(&mut self) -> Result<Value, RuntimeError> {
         if self.stack.is_empty() {
-            Err(String::from("Empty stack"))
+            Err(runtime_error(String::from("Empty stack")))
         } else {
-            Ok(self.stack.pop().unwrap())
+            Ok(self.stack.pop().unwrap().into_value())
+        }
+    }
+
+    // The top n values, bottom-first
+    fn pop_n(&mut self, n: usize) -> Result<Vec<Value>, RuntimeError> {
+        if self.stack.len() < n {
+            return Err(runtime_error(String::from("Empty stack")))
         }
+        let len = self.stack.len();
+        Ok(self.stack.split_off(len - n).into_iter().map(NanBoxed::into_value).collect())
     }
 
-    fn peek(&mut self) -> Result<&Value, String> {
+    fn peek(&mut self) -> Result<Cow<'_, Value>, RuntimeError> {
         if self.stack.is_empty() {
-            Err(String::from("Empty stack"))
+            Err(runtime_error(String::from("Empty stack")))
         } else {
-            Ok(self.stack.last().unwrap())
+            Ok(self.stack.last().unwrap().as_value())
         }
     }
 
-    fn pick(&self, offset: usize) -> Result<&Value, String> {
+    fn pick(&self, offset: usize) -> Result<Cow<'_, Value>, RuntimeError> {
         if self.stack.len() <= offset {
-            Err(String::from("Pick out of bounds"))
+            Err(index_error(String::from("Pick out of bounds")))
         } else {
-            Ok(&self.stack[self.stack.len() - offset - 1])
+            Ok(self.stack[self.stack.len() - offset - 1].as_value())
+        }
+    }
+
+    // Natives are found when no global has their name
+    fn lookup_global(&self, name: &str) -> Option<Value> {
+        resolve_namespaced_symbol(name, &self.globals.read().unwrap())
+            .or_else(|| natives::find_native(name).map(Value::Native))
+    }
+
+    // Clears the stack and unwinds to the top-level frame, keeping globals
+    pub fn reset_stack(&mut self) {
+        self.stack.clear();
+        self.call_stack = vec![main_frame(0)];
+        self.handlers.clear();
+    }
+
+    // Whether nothing is left over from a previous run, as after reset_stack
+    #[allow(dead_code)]
+    pub fn is_idle(&self) -> bool {
+        self.stack.is_empty() && self.call_stack.len() == 1 && self.handlers.is_empty()
+    }
+
+    // Also forgets all globals and the functions they refer to
+    pub fn reset_all(&mut self) {
+        self.reset_stack();
+        self.globals.write().unwrap().clear();
+        self.chunks.clear();
+        for shadowed in self.shadowed_natives.iter() {
+            shadowed.store(false, atomic::Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    pub fn set_profile_mode(&mut self, profile_mode: bool) {
+        self.profile_mode = profile_mode;
+    }
+
+    // Prints how often each opcode was dispatched, most frequent first
+    pub fn print_profile(&self) {
+        let mut counts: Vec<(&OpCodeKind, &u64)> = self.opcode_counts.iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(a.1).then(format!("{:?}", a.0).cmp(&format!("{:?}", b.0))));
+        let total: u64 = self.opcode_counts.values().sum();
+        println!("== profile ==");
+        for (kind, count) in counts {
+            println!("{:<12} {:>10} {:>5.1}%", format!("{:?}", kind), count,
+                     100.0 * *count as f64 / total as f64);
+        }
+        println!("{:<12} {:>10}", "total", total);
+    }
+
+    // A snapshot of all globals
+    pub fn globals(&self) -> HashMap<String, Value> {
+        self.globals.read().unwrap().clone()
+    }
+
+    // Adds a chunk and its function chunks, returning the chunk's index
+    fn load_chunk(&mut self, mut chunk: Chunk) -> usize {
+        // Chunks from earlier runs are kept for the functions defined in
+        // them, so this chunk's function indices need to be shifted past them
+        let chunk_base = self.chunks.len();
+        chunk.flatten();
+        chunk.relocate_functions(chunk_base);
+        for function_chunk in chunk.chunks.iter_mut() {
+            function_chunk.relocate_functions(chunk_base);
+        }
+        chunk.constant_pool_merge();
+        let function_chunks = std::mem::replace(&mut chunk.chunks, vec![]);
+        self.chunks.push(Arc::new(chunk));
+        self.chunks.extend(function_chunks.into_iter().map(Arc::new));
+        chunk_base
+    }
+
+    // Returns the value of the last expression if the chunk was compiled in
+    // REPL mode, and nothing otherwise
+    pub fn interpret<'a>(&mut self, start_chunk: Chunk, debug: bool) -> Result<Option<Value>, String> {
+        self.debug = debug;
+        let repl_last_value = start_chunk.repl_last_value;
+        let chunk_idx = self.load_chunk(start_chunk);
+        self.stack = vec![Value::function("main", chunk_idx).into()];
+        self.call_stack = vec![main_frame(chunk_idx)];
+        try!(self.run().map_err(|error| format!("{}{}", error, self.stack_trace())));
+        if repl_last_value {
+            self.pop().map(Some).map_err(|error| error.to_string())
+        } else {
+            Ok(None)
+        }
+    }
+
+    // The frames left on the call stack by an uncaught error, innermost first
+    fn stack_trace(&self) -> String {
+        let mut trace = String::new();
+        for frame in self.call_stack.iter().rev() {
+            let chunk = &self.chunks[frame.chunk_idx];
+            let file = chunk.file.as_ref().map_or("<input>", |f| f.as_str());
+            let line = chunk.line_for_instruction(frame.ip);
+            trace.push_str(&format!("\n  at {} ({}:{})", frame.fn_name, file, line));
+        }
+        trace
+    }
+
+    // Runs a chunk on top of the current stack and returns the value it
+    // evaluates to, sharing globals with the running program
+    pub fn eval_chunk(&mut self, chunk: Chunk) -> Result<Value, RuntimeError> {
+        let chunk_idx = self.load_chunk(chunk);
+        self.call_value(Value::function("eval", chunk_idx), vec![])
+    }
+
+    // Calls a function from outside the dispatch loop and returns its result
+    pub fn call_value(&mut self, f: Value, mut args: Vec<Value>) -> Result<Value, RuntimeError> {
+        match f {
+            Value::Keyword(_) | Value::Symbol(_) => return natives::call_key(&f, &args),
+            _ => (),
+        }
+        let f = match f {
+            Value::WithMeta(f, _) => *f,
+            f => f,
+        };
+        let f = match f {
+            Value::MultiArity{name, clauses} => try!(select_arity(&name, clauses, args.len())),
+            Value::Native(idx) => return (natives::NATIVES[idx].1)(self, &args),
+            f => f,
+        };
+        let (name, params, chunk_idx) = match &f {
+            Value::Function{name, params, keywords, rest, chunk_idx, ..} if !keywords.is_empty() || rest.is_some() => {
+                if args.len() < params.len() {
+                    return Err(arity_error(format!("Arity mismatch: {} expects at least {}, got {}", name, params.len(), args.len())))
+                }
+                let extra = args.split_off(params.len());
+                args.push(try!(extra_args(name, keywords, rest, extra)));
+                (name.clone(), args.len(), *chunk_idx)
+            }
+            Value::Function{name, params, chunk_idx, ..} => (name.clone(), params.len(), *chunk_idx),
+            _ => return Err(type_error(format!("{} is not callable", f))),
+        };
+        if params != args.len() {
+            return Err(arity_error(format!("Arity mismatch: {} expects {}, got {}", name, params, args.len())))
+        }
+        let stack_start = self.stack.len();
+        self.stack.push(f.into());
+        self.stack.extend(args.drain(..).map(NanBoxed::from));
+        self.call_stack.append(&mut vec![CallFrame{
+            fn_name: name,
+            ip: 0,
+            stack_start: stack_start,
+            chunk_idx: chunk_idx,
+        }]);
+        try!(self.run());
+        self.pop()
+    }
+
+    // Like call_value, for embedding: on an error the stack and call stack
+    // are put back the way they were, so the VM can be used again. The error
+    // keeps its kind, and displays as its message.
+    pub fn call_function(&mut self, f: &Value, args: &[Value]) -> Result<Value, RuntimeError> {
+        let stack_len = self.stack.len();
+        let frames = self.call_stack.len();
+        let result = self.call_value(f.clone(), args.to_vec());
+        if result.is_err() {
+            self.stack.truncate(stack_len);
+            self.call_stack.truncate(frames);
+        }
+        result
+    }
+
+    // Calls the value below the top argc values on the stack. Returns true
+    // if that pushed a frame to run, and false if the result is already on
+    // the stack in place of the function and its arguments.
+    fn call(&mut self, argc: usize) -> Result<bool, RuntimeError> {
+        // Plain functions are the common case, which don't need to be copied
+        // off the stack
+        let plain = match &*try!(self.pick(argc)) {
+            Value::Function{name, params, keywords, rest: None, chunk_idx, ..}
+                if keywords.is_empty() && params.len() == argc => Some((name.clone(), *chunk_idx)),
+            _ => None,
+        };
+        if let Some((n, c_idx)) = plain {
+            self.call_stack.append(&mut vec![CallFrame{
+                fn_name: n,
+                ip: 0,
+                stack_start: self.stack.len() - argc - 1,
+                chunk_idx: c_idx,
+            }]);
+            return Ok(true)
+        }
+        let f = match try!(self.pick(argc)).into_owned() {
+            Value::WithMeta(f, _) => *f,
+            f => f,
+        };
+        let f = match f {
+            Value::MultiArity{name, clauses} => {
+                let clause = try!(select_arity(&name, clauses, argc));
+                // The clause replaces the callee so upvalues are read from it
+                let callee = self.stack.len() - argc - 1;
+                self.stack[callee] = clause.clone().into();
+                clause
+            }
+            f => f,
+        };
+        match f {
+            Value::Function{name: n, params, keywords, rest, chunk_idx: c_idx, ..} => {
+                let argc = if keywords.is_empty() && rest.is_none() {
+                    if params.len() != argc {
+                        return Err(arity_error(format!{"Arity mismatch: {} expects {}, got {}", n, params.len(), argc}))
+                    }
+                    argc
+                } else {
+                    if argc < params.len() {
+                        return Err(arity_error(format!{"Arity mismatch: {} expects at least {}, got {}", n, params.len(), argc}))
+                    }
+                    let extra: Vec<Value> = self.stack.split_off(self.stack.len() - (argc - params.len()))
+                        .into_iter()
+                        .map(NanBoxed::into_value)
+                        .collect();
+                    let extra = try!(extra_args(&n, &keywords, &rest, extra));
+                    self.stack.push(extra.into());
+                    params.len() + 1
+                };
+                self.call_stack.append(&mut vec![CallFrame{
+                    fn_name: n.to_string(),
+                    ip: 0,
+                    stack_start: self.stack.len() - argc - 1,
+                    chunk_idx: c_idx,
+                }]);
+                Ok(true)
+            }
+            Value::Keyword(_) | Value::Symbol(_) | Value::Native(_) => {
+                let args: Vec<Value> = self.stack.split_off(self.stack.len() - argc)
+                    .into_iter()
+                    .map(NanBoxed::into_value)
+                    .collect();
+                self.stack.pop();
+                let v = match f {
+                    Value::Native(idx) => try!((natives::NATIVES[idx].1)(self, &args)),
+                    _ => try!(natives::call_key(&f, &args)),
+                };
+                self.stack.push(v.into());
+                Ok(false)
+            }
+            _ => Err(type_error(format!("{} is not callable", f))),
+        }
+    }
+
+    // A fresh VM for another thread, sharing globals, functions and ports
+    pub fn spawn(&self) -> VM {
+        VM{
+            stack: vec![],
+            globals: self.globals.clone(),
+            call_stack: vec![],
+            chunks: self.chunks.clone(),
+            debug: self.debug,
+            input_port: self.input_port.clone(),
+            output_port: self.output_port.clone(),
+            error_port: self.error_port.clone(),
+            handlers: vec![],
+            shadowed_natives: self.shadowed_natives.clone(),
+            // Promises run on their own threads and are not profiled
+            profile_mode: false,
+            opcode_counts: HashMap::new(),
         }
     }
 
-    pub fn interpret<'a>(&mut self, start_chunk: Chunk, debug: bool) -> Result<(), String> {
-        let mut all_chunks = start_chunk.chunks.clone();
-        self.chunks.append(&mut vec![start_chunk]);
-        self.chunks.append(&mut all_chunks);
-        self.stack = vec![Value::Function(String::from("main"), 0, 0)];
+    // Runs until the frame on top of the call stack returns, unwinding to the
+    // innermost handler installed during this run on errors
+    fn run(&mut self) -> Result<(), RuntimeError> {
+        let run_depth = self.call_stack.len();
+        loop {
+            let error = match self.execute(run_depth) {
+                Ok(()) => return Ok(()),
+                Err(error) => error,
+            };
+            let handler = match self.handlers.last() {
+                Some(h) if run_depth <= h.frame_depth => self.handlers.pop().unwrap(),
+                _ => return Err(error),
+            };
+            self.call_stack.truncate(handler.frame_depth);
+            self.stack.truncate(handler.stack_len);
+            self.stack.push(error.into_value().into());
+            self.current_frame_mut().ip = handler.target;
+        }
+    }
+
+    fn execute(&mut self, run_depth: usize) -> Result<(), RuntimeError> {
+        let debug = self.debug;
         loop {
             let mut curr_chunk = &self.chunks[self.call_stack.last().unwrap().chunk_idx];
             let chunk = &mut curr_chunk;
@@ -338,62 +1812,128 @@ impl VM {
             if debug {
                 chunk.disassemble_instruction(self.current_frame().ip);
             }
-            let done = self.call_stack.len() == 1 && chunk.code.len() - 1 <= current_frame.ip;
+            let done = self.call_stack.len() == run_depth && chunk.code.len() - 1 <= current_frame.ip;
+            if self.profile_mode {
+                *self.opcode_counts.entry(chunk.code[current_frame.ip].kind()).or_insert(0) += 1;
+            }
             match chunk.code[current_frame.ip] {
                 OpCode::Constant(ptr) => {
-                    self.stack.push(chunk.read_constant(ptr));
+                    self.stack.push(chunk.read_constant(ptr).into());
                 }
                 OpCode::DefineGlobal(ptr) => {
                     let v = try!(
                         if self.stack.is_empty() {
-                            Err(String::from("Empty stack"))
+                            Err(runtime_error(String::from("Empty stack")))
                         } else {
-                            Ok(self.stack.pop().unwrap())
+                            Ok(self.stack.pop().unwrap().into_value())
                         }
                     );
                     let name = chunk.read_constant(ptr);
-                    self.globals.insert(name.to_string(), v);
-                    self.stack.push(Value::Symbol(name.to_string()));
+                    if let Some(idx) = natives::find_native(&name.to_string()) {
+                        self.shadowed_natives[idx].store(true, atomic::Ordering::Relaxed);
+                    }
+                    self.globals.write().unwrap().insert(name.to_string(), v);
+                    self.stack.push(Value::Symbol(intern(&name.to_string())).into());
                 }
                 OpCode::GetGlobal(ptr) => {
                     let name = chunk.read_constant(ptr);
-                    match self.globals.get(&name.to_string()) {
-                        Some(v) => self.stack.push(v.clone()),
-                        None => break runtime_error(format!("Symbol {} not found", name).as_str()),
+                    match self.lookup_global(&name.to_string()) {
+                        Some(v) => self.stack.push(v.into()),
+                        None => break Err(key_error(format!("Symbol {} not found", name))),
                     }
                 }
                 OpCode::DefineLocal(ptr) => {
                     let name = chunk.read_constant(ptr);
-                    self.stack.push(Value::Symbol(name.to_string()));
+                    self.stack.push(Value::Symbol(intern(&name.to_string())).into());
                 }
                 OpCode::GetLocal(idx) => {
                     // + 1 to jump over the function in slot 0
                     let total_idx = self.current_frame().stack_start + idx + 1;
-                    self.stack.push(self.stack[total_idx].clone());
+                    let v = self.stack[total_idx].clone();
+                    self.stack.push(v);
+                }
+                OpCode::GetUpvalue(idx) => {
+                    let v = try!(self.upvalue(idx));
+                    self.stack.push(v.into());
+                }
+                OpCode::LoadClosure(ptr, ref captures) => {
+                    let captures = captures.clone();
+                    let mut function = chunk.read_constant(ptr);
+                    let mut values = vec![];
+                    for capture in captures {
+                        values.push(match capture {
+                            Capture::Local(slot) => {
+                                let total_idx = self.current_frame().stack_start + slot + 1;
+                                self.stack[total_idx].as_value().into_owned()
+                            }
+                            Capture::Upvalue(idx) => try!(self.upvalue(idx)),
+                        });
+                    }
+                    if let Value::Function{ref mut upvalues, ..} = function {
+                        *upvalues = Arc::new(values);
+                    }
+                    self.stack.push(function.into());
+                }
+                OpCode::SetLocal(idx) => {
+                    let v = try!(self.pop());
+                    let total_idx = self.current_frame().stack_start + idx + 1;
+                    if self.stack.len() <= total_idx {
+                        return Err(index_error(String::from("SetLocal out of bounds")))
+                    }
+                    self.stack[total_idx] = v.into();
+                }
+                OpCode::AppendLocal(idx) => {
+                    let v = try!(self.pop());
+                    let total_idx = self.current_frame().stack_start + idx + 1;
+                    if self.stack.len() <= total_idx {
+                        return Err(index_error(String::from("AppendLocal out of bounds")))
+                    }
+                    let local = std::mem::replace(&mut self.stack[total_idx], Value::Nil.into());
+                    let mut items = match local.into_value() {
+                        Value::Vector(items) => items,
+                        other => return Err(type_error(format!("Cannot append to {:?}", other))),
+                    };
+                    items.push(v);
+                    self.stack[total_idx] = Value::Vector(items).into();
+                }
+                // Jump targets are the next instruction to run, so these
+                // shortcut the ip++ at the end
+                OpCode::Jump(ptr) => {
+                    self.current_frame_mut().ip = ptr;
+                    continue
                 }
-                OpCode::Jump(ptr) => self.current_frame_mut().ip = ptr,
                 OpCode::JumpIfFalse(ptr) => {
                     let v = try!(self.peek());
                     if !v.truthy() {
                         self.current_frame_mut().ip = ptr;
+                        continue
                     }
                 }
                 OpCode::Call(argc) => {
-                    let f = try!(self.pick(argc)).clone();
-                    match f {
-                        Value::Function(n, a, c_idx) => {
-                            if a != argc {
-                                break Err(format!{"Arity mismatch: {} expects {}, got {}", n, a, argc})
-                            }
-                            self.call_stack.append(&mut vec![CallFrame{
-                                fn_name: n.to_string(),
-                                ip: 0,
-                                stack_start: self.stack.len() - argc - 1,
-                                chunk_idx: c_idx,
-                            }]);
+                    if try!(self.call(argc)) {
+                        continue // shortcut the ip++ at the end
+                    }
+                }
+                OpCode::CallNative(idx, argc) => {
+                    if self.stack.len() < argc {
+                        return Err(runtime_error(String::from("Empty stack")))
+                    }
+                    let (name, native) = natives::NATIVES[idx];
+                    if self.shadowed_natives[idx].load(atomic::Ordering::Relaxed) {
+                        // Called like any other function, from below its arguments
+                        let f = try!(self.lookup_global(name).ok_or_else(|| key_error(format!("Symbol {} not found", name))));
+                        let callee = self.stack.len() - argc;
+                        self.stack.insert(callee, f.into());
+                        if try!(self.call(argc)) {
                             continue // shortcut the ip++ at the end
                         }
-                        _ => break Err(format!("{} is not callable", f))
+                    } else {
+                        let args: Vec<Value> = self.stack.split_off(self.stack.len() - argc)
+                            .into_iter()
+                            .map(NanBoxed::into_value)
+                            .collect();
+                        let v = try!(native(self, &args));
+                        self.stack.push(v.into());
                     }
                 }
                 OpCode::Return => {
@@ -404,72 +1944,110 @@ impl VM {
                         }
                     }
                     self.call_stack.pop();
-                    self.stack.push(c);
+                    self.stack.push(c.into());
                 }
                 OpCode::Negate => {
                     let v = try!(self.pop());
                     let nv = try!(v.negate());
-                    self.stack.push(nv);
+                    self.stack.push(nv.into());
                 }
                 OpCode::Add => {
                     let a = try!(self.pop());
                     let b = try!(self.pop());
                     let v = try!(b.add(&a));
-                    self.stack.push(v);
+                    self.stack.push(v.into());
                 }
                 OpCode::Subtract => {
                     let a = try!(self.pop());
                     let b = try!(self.pop());
                     let v = try!(b.subtract(&a));
-                    self.stack.push(v);
+                    self.stack.push(v.into());
                 }
                 OpCode::Multiply => {
                     let a = try!(self.pop());
                     let b = try!(self.pop());
                     let v = try!(b.multiply(&a));
-                    self.stack.push(v);
+                    self.stack.push(v.into());
                 }
                 OpCode::Divide => {
                     let a = try!(self.pop());
                     let b = try!(self.pop());
                     let v = try!(b.divide(&a));
-                    self.stack.push(v);
+                    self.stack.push(v.into());
                 }
                 OpCode::Not => {
                     let b = try!(self.pop());
-                    self.stack.push(b.not());
+                    self.stack.push(b.not().into());
+                }
+                OpCode::Ternary(yes, no) => {
+                    let v = match self.stack.pop() {
+                        Some(v) => v.into_value(),
+                        None => return Err(runtime_error(String::from("Empty stack"))),
+                    };
+                    self.stack.push(chunk.read_constant(if v.truthy() { yes } else { no }).into());
                 }
                 OpCode::Equal => {
                     let a = try!(self.pop());
                     let b = try!(self.pop());
-                    self.stack.push(b.equal(&a));
+                    self.stack.push(b.equal(&a).into());
                 }
                 OpCode::GreaterThan => {
                     let a = try!(self.pop());
                     let b = try!(self.pop());
                     let v = try!(b.greater_than(&a));
-                    self.stack.push(v);
+                    self.stack.push(v.into());
                 }
                 OpCode::LessThan => {
                     let a = try!(self.pop());
                     let b = try!(self.pop());
                     let v = try!(b.less_than(&a));
-                    self.stack.push(v);
+                    self.stack.push(v.into());
                 }
                 OpCode::Print => {
                     let c = try!(self.pop());
-                    println!("{}", c); // TODO raw print without newline
-                    self.stack.push(Value::Nil);
+                    // TODO raw print without newline
+                    try!(writeln!(self.output_port.lock().unwrap(), "{}", c).map_err(|e| runtime_error(e.to_string())));
+                    self.stack.push(Value::Nil.into());
                 }
                 OpCode::Pop => {
                     try!(self.pop());
                 }
+                OpCode::PopN(n) => {
+                    let len = self.stack.len();
+                    self.stack.truncate(len.saturating_sub(n));
+                }
                 OpCode::Zap(ptr) => {
                     if self.stack.len() <= ptr {
-                        return runtime_error("Zap out of bounds")
+                        return Err(index_error(String::from("Zap out of bounds")))
                     }
                     self.stack.remove(ptr);
                 }
+                OpCode::PushHandler(target) => {
+                    self.handlers.push(Handler{
+                        frame_depth: self.call_stack.len(),
+                        stack_len: self.stack.len(),
+                        target: target,
+                    });
+                }
+                OpCode::PopHandler => {
+                    self.handlers.pop();
+                }
+                OpCode::MakeList(n) => {
+                    let items = try!(self.pop_n(n));
+                    self.stack.push(Value::List(items).into());
+                }
+                OpCode::MakeVector(n) => {
+                    let items = try!(self.pop_n(n));
+                    self.stack.push(Value::Vector(items).into());
+                }
+                OpCode::MakeMap(n) => {
+                    let items = try!(self.pop_n(2 * n));
+                    let mut map = OrderedMap::new();
+                    for pair in items.chunks(2) {
+                        map.insert(pair[0].map_key(), (pair[0].clone(), pair[1].clone()));
+                    }
+                    self.stack.push(Value::Map(map).into());
+                }
             };
             if done {
                 if debug {
@@ -482,17 +2060,87 @@ impl VM {
     }
 }
 
-pub fn init_vm() -> VM {
-    let top_frame = CallFrame{
+fn main_frame(chunk_idx: usize) -> CallFrame {
+    CallFrame{
         fn_name: String::from("main"),
         ip: 0,
         stack_start: 0,
-        chunk_idx: 0,
-    };
+        chunk_idx: chunk_idx,
+    }
+}
+
+pub fn init_vm() -> VM {
     VM{
         stack: vec![],
-        globals: HashMap::new(),
-        call_stack: vec![top_frame],
+        globals: Arc::new(RwLock::new(HashMap::new())),
+        call_stack: vec![main_frame(0)],
         chunks: vec![],
+        debug: false,
+        input_port: Arc::new(Mutex::new(Box::new(BufReader::new(io::stdin())))),
+        output_port: Arc::new(Mutex::new(Box::new(io::stdout()))),
+        error_port: Arc::new(Mutex::new(Box::new(io::stderr()))),
+        handlers: vec![],
+        shadowed_natives: Arc::new(natives::NATIVES.iter().map(|_| AtomicBool::new(false)).collect()),
+        profile_mode: false,
+        opcode_counts: HashMap::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compiler::{interpret, CompilerConfig};
+
+    fn define(vm: &mut VM, source: &str, name: &str) -> Value {
+        interpret(vm, format!("{}\n", source), &CompilerConfig::new()).unwrap();
+        vm.globals()[name].clone()
+    }
+
+    #[test]
+    fn call_function_returns_the_result() {
+        let mut vm = init_vm();
+        let add = define(&mut vm, "(defn add (a b) (+ a b))", "add");
+        let result = vm.call_function(&add, &[Value::Int(1), Value::Int(2)]).unwrap();
+        assert!(result.equal(&Value::Int(3)).truthy());
+    }
+
+    #[test]
+    fn closures_are_compared_by_identity() {
+        let mut vm = init_vm();
+        let make_adder = define(&mut vm, "(defn make-adder (n) (fn (x) (+ x n)))", "make-adder");
+        let a = vm.call_function(&make_adder, &[Value::Int(1)]).unwrap();
+        let b = vm.call_function(&make_adder, &[Value::Int(1)]).unwrap();
+        assert!(a.equal(&a.clone()).truthy());
+        assert!(!a.equal(&b).truthy());
+        assert!(make_adder.equal(&define(&mut vm, "", "make-adder")).truthy());
+    }
+
+    #[test]
+    fn call_function_returns_errors() {
+        let mut vm = init_vm();
+        let add = define(&mut vm, "(defn add (a b) (+ a b))", "add");
+        match vm.call_function(&add, &[Value::Int(1), Value::String(String::from("a"))]) {
+            Err(RuntimeError::Error(ErrorKind::Type, message)) => assert_eq!(message, "Cannot add a to 1"),
+            _ => panic!("expected a type error"),
+        }
+        let error = vm.call_function(&add, &[Value::Nil, Value::Int(1)]).unwrap_err();
+        assert_eq!(error.to_string(), "Cannot add 1 to nil");
+        match vm.call_function(&add, &[Value::Int(1)]) {
+            Err(RuntimeError::Error(ErrorKind::Arity, _)) => (),
+            _ => panic!("expected an arity error"),
+        }
+    }
+
+    #[test]
+    fn the_vm_can_be_used_again_after_an_error() {
+        let mut vm = init_vm();
+        let outer = define(&mut vm, "(defn inner (x) (+ x 1)) (defn outer (x) (* 2 (inner x)))", "outer");
+        let stack_len = vm.stack.len();
+        let frames = vm.call_stack.len();
+        assert!(vm.call_function(&outer, &[Value::Nil]).is_err());
+        assert_eq!(vm.stack.len(), stack_len);
+        assert_eq!(vm.call_stack.len(), frames);
+        let result = vm.call_function(&outer, &[Value::Int(4)]).unwrap();
+        assert!(result.equal(&Value::Int(10)).truthy());
     }
 }
@@ -0,0 +1,243 @@
+use std::cmp::Ordering;
+
+// Arbitrary precision integer. The magnitude is stored as 32-bit limbs in
+// little-endian order, without trailing zero limbs. Zero has no limbs and is
+// never negative.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BigInt {
+    negative: bool,
+    limbs: Vec<u32>,
+}
+
+fn compare_magnitude(a: &[u32], b: &[u32]) -> Ordering {
+    if a.len() != b.len() {
+        return a.len().cmp(&b.len())
+    }
+    for i in (0..a.len()).rev() {
+        if a[i] != b[i] {
+            return a[i].cmp(&b[i])
+        }
+    }
+    Ordering::Equal
+}
+
+fn add_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = vec![];
+    let mut carry: u64 = 0;
+    for i in 0..std::cmp::max(a.len(), b.len()) {
+        let sum = carry
+            + *a.get(i).unwrap_or(&0) as u64
+            + *b.get(i).unwrap_or(&0) as u64;
+        result.push(sum as u32);
+        carry = sum >> 32;
+    }
+    if 0 < carry {
+        result.push(carry as u32);
+    }
+    result
+}
+
+// Expects a to be at least as large as b
+fn subtract_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = vec![];
+    let mut borrow: i64 = 0;
+    for i in 0..a.len() {
+        let mut diff = a[i] as i64 - borrow - *b.get(i).unwrap_or(&0) as i64;
+        if diff < 0 {
+            diff += 1 << 32;
+            borrow = 1;
+        } else {
+            borrow = 0;
+        }
+        result.push(diff as u32);
+    }
+    result
+}
+
+// Divides the magnitude in place and returns the remainder
+fn divide_magnitude_small(limbs: &mut Vec<u32>, divisor: u32) -> u32 {
+    let mut remainder: u64 = 0;
+    for i in (0..limbs.len()).rev() {
+        let current = (remainder << 32) | limbs[i] as u64;
+        limbs[i] = (current / divisor as u64) as u32;
+        remainder = current % divisor as u64;
+    }
+    while limbs.last() == Some(&0) {
+        limbs.pop();
+    }
+    remainder as u32
+}
+
+impl From<i64> for BigInt {
+    fn from(n: i64) -> BigInt {
+        BigInt::from(n as i128)
+    }
+}
+
+impl From<i128> for BigInt {
+    fn from(n: i128) -> BigInt {
+        let mut magnitude = n.unsigned_abs();
+        let mut limbs = vec![];
+        while 0 < magnitude {
+            limbs.push(magnitude as u32);
+            magnitude >>= 32;
+        }
+        BigInt{
+            negative: n < 0,
+            limbs: limbs,
+        }
+    }
+}
+
+impl BigInt {
+    fn normalise(mut self) -> BigInt {
+        while self.limbs.last() == Some(&0) {
+            self.limbs.pop();
+        }
+        if self.limbs.is_empty() {
+            self.negative = false;
+        }
+        self
+    }
+
+    pub fn parse(s: &str) -> Option<BigInt> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        if digits.is_empty() {
+            return None
+        }
+        let ten = BigInt::from(10i64);
+        let mut result = BigInt::from(0i64);
+        for c in digits.chars() {
+            let digit = match c.to_digit(10) {
+                Some(d) => d,
+                None => return None,
+            };
+            result = result.multiply(&ten).add(&BigInt::from(digit as i64));
+        }
+        if negative {
+            result = result.negate();
+        }
+        Some(result)
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        if 2 < self.limbs.len() {
+            return None
+        }
+        let mut magnitude: u64 = 0;
+        for (i, limb) in self.limbs.iter().enumerate() {
+            magnitude |= (*limb as u64) << (32 * i);
+        }
+        if self.negative {
+            if magnitude <= i64::MAX as u64 + 1 {
+                Some((magnitude as i64).wrapping_neg())
+            } else {
+                None
+            }
+        } else if magnitude <= i64::MAX as u64 {
+            Some(magnitude as i64)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let mut f = 0.0;
+        for limb in self.limbs.iter().rev() {
+            f = f * 4294967296.0 + *limb as f64;
+        }
+        if self.negative { -f } else { f }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.limbs.is_empty()
+    }
+
+    pub fn negate(&self) -> BigInt {
+        BigInt{
+            negative: !self.negative,
+            limbs: self.limbs.clone(),
+        }.normalise()
+    }
+
+    pub fn add(&self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt{
+                negative: self.negative,
+                limbs: add_magnitude(&self.limbs, &other.limbs),
+            }.normalise()
+        }
+        match compare_magnitude(&self.limbs, &other.limbs) {
+            Ordering::Less => BigInt{
+                negative: other.negative,
+                limbs: subtract_magnitude(&other.limbs, &self.limbs),
+            },
+            _ => BigInt{
+                negative: self.negative,
+                limbs: subtract_magnitude(&self.limbs, &other.limbs),
+            },
+        }.normalise()
+    }
+
+    pub fn subtract(&self, other: &BigInt) -> BigInt {
+        self.add(&other.negate())
+    }
+
+    pub fn multiply(&self, other: &BigInt) -> BigInt {
+        // Schoolbook multiplication
+        let mut limbs = vec![0u32; self.limbs.len() + other.limbs.len()];
+        for (i, a) in self.limbs.iter().enumerate() {
+            let mut carry: u64 = 0;
+            for (j, b) in other.limbs.iter().enumerate() {
+                let t = limbs[i + j] as u64 + *a as u64 * *b as u64 + carry;
+                limbs[i + j] = t as u32;
+                carry = t >> 32;
+            }
+            let mut k = i + other.limbs.len();
+            while 0 < carry {
+                let t = limbs[k] as u64 + carry;
+                limbs[k] = t as u32;
+                carry = t >> 32;
+                k += 1;
+            }
+        }
+        BigInt{
+            negative: self.negative != other.negative,
+            limbs: limbs,
+        }.normalise()
+    }
+
+    pub fn compare(&self, other: &BigInt) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => compare_magnitude(&self.limbs, &other.limbs),
+            (true, true) => compare_magnitude(&other.limbs, &self.limbs),
+        }
+    }
+}
+
+impl std::fmt::Display for BigInt {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        if self.is_zero() {
+            return write!(f, "0")
+        }
+        // Peel off base 10^9 chunks, least significant first
+        let mut limbs = self.limbs.clone();
+        let mut chunks = vec![];
+        while !limbs.is_empty() {
+            chunks.push(divide_magnitude_small(&mut limbs, 1_000_000_000));
+        }
+        if self.negative {
+            try!(write!(f, "-"));
+        }
+        try!(write!(f, "{}", chunks.pop().unwrap()));
+        for chunk in chunks.iter().rev() {
+            try!(write!(f, "{:09}", chunk));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+// Each level of the trie uses five bits of the hash, for 32-way branching
+const BITS: u32 = 5;
+const MASK: u64 = 31;
+
+#[derive(Clone)]
+enum Node<V> {
+    // Only the occupied slots have children, in slot order. A set bit in the
+    // bitmap marks a slot as occupied.
+    Branch(u32, Vec<Arc<Node<V>>>),
+    Leaf(u64, String, V),
+    // Keys whose hashes are equal in all 64 bits
+    Collision(u64, Vec<(String, V)>),
+}
+
+// A persistent hash array mapped trie from strings. Cloning it is cheap, and
+// changing a clone copies only the nodes on the path to the changed key,
+// sharing the rest with the original.
+#[derive(Clone)]
+pub struct Hamt<V> {
+    root: Arc<Node<V>>,
+    len: usize,
+}
+
+fn hash_key(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// The bit of a slot in a branch's bitmap, and the index of its child
+fn slot(bitmap: u32, hash: u64, shift: u32) -> (u32, usize) {
+    let bit = 1 << ((hash >> shift) & MASK);
+    (bit, (bitmap & (bit - 1)).count_ones() as usize)
+}
+
+impl<V: Clone> Hamt<V> {
+    pub fn new() -> Hamt<V> {
+        Hamt{
+            root: Arc::new(Node::Branch(0, vec![])),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        let hash = hash_key(key);
+        let mut node = &*self.root;
+        let mut shift = 0;
+        loop {
+            match node {
+                Node::Branch(bitmap, children) => {
+                    let (bit, index) = slot(*bitmap, hash, shift);
+                    if bitmap & bit == 0 {
+                        return None
+                    }
+                    node = &children[index];
+                    shift += BITS;
+                }
+                Node::Leaf(_, k, v) => return if k == key { Some(v) } else { None },
+                Node::Collision(_, entries) =>
+                    return entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: String, value: V) {
+        let hash = hash_key(&key);
+        if insert_into(&mut self.root, 0, hash, key, value) {
+            self.len += 1;
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        if self.get(key).is_some() {
+            remove_from(&mut self.root, 0, hash_key(key), key);
+            self.len -= 1;
+        }
+    }
+
+    // In no particular order
+    pub fn entries(&self) -> Vec<(&String, &V)> {
+        let mut entries = Vec::with_capacity(self.len);
+        collect(&self.root, &mut entries);
+        entries
+    }
+}
+
+// Returns whether the key is new. Nodes shared with other tries are copied
+// before they are changed.
+fn insert_into<V: Clone>(node: &mut Arc<Node<V>>, shift: u32, hash: u64, key: String, value: V) -> bool {
+    // A leaf or collision node for other keys is pushed down into a branch,
+    // unless the hashes are equal and the keys have to share a collision node
+    let split = match &**node {
+        Node::Leaf(h, k, v) if *k != key => if *h == hash {
+            Some(Node::Collision(hash, vec![(k.clone(), v.clone())]))
+        } else {
+            Some(Node::Branch(slot(0, *h, shift).0, vec![node.clone()]))
+        },
+        Node::Collision(h, _) if *h != hash =>
+            Some(Node::Branch(slot(0, *h, shift).0, vec![node.clone()])),
+        _ => None,
+    };
+    if let Some(split) = split {
+        *node = Arc::new(split);
+    }
+    match Arc::make_mut(node) {
+        Node::Branch(bitmap, children) => {
+            let (bit, index) = slot(*bitmap, hash, shift);
+            if *bitmap & bit == 0 {
+                *bitmap |= bit;
+                children.insert(index, Arc::new(Node::Leaf(hash, key, value)));
+                true
+            } else {
+                insert_into(&mut children[index], shift + BITS, hash, key, value)
+            }
+        }
+        Node::Leaf(_, _, v) => {
+            *v = value;
+            false
+        }
+        Node::Collision(_, entries) => match entries.iter().position(|(k, _)| *k == key) {
+            Some(i) => {
+                entries[i].1 = value;
+                false
+            }
+            None => {
+                entries.push((key, value));
+                true
+            }
+        },
+    }
+}
+
+// The key has to be in the node. Returns whether the node is left empty.
+fn remove_from<V: Clone>(node: &mut Arc<Node<V>>, shift: u32, hash: u64, key: &str) -> bool {
+    match Arc::make_mut(node) {
+        Node::Branch(bitmap, children) => {
+            let (bit, index) = slot(*bitmap, hash, shift);
+            if remove_from(&mut children[index], shift + BITS, hash, key) {
+                *bitmap &= !bit;
+                children.remove(index);
+            }
+            children.is_empty()
+        }
+        Node::Leaf(..) => true,
+        Node::Collision(_, entries) => {
+            entries.retain(|(k, _)| k != key);
+            entries.is_empty()
+        }
+    }
+}
+
+fn collect<'a, V>(node: &'a Node<V>, entries: &mut Vec<(&'a String, &'a V)>) {
+    match node {
+        Node::Branch(_, children) => for child in children {
+            collect(child, entries);
+        },
+        Node::Leaf(_, k, v) => entries.push((k, v)),
+        Node::Collision(_, pairs) => entries.extend(pairs.iter().map(|(k, v)| (k, v))),
+    }
+}
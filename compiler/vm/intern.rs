@@ -0,0 +1,51 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Mutex, OnceLock};
+
+// A symbol name stored once for the lifetime of the process. Interning the
+// same name twice yields the same pointer, so equality is a pointer compare.
+#[derive(Clone, Copy)]
+pub struct InternedStr(&'static str);
+
+static INTERNER: OnceLock<Mutex<HashSet<&'static str>>> = OnceLock::new();
+
+pub fn intern(name: &str) -> InternedStr {
+    let mut names = INTERNER.get_or_init(|| Mutex::new(HashSet::new())).lock().unwrap();
+    if let Some(existing) = names.get(name) {
+        return InternedStr(existing)
+    }
+    // Interned names are never freed, there are only as many as the
+    // distinct symbols a program mentions
+    let leaked: &'static str = Box::leak(name.to_string().into_boxed_str());
+    names.insert(leaked);
+    InternedStr(leaked)
+}
+
+impl PartialEq for InternedStr {
+    fn eq(&self, other: &InternedStr) -> bool {
+        std::ptr::eq(self.0, other.0)
+    }
+}
+
+impl Eq for InternedStr {}
+
+impl Deref for InternedStr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.0
+    }
+}
+
+impl fmt::Display for InternedStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Debug for InternedStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
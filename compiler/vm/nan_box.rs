@@ -0,0 +1,114 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use super::Value;
+
+// A Value packed into 64 bits. Floats are stored as their own bits, with every
+// NaN made the positive quiet NaN. Everything else lives in the payload of a
+// negative quiet NaN, with a three bit tag above a 48 bit payload:
+//
+//   1 11111111111 1 TTT PPPP...PPPP
+//
+// Nil, booleans and ints that fit in an i32 are stored inline, anything else
+// is a Box<Value> whose pointer is the payload.
+pub struct NanBoxed(u64);
+
+const BOXED: u64 = 0xfff8_0000_0000_0000;
+const TAG_SHIFT: u32 = 48;
+const TAG_MASK: u64 = 0x7 << TAG_SHIFT;
+const PAYLOAD_MASK: u64 = (1 << TAG_SHIFT) - 1;
+const CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+
+const TAG_NIL: u64 = 0;
+const TAG_BOOL: u64 = 1;
+const TAG_INT: u64 = 2;
+const TAG_HEAP: u64 = 3;
+
+impl NanBoxed {
+    fn tagged(tag: u64, payload: u64) -> NanBoxed {
+        NanBoxed(BOXED | tag << TAG_SHIFT | payload & PAYLOAD_MASK)
+    }
+
+    fn is_boxed(&self) -> bool {
+        self.0 & BOXED == BOXED
+    }
+
+    fn tag(&self) -> u64 {
+        (self.0 & TAG_MASK) >> TAG_SHIFT
+    }
+
+    fn payload(&self) -> u64 {
+        self.0 & PAYLOAD_MASK
+    }
+
+    fn heap_ptr(&self) -> Option<*mut Value> {
+        if self.is_boxed() && self.tag() == TAG_HEAP {
+            Some(self.payload() as *mut Value)
+        } else {
+            None
+        }
+    }
+
+    // The unpacked value, only copied when it was stored inline
+    pub fn as_value(&self) -> Cow<'_, Value> {
+        if !self.is_boxed() {
+            return Cow::Owned(Value::Float(f64::from_bits(self.0)))
+        }
+        match self.tag() {
+            TAG_NIL => Cow::Owned(Value::Nil),
+            TAG_BOOL => Cow::Owned(Value::Bool(self.payload() != 0)),
+            TAG_INT => Cow::Owned(Value::Int(self.payload() as u32 as i32 as i64)),
+            _ => Cow::Borrowed(unsafe { &*(self.payload() as *const Value) }),
+        }
+    }
+
+    pub fn into_value(self) -> Value {
+        match self.heap_ptr() {
+            Some(ptr) => {
+                std::mem::forget(self);
+                *unsafe { Box::from_raw(ptr) }
+            }
+            None => self.as_value().into_owned(),
+        }
+    }
+}
+
+impl From<Value> for NanBoxed {
+    fn from(v: Value) -> NanBoxed {
+        match v {
+            Value::Nil => NanBoxed::tagged(TAG_NIL, 0),
+            Value::Bool(b) => NanBoxed::tagged(TAG_BOOL, b as u64),
+            Value::Int(n) if n as i32 as i64 == n => NanBoxed::tagged(TAG_INT, n as i32 as u32 as u64),
+            Value::Float(f) if f.is_nan() => NanBoxed(CANONICAL_NAN),
+            Value::Float(f) => NanBoxed(f.to_bits()),
+            v => {
+                let ptr = Box::into_raw(Box::new(v)) as u64;
+                debug_assert!(ptr & !PAYLOAD_MASK == 0, "pointer does not fit in 48 bits");
+                NanBoxed::tagged(TAG_HEAP, ptr)
+            }
+        }
+    }
+}
+
+impl Clone for NanBoxed {
+    fn clone(&self) -> NanBoxed {
+        match self.heap_ptr() {
+            Some(ptr) => NanBoxed::from(unsafe { (*ptr).clone() }),
+            None => NanBoxed(self.0),
+        }
+    }
+}
+
+impl Drop for NanBoxed {
+    fn drop(&mut self) {
+        if let Some(ptr) = self.heap_ptr() {
+            drop(unsafe { Box::from_raw(ptr) });
+        }
+    }
+}
+
+impl fmt::Debug for NanBoxed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_value())
+    }
+}
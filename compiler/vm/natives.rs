@@ -0,0 +1,2036 @@
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use compiler::{compile_eval, compile_form, read_all, CompilerConfig};
+use super::{split_qualified, Chunk, InputPort, OutputPort, OpCode, Promise, PromiseState, RuntimeError, Stream, StreamState, Value, VM};
+use super::{arity_error, index_error, runtime_error, type_error};
+use super::intern::intern;
+use super::ordered_map::OrderedMap;
+use super::regex::Regex;
+
+// Built-in functions implemented in Rust, called with their evaluated arguments
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, RuntimeError>;
+
+pub static NATIVES: &[(&str, NativeFn)] = &[
+    // Calls to the operators by name are compiled to instructions, these are
+    // for passing them around as values
+    ("+", add),
+    ("-", subtract),
+    ("*", multiply),
+    ("/", divide),
+    ("=", equal),
+    (">", greater_than),
+    (">=", greater_or_equal),
+    ("<", less_than),
+    ("<=", less_or_equal),
+    ("not", not),
+    ("print", write_line),
+    ("println", write_line),
+    ("list", list),
+    ("concat", concat),
+    ("count", count),
+    ("nth", nth),
+    ("seq", seq),
+    ("first", first),
+    ("rest", rest),
+    ("car", first),
+    ("cdr", rest),
+    ("cons", cons),
+    ("map", map),
+    ("mapcat", mapcat),
+    ("flatten", flatten),
+    ("flatten-one", flatten_one),
+    ("juxt", juxt),
+    ("trampoline", trampoline),
+    ("walk", walk),
+    ("prewalk", prewalk),
+    ("postwalk", postwalk),
+    ("list?", is_list),
+    ("pair?", is_pair),
+    ("null?", is_null),
+    ("hash-map", hash_map),
+    ("sorted-map", sorted_map),
+    ("sort", sort),
+    ("hash", hash),
+    ("into-map", into_map),
+    ("keys", keys),
+    ("values", values),
+    ("get", get),
+    ("?", safe_get),
+    ("contains?", contains),
+    ("assoc", assoc),
+    ("dissoc", dissoc),
+    ("transient", transient),
+    ("assoc!", assoc_in_place),
+    ("dissoc!", dissoc_in_place),
+    ("persistent!", persistent),
+    ("select-keys", select_keys),
+    ("rename-keys", rename_keys),
+    ("with-meta", with_meta),
+    ("make-error", make_error),
+    ("make-exception", make_error),
+    ("raise", raise),
+    ("condition-failed", condition_failed),
+    ("error-kind", error_kind),
+    ("error-message", error_message),
+    ("error-data", error_data),
+    ("error-cause", error_cause),
+    ("error?", is_error),
+    ("exception?", is_error),
+    ("exception-kind", error_kind),
+    ("exception-message", error_message),
+    ("exception-cause", error_cause),
+    ("meta", meta),
+    ("doc", doc),
+    ("vector", vector),
+    ("vector?", is_vector),
+    ("vector-set", vector_set),
+    ("list-set", list_set),
+    ("list-insert", list_insert),
+    ("list-remove", list_remove),
+    ("vector-conj", vector_conj),
+    ("conj", conj),
+    ("subvec", subvec),
+    ("complex", complex),
+    ("real-part", real_part),
+    ("imag-part", imag_part),
+    ("magnitude", magnitude),
+    ("angle", angle),
+    ("conjugate", conjugate),
+    ("sqrt", sqrt),
+    ("string->list", string_to_list),
+    ("list->string", list_to_string),
+    ("string-pad-left", string_pad_left),
+    ("string-pad-right", string_pad_right),
+    ("string-center", string_center),
+    ("string-contains?", string_contains),
+    ("string-index-of", string_index_of),
+    ("string-repeat", string_repeat),
+    ("string-reverse", string_reverse),
+    ("char-alphabetic?", is_char_alphabetic),
+    ("char-numeric?", is_char_numeric),
+    ("char-whitespace?", is_char_whitespace),
+    ("char-upper-case?", is_char_upper_case),
+    ("char-lower-case?", is_char_lower_case),
+    ("read-all", read_all_native),
+    ("eval", eval),
+    ("str", str_native),
+    ("type-of", type_of),
+    ("global-bindings", global_bindings),
+    ("fn-file", fn_file),
+    ("fn-line", fn_line),
+    ("namespace", namespace),
+    ("name", name),
+    ("symbol", symbol),
+    ("protocol-dispatch", protocol_dispatch),
+    ("make-multimethod", make_multimethod),
+    ("make-multi-arity", make_multi_arity),
+    ("add-method", add_method),
+    ("multimethod-dispatch", multimethod_dispatch),
+    ("make-string-builder", make_string_builder),
+    ("sb-append!", sb_append),
+    ("sb-build", sb_build),
+    ("re-compile", re_compile),
+    ("re-find", re_find),
+    ("re-match", re_match),
+    ("re-split", re_split),
+    ("re-replace", re_replace),
+    ("re-replace-all", re_replace_all),
+    ("re-replace-fn", re_replace_fn),
+    ("open-input-file", open_input_file),
+    ("open-output-file", open_output_file),
+    ("close-port", close_port),
+    ("current-input-port", current_input_port),
+    ("current-output-port", current_output_port),
+    ("current-error-port", current_error_port),
+    ("read-char", read_char),
+    ("peek-char", peek_char),
+    ("read-line", read_line),
+    ("write-char", write_char),
+    ("write-string", write_string),
+    ("write-line", write_line),
+    ("run-bench", run_bench),
+    ("promise", promise),
+    ("deref-promise", deref_promise),
+    ("resolved?", is_resolved),
+    ("all-promises", all_promises),
+    ("range", range),
+    ("stream-from-list", stream_from_list),
+    ("stream-next!", stream_next_native),
+    ("stream-take", stream_take),
+    ("stream-map", stream_map),
+    ("stream-filter", stream_filter),
+    ("lazy-concat", lazy_concat),
+    ("stream->list", stream_to_list),
+];
+
+pub fn find_native(name: &str) -> Option<usize> {
+    NATIVES.iter().position(|(n, _)| *n == name)
+}
+
+fn check_arity(name: &str, args: &[Value], expected: usize) -> Result<(), RuntimeError> {
+    if args.len() == expected {
+        Ok(())
+    } else {
+        Err(arity_error(format!("Arity mismatch: {} expects {}, got {}", name, expected, args.len())))
+    }
+}
+
+fn check_arity_range(name: &str, args: &[Value], min: usize, max: usize) -> Result<(), RuntimeError> {
+    if min <= args.len() && args.len() <= max {
+        Ok(())
+    } else {
+        Err(arity_error(format!("Arity mismatch: {} expects {} to {}, got {}", name, min, max, args.len())))
+    }
+}
+
+fn string_arg<'a>(name: &str, value: &'a Value) -> Result<&'a String, RuntimeError> {
+    match value {
+        Value::String(s) => Ok(s),
+        _ => Err(type_error(format!("{} expects a string, got {:?}", name, value))),
+    }
+}
+
+// Patterns can be passed as compiled regexes or as strings
+fn regex_arg(name: &str, value: &Value) -> Result<Regex, RuntimeError> {
+    match value {
+        Value::Regex(r) => Ok(r.clone()),
+        Value::String(s) => Regex::new(s).map_err(runtime_error),
+        _ => Err(type_error(format!("{} expects a regex, got {:?}", name, value))),
+    }
+}
+
+fn add(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("+", args, 2));
+    args[0].add(&args[1])
+}
+
+fn subtract(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("-", args, 2));
+    args[0].subtract(&args[1])
+}
+
+fn multiply(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("*", args, 2));
+    args[0].multiply(&args[1])
+}
+
+fn divide(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("/", args, 2));
+    args[0].divide(&args[1])
+}
+
+fn equal(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("=", args, 2));
+    Ok(args[0].equal(&args[1]))
+}
+
+fn greater_than(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity(">", args, 2));
+    args[0].greater_than(&args[1])
+}
+
+fn greater_or_equal(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity(">=", args, 2));
+    args[0].less_than(&args[1]).map(|v| v.not())
+}
+
+fn less_than(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("<", args, 2));
+    args[0].less_than(&args[1])
+}
+
+fn less_or_equal(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("<=", args, 2));
+    args[0].greater_than(&args[1]).map(|v| v.not())
+}
+
+fn not(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("not", args, 1));
+    Ok(args[0].not())
+}
+
+fn list(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::List(args.to_vec()))
+}
+
+fn concat(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let mut items = vec![];
+    for arg in args {
+        items.extend(try!(seq_items("concat", arg)));
+    }
+    Ok(list_or_nil(items))
+}
+
+fn count(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("count", args, 1));
+    match &args[0] {
+        Value::Nil => Ok(Value::Int(0)),
+        Value::List(l) | Value::Vector(l) => Ok(Value::Int(l.len() as i64)),
+        Value::String(s) => Ok(Value::Int(s.chars().count() as i64)),
+        v => Err(type_error(format!("Cannot count {:?}", v))),
+    }
+}
+
+fn complex_arg(name: &str, value: &Value) -> Result<(f64, f64), RuntimeError> {
+    match value.as_complex() {
+        Some(c) => Ok(c),
+        None => Err(type_error(format!("{} expects a number, got {:?}", name, value))),
+    }
+}
+
+fn complex(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("complex", args, 2));
+    match (args[0].as_f64(), args[1].as_f64()) {
+        (Some(re), Some(im)) => Ok(Value::Complex(re, im)),
+        _ => Err(type_error(format!("complex expects two real numbers, got {:?} and {:?}", args[0], args[1]))),
+    }
+}
+
+fn real_part(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("real-part", args, 1));
+    let (re, _) = try!(complex_arg("real-part", &args[0]));
+    Ok(Value::Float(re))
+}
+
+fn imag_part(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("imag-part", args, 1));
+    let (_, im) = try!(complex_arg("imag-part", &args[0]));
+    Ok(Value::Float(im))
+}
+
+fn magnitude(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("magnitude", args, 1));
+    let (re, im) = try!(complex_arg("magnitude", &args[0]));
+    Ok(Value::Float(re.hypot(im)))
+}
+
+fn angle(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("angle", args, 1));
+    let (re, im) = try!(complex_arg("angle", &args[0]));
+    Ok(Value::Float(im.atan2(re)))
+}
+
+fn conjugate(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("conjugate", args, 1));
+    let (re, im) = try!(complex_arg("conjugate", &args[0]));
+    Ok(Value::Complex(re, -im))
+}
+
+// Negative numbers have complex roots
+fn sqrt(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("sqrt", args, 1));
+    match &args[0] {
+        Value::Complex(re, im) => {
+            let r = re.hypot(*im).sqrt();
+            let theta = im.atan2(*re) / 2.0;
+            Ok(Value::Complex(r * theta.cos(), r * theta.sin()))
+        }
+        v => match v.as_f64() {
+            Some(x) if x < 0.0 => Ok(Value::Complex(0.0, (-x).sqrt())),
+            Some(x) => Ok(Value::Float(x.sqrt())),
+            None => Err(type_error(format!("sqrt expects a number, got {:?}", v))),
+        },
+    }
+}
+
+fn index_arg(name: &str, value: &Value) -> Result<usize, RuntimeError> {
+    match value {
+        Value::Int(n) if 0 <= *n => Ok(*n as usize),
+        _ => Err(type_error(format!("{} expects an index, got {:?}", name, value))),
+    }
+}
+
+fn vector_arg<'a>(name: &str, value: &'a Value) -> Result<&'a Vec<Value>, RuntimeError> {
+    match value {
+        Value::Vector(v) => Ok(v),
+        _ => Err(type_error(format!("{} expects a vector, got {:?}", name, value))),
+    }
+}
+
+// The elements of any sequence, nil counts as an empty one
+fn seq_items(name: &str, value: &Value) -> Result<Vec<Value>, RuntimeError> {
+    match value {
+        Value::Nil => Ok(vec![]),
+        Value::List(l) | Value::Vector(l) => Ok(l.clone()),
+        Value::String(s) => Ok(s.chars().map(Value::Char).collect()),
+        v => Err(type_error(format!("{} expects a sequence, got {:?}", name, v))),
+    }
+}
+
+// Empty lists are nil
+fn list_or_nil(items: Vec<Value>) -> Value {
+    if items.is_empty() {
+        Value::Nil
+    } else {
+        Value::List(items)
+    }
+}
+
+fn seq(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("seq", args, 1));
+    Ok(list_or_nil(try!(seq_items("seq", &args[0]))))
+}
+
+fn first(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("first", args, 1));
+    let items = try!(seq_items("first", &args[0]));
+    Ok(items.into_iter().next().unwrap_or(Value::Nil))
+}
+
+fn rest(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("rest", args, 1));
+    let items = try!(seq_items("rest", &args[0]));
+    Ok(list_or_nil(items.into_iter().skip(1).collect()))
+}
+
+fn cons(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("cons", args, 2));
+    let mut items = try!(seq_items("cons", &args[1]));
+    items.insert(0, args[0].clone());
+    Ok(Value::List(items))
+}
+
+fn map(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("map", args, 2));
+    let items = try!(seq_items("map", &args[1]));
+    let mut results = vec![];
+    for item in items {
+        results.push(try!(vm.call_value(args[0].clone(), vec![item])));
+    }
+    Ok(list_or_nil(results))
+}
+
+// Results keep the kind of the outer sequence, so vectors flatten to vectors
+fn same_kind(like: &Value, items: Vec<Value>) -> Value {
+    match like {
+        Value::Vector(_) => Value::Vector(items),
+        _ => list_or_nil(items),
+    }
+}
+
+// Removes one level of nesting, leaving anything that isn't a list or vector
+fn flatten_one_items(items: Vec<Value>) -> Vec<Value> {
+    let mut flat = vec![];
+    for item in items {
+        match item {
+            Value::List(l) | Value::Vector(l) => flat.extend(l),
+            Value::Nil => (),
+            v => flat.push(v),
+        }
+    }
+    flat
+}
+
+fn flatten_items(items: Vec<Value>, flat: &mut Vec<Value>) {
+    for item in items {
+        match item {
+            Value::List(l) | Value::Vector(l) => flatten_items(l, flat),
+            Value::Nil => (),
+            v => flat.push(v),
+        }
+    }
+}
+
+fn flatten_one(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("flatten-one", args, 1));
+    let items = try!(seq_items("flatten-one", &args[0]));
+    Ok(same_kind(&args[0], flatten_one_items(items)))
+}
+
+fn flatten(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("flatten", args, 1));
+    let mut flat = vec![];
+    flatten_items(try!(seq_items("flatten", &args[0])), &mut flat);
+    Ok(same_kind(&args[0], flat))
+}
+
+// `(mapcat f xs)` maps and then flattens the results by one level
+fn mapcat(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("mapcat", args, 2));
+    let items = try!(seq_items("mapcat", &args[1]));
+    let mut results = vec![];
+    for item in items {
+        results.push(try!(vm.call_value(args[0].clone(), vec![item])));
+    }
+    Ok(same_kind(&args[1], flatten_one_items(results)))
+}
+
+// `(trampoline f args...)` calls f, then keeps calling what it returns for as
+// long as that is a function, so functions can tail call each other by
+// returning `(fn () (g x))` without growing the stack
+fn trampoline(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args.is_empty() {
+        return Err(arity_error(String::from("Arity mismatch: trampoline expects at least 1, got 0")))
+    }
+    let mut result = try!(vm.call_function(&args[0], &args[1..]));
+    loop {
+        let is_fn = match &result {
+            Value::Function{..} | Value::Native(_) | Value::MultiArity{..} => true,
+            Value::WithMeta(v, _) => match **v {
+                Value::Function{..} | Value::Native(_) | Value::MultiArity{..} => true,
+                _ => false,
+            },
+            _ => false,
+        };
+        if !is_fn {
+            return Ok(result)
+        }
+        result = try!(vm.call_value(result, vec![]));
+    }
+}
+
+// `(juxt f g)` is a function of one argument returning `(list (f x) (g x))`,
+// a closure over the functions
+fn juxt(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args.is_empty() {
+        return Err(arity_error(String::from("Arity mismatch: juxt expects at least 1, got 0")))
+    }
+    let mut chunk = Chunk{
+        code: vec![],
+        constants: vec![],
+        lines: vec![],
+        chunks: vec![],
+        shared_constants: None,
+        label_map: vec![],
+        file: None,
+        repl_last_value: false,
+    };
+    for idx in 0..args.len() {
+        chunk.write_code(OpCode::GetUpvalue(idx), 0);
+        chunk.write_code(OpCode::GetLocal(0), 0);
+        chunk.write_code(OpCode::Call(1), 0);
+    }
+    chunk.write_code(OpCode::CallNative(find_native("list").unwrap(), args.len()), 0);
+    chunk.write_code(OpCode::Return, 0);
+    let chunk_idx = vm.load_chunk(chunk);
+    Ok(Value::Function{
+        name: String::from("juxt"),
+        params: vec![String::from("x")],
+        keywords: vec![],
+        rest: None,
+        chunk_idx: chunk_idx,
+        file: None,
+        line: 0,
+        upvalues: Arc::new(args.to_vec()),
+    })
+}
+
+// Rebuilds a list, vector or map from its children passed through inner. Map
+// entries are passed as `[key value]` and have to come back as pairs.
+fn walk_children(vm: &mut VM,
+                 form: &Value,
+                 inner: &mut dyn FnMut(&mut VM, Value) -> Result<Value, RuntimeError>)
+                 -> Result<Value, RuntimeError> {
+    match form {
+        Value::List(items) | Value::Vector(items) => {
+            let mut walked = vec![];
+            for item in items {
+                walked.push(try!(inner(vm, item.clone())));
+            }
+            Ok(match form {
+                Value::List(_) => Value::List(walked),
+                _ => Value::Vector(walked),
+            })
+        }
+        Value::Map(m) => {
+            let mut walked = OrderedMap::new();
+            for (k, v) in m.values() {
+                match try!(inner(vm, Value::Vector(vec![k.clone(), v.clone()]))) {
+                    Value::List(ref pair) | Value::Vector(ref pair) if pair.len() == 2 =>
+                        walked.insert(pair[0].map_key(), (pair[0].clone(), pair[1].clone())),
+                    v => return Err(type_error(format!("walk expects map entries to stay pairs, got {:?}", v))),
+                }
+            }
+            Ok(Value::Map(walked))
+        }
+        Value::WithMeta(v, meta) => Ok(Value::WithMeta(Box::new(try!(walk_children(vm, v, inner))), meta.clone())),
+        v => Ok(v.clone()),
+    }
+}
+
+// `(walk inner outer form)` calls inner on the children of form and outer on
+// the rebuilt form
+fn walk(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("walk", args, 3));
+    let inner = args[0].clone();
+    let form = try!(walk_children(vm, &args[2], &mut |vm, child| vm.call_value(inner.clone(), vec![child])));
+    vm.call_value(args[1].clone(), vec![form])
+}
+
+fn prewalk_with(vm: &mut VM, f: &Value, form: Value) -> Result<Value, RuntimeError> {
+    let form = try!(vm.call_value(f.clone(), vec![form]));
+    walk_children(vm, &form, &mut |vm, child| prewalk_with(vm, f, child))
+}
+
+fn postwalk_with(vm: &mut VM, f: &Value, form: Value) -> Result<Value, RuntimeError> {
+    let form = try!(walk_children(vm, &form, &mut |vm, child| postwalk_with(vm, f, child)));
+    vm.call_value(f.clone(), vec![form])
+}
+
+// Calls f on every node of a nested structure before walking its children
+fn prewalk(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("prewalk", args, 2));
+    prewalk_with(vm, &args[0], args[1].clone())
+}
+
+// Calls f on every node of a nested structure after walking its children
+fn postwalk(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("postwalk", args, 2));
+    postwalk_with(vm, &args[0], args[1].clone())
+}
+
+fn nth(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("nth", args, 2));
+    let idx = try!(index_arg("nth", &args[1]));
+    let item = match &args[0] {
+        Value::Vector(v) => v.get(idx),
+        Value::List(l) => l.iter().nth(idx),
+        Value::Nil => None,
+        v => return Err(type_error(format!("nth expects a list or vector, got {:?}", v))),
+    };
+    match item {
+        Some(v) => Ok(v.clone()),
+        None => Err(index_error(format!("Index {} out of bounds", idx))),
+    }
+}
+
+fn is_list(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("list?", args, 1));
+    match &args[0] {
+        Value::List(_) | Value::Nil => Ok(Value::Bool(true)),
+        _ => Ok(Value::Bool(false)),
+    }
+}
+
+// Non-empty lists, the empty list is nil
+fn is_pair(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("pair?", args, 1));
+    match &args[0] {
+        Value::List(l) => Ok(Value::Bool(!l.is_empty())),
+        _ => Ok(Value::Bool(false)),
+    }
+}
+
+fn is_null(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("null?", args, 1));
+    match &args[0] {
+        Value::Nil => Ok(Value::Bool(true)),
+        Value::List(l) => Ok(Value::Bool(l.is_empty())),
+        _ => Ok(Value::Bool(false)),
+    }
+}
+
+fn vector(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Vector(args.to_vec()))
+}
+
+fn is_vector(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("vector?", args, 1));
+    match &args[0] {
+        Value::Vector(_) => Ok(Value::Bool(true)),
+        _ => Ok(Value::Bool(false)),
+    }
+}
+
+// Returns a copy with the element at the index replaced
+fn vector_set(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("vector-set", args, 3));
+    let mut items = try!(vector_arg("vector-set", &args[0])).clone();
+    let idx = try!(index_arg("vector-set", &args[1]));
+    if items.len() <= idx {
+        return Err(index_error(format!("Index {} out of bounds", idx)))
+    }
+    items[idx] = args[2].clone();
+    Ok(Value::Vector(items))
+}
+
+// The elements of a list or vector and an index into them, which can be one
+// past the end when inserting
+fn list_index_args(name: &str, args: &[Value], inserting: bool) -> Result<(Vec<Value>, usize), RuntimeError> {
+    let items = match &args[0] {
+        Value::Nil => vec![],
+        Value::List(l) | Value::Vector(l) => l.clone(),
+        v => return Err(type_error(format!("{} expects a list or vector, got {:?}", name, v))),
+    };
+    let idx = try!(index_arg(name, &args[1]));
+    if items.len() < idx || (idx == items.len() && !inserting) {
+        return Err(index_error(format!("Index {} out of bounds", idx)))
+    }
+    Ok((items, idx))
+}
+
+// Returns a copy with the element at the index replaced
+fn list_set(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("list-set", args, 3));
+    let (mut items, idx) = try!(list_index_args("list-set", args, false));
+    items[idx] = args[2].clone();
+    Ok(same_kind(&args[0], items))
+}
+
+// Returns a copy with the value inserted before the index
+fn list_insert(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("list-insert", args, 3));
+    let (mut items, idx) = try!(list_index_args("list-insert", args, true));
+    items.insert(idx, args[2].clone());
+    Ok(same_kind(&args[0], items))
+}
+
+// Returns a copy without the element at the index
+fn list_remove(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("list-remove", args, 2));
+    let (mut items, idx) = try!(list_index_args("list-remove", args, false));
+    items.remove(idx);
+    Ok(same_kind(&args[0], items))
+}
+
+// Returns a copy with the further arguments appended
+fn vector_conj(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args.is_empty() {
+        return Err(arity_error(String::from("Arity mismatch: vector-conj expects at least 1, got 0")))
+    }
+    let mut items = try!(vector_arg("vector-conj", &args[0])).clone();
+    items.extend(args[1..].iter().cloned());
+    Ok(Value::Vector(items))
+}
+
+// Adds the further arguments where the collection adds them cheapest: lists
+// at the front, vectors at the back. Maps take key-value pairs or other maps.
+fn conj(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args.is_empty() {
+        return Err(arity_error(String::from("Arity mismatch: conj expects at least 1, got 0")))
+    }
+    match &args[0] {
+        Value::Nil | Value::List(_) => {
+            let mut items = try!(seq_items("conj", &args[0]));
+            for v in &args[1..] {
+                items.insert(0, v.clone());
+            }
+            Ok(list_or_nil(items))
+        }
+        Value::Vector(_) => vector_conj(vm, args),
+        Value::Map(m) => {
+            let mut map = m.clone();
+            for v in &args[1..] {
+                match v {
+                    Value::Vector(pair) | Value::List(pair) if pair.len() == 2 => {
+                        map.insert(pair[0].map_key(), (pair[0].clone(), pair[1].clone()));
+                    }
+                    Value::Map(other) => {
+                        for (k, entry) in other.iter() {
+                            map.insert(k.clone(), entry.clone());
+                        }
+                    }
+                    v => return Err(type_error(format!("conj expects a key-value pair or a map, got {:?}", v))),
+                }
+            }
+            Ok(Value::Map(map))
+        }
+        // Keeps the metadata of the collection
+        Value::WithMeta(v, meta) => {
+            let mut inner = args.to_vec();
+            inner[0] = (**v).clone();
+            let result = try!(conj(vm, &inner));
+            Ok(Value::WithMeta(Box::new(result), meta.clone()))
+        }
+        v => Err(type_error(format!("conj expects a collection, got {:?}", v))),
+    }
+}
+
+// The elements from start up to end, or to the end of the vector
+fn subvec(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity_range("subvec", args, 2, 3));
+    let items = try!(vector_arg("subvec", &args[0]));
+    let start = try!(index_arg("subvec", &args[1]));
+    let end = match args.get(2) {
+        Some(v) => try!(index_arg("subvec", v)),
+        None => items.len(),
+    };
+    if end < start || items.len() < end {
+        return Err(index_error(format!("Range {} to {} out of bounds", start, end)))
+    }
+    Ok(Value::Vector(items[start..end].to_vec()))
+}
+
+fn hash_map(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args.len() % 2 != 0 {
+        return Err(type_error(String::from("hash-map expects an even number of arguments")))
+    }
+    let mut map = OrderedMap::new();
+    for pair in args.chunks(2) {
+        map.insert(pair[0].map_key(), (pair[0].clone(), pair[1].clone()));
+    }
+    Ok(Value::Map(map))
+}
+
+// Orders numbers by value and chars and strings alphabetically, and anything
+// else by type and then printed form
+struct SortKey(Value);
+
+impl Ord for SortKey {
+    fn cmp(&self, other: &SortKey) -> Ordering {
+        compare(&self.0, &other.0)
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> Ordering {
+    match (a.less_than(b), a.greater_than(b)) {
+        (Ok(Value::Bool(true)), _) => return Ordering::Less,
+        (_, Ok(Value::Bool(true))) => return Ordering::Greater,
+        _ => (),
+    }
+    match (a, b) {
+        (Value::String(x), Value::String(y)) => x.cmp(y),
+        _ => a.type_name().cmp(b.type_name()).then_with(|| a.map_key().cmp(&b.map_key())),
+    }
+}
+
+// Map entries by their keys
+fn compare_entries(a: &(Value, Value), b: &(Value, Value)) -> Ordering {
+    compare(&a.0, &b.0)
+}
+
+impl PartialOrd for SortKey {
+    fn partial_cmp(&self, other: &SortKey) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for SortKey {
+    fn eq(&self, other: &SortKey) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SortKey {}
+
+// In the order of SortKey, vectors stay vectors and anything else becomes a
+// list
+fn sort(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("sort", args, 1));
+    let mut items: Vec<SortKey> = try!(seq_items("sort", &args[0])).into_iter().map(SortKey).collect();
+    items.sort();
+    let items = items.into_iter().map(|key| key.0).collect();
+    match args[0] {
+        Value::Vector(_) => Ok(Value::Vector(items)),
+        _ => Ok(list_or_nil(items)),
+    }
+}
+
+// Like hash-map, but with the entries in the order of their keys, also after
+// assoc and dissoc
+fn sorted_map(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args.len() % 2 != 0 {
+        return Err(type_error(String::from("sorted-map expects an even number of arguments")))
+    }
+    let mut map = OrderedMap::sorted_by(compare_entries);
+    for pair in args.chunks(2) {
+        map.insert(pair[0].map_key(), (pair[0].clone(), pair[1].clone()));
+    }
+    Ok(Value::Map(map))
+}
+
+// Equal values hash the same. The hash is the same on every run, but may
+// change with a new version of losp.
+fn hash(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("hash", args, 1));
+    let mut hasher = DefaultHasher::new();
+    args[0].map_key().hash(&mut hasher);
+    Ok(Value::Int(hasher.finish() as i64))
+}
+
+// Takes a sequence of key-value pairs, or of alternating keys and values if
+// not every item is a pair
+fn into_map(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("into-map", args, 1));
+    let items = try!(seq_items("into-map", &args[0]));
+    let pairs: Option<Vec<(Value, Value)>> = items.iter().map(|item| match item {
+        Value::List(l) | Value::Vector(l) if l.len() == 2 => Some((l[0].clone(), l[1].clone())),
+        _ => None,
+    }).collect();
+    let pairs = match pairs {
+        Some(pairs) => pairs,
+        None if items.len() % 2 == 0 => {
+            items.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect()
+        }
+        None => return Err(type_error(String::from("into-map expects pairs or an even number of keys and values"))),
+    };
+    let mut map = OrderedMap::new();
+    for (key, value) in pairs {
+        map.insert(key.map_key(), (key, value));
+    }
+    Ok(Value::Map(map))
+}
+
+// Replaces any metadata the value already has
+fn map_arg<'a>(name: &str, value: &'a Value) -> Result<&'a OrderedMap<(Value, Value)>, RuntimeError> {
+    match value {
+        Value::Map(m) => Ok(m),
+        Value::WithMeta(v, _) => map_arg(name, v),
+        _ => Err(type_error(format!("{} expects a map, got {:?}", name, value))),
+    }
+}
+
+// In the order the keys were added in
+fn keys(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("keys", args, 1));
+    let map = try!(map_arg("keys", &args[0]));
+    Ok(list_or_nil(map.values().map(|(k, _)| k.clone()).collect()))
+}
+
+fn values(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("values", args, 1));
+    let map = try!(map_arg("values", &args[0]));
+    Ok(list_or_nil(map.values().map(|(_, v)| v.clone()).collect()))
+}
+
+// The value under a key of a map, or an index of a list or vector. Nil has
+// nothing under any key.
+fn lookup(name: &str, coll: &Value, key: &Value) -> Result<Option<Value>, RuntimeError> {
+    match (coll, key) {
+        (Value::WithMeta(v, _), _) => lookup(name, v, key),
+        (Value::Nil, _) => Ok(None),
+        (Value::Map(m), _) => Ok(m.get(&key.map_key()).map(|(_, v)| v.clone())),
+        (Value::Vector(l), Value::Int(i)) | (Value::List(l), Value::Int(i)) if 0 <= *i =>
+            Ok(l.get(*i as usize).cloned()),
+        (Value::Vector(_), Value::Int(_)) | (Value::List(_), Value::Int(_)) => Ok(None),
+        (v, _) => Err(type_error(format!("{} expects a map, list or vector, got {:?}", name, v))),
+    }
+}
+
+// With an optional default for missing keys
+fn get(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity_range("get", args, 2, 3));
+    let default = args.get(2).cloned().unwrap_or(Value::Nil);
+    Ok(try!(lookup("get", &args[0], &args[1])).unwrap_or(default))
+}
+
+// Calling a keyword, `(:a m)`, is `(get m :a)`. Calling a symbol, `('a m)`,
+// looks up the symbol and then the keyword of the same name.
+pub fn call_key(key: &Value, args: &[Value]) -> Result<Value, RuntimeError> {
+    let name = key.to_string();
+    try!(check_arity_range(&name, args, 1, 2));
+    let default = args.get(1).cloned().unwrap_or(Value::Nil);
+    let map = match &args[0] {
+        Value::Nil => return Ok(default),
+        m => try!(map_arg(&name, m)),
+    };
+    let entry = match key {
+        Value::Symbol(s) => map.get(&key.map_key())
+            .or_else(|| map.get(&Value::Keyword(s.to_string()).map_key())),
+        _ => map.get(&key.map_key()),
+    };
+    Ok(entry.map_or(default, |(_, v)| v.clone()))
+}
+
+// `(? m :a :b)` looks up each key in the result of the last lookup, and is
+// nil as soon as one is missing
+fn safe_get(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args.is_empty() {
+        return Err(arity_error(String::from("Arity mismatch: ? expects at least 1, got 0")))
+    }
+    let mut current = args[0].clone();
+    for key in &args[1..] {
+        current = match try!(lookup("?", &current, key)) {
+            Some(v) => v,
+            None => return Ok(Value::Nil),
+        };
+    }
+    Ok(current)
+}
+
+fn contains(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("contains?", args, 2));
+    match &args[0] {
+        Value::Nil => Ok(Value::Bool(false)),
+        v => Ok(Value::Bool(try!(map_arg("contains?", v)).get(&args[1].map_key()).is_some())),
+    }
+}
+
+// Returns a copy with the further keys and values added, nil is an empty map
+fn assoc(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args.len() < 3 || args.len() % 2 == 0 {
+        return Err(type_error(String::from("assoc expects a map followed by keys and values")))
+    }
+    let mut map = match &args[0] {
+        Value::Nil => OrderedMap::new(),
+        v => try!(map_arg("assoc", v)).clone(),
+    };
+    for pair in args[1..].chunks(2) {
+        map.insert(pair[0].map_key(), (pair[0].clone(), pair[1].clone()));
+    }
+    Ok(Value::Map(map))
+}
+
+// Returns a copy without the further keys
+fn dissoc(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args.is_empty() {
+        return Err(arity_error(String::from("Arity mismatch: dissoc expects at least 1, got 0")))
+    }
+    if let Value::Nil = args[0] {
+        return Ok(Value::Nil)
+    }
+    let mut map = try!(map_arg("dissoc", &args[0])).clone();
+    for key in &args[1..] {
+        map.remove(&key.map_key());
+    }
+    Ok(Value::Map(map))
+}
+
+fn transient_arg<'a>(name: &str, value: &'a Value) -> Result<&'a Arc<Mutex<OrderedMap<(Value, Value)>>>, RuntimeError> {
+    match value {
+        Value::TransientMap(m) => Ok(m),
+        _ => Err(type_error(format!("{} expects a transient map, got {:?}", name, value))),
+    }
+}
+
+// A transient map with the entries of a map, nil is an empty map
+fn transient(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("transient", args, 1));
+    let map = match &args[0] {
+        Value::Nil => OrderedMap::new(),
+        v => try!(map_arg("transient", v)).clone(),
+    };
+    Ok(Value::TransientMap(Arc::new(Mutex::new(map))))
+}
+
+// Adds the further keys and values to a transient map and returns it
+fn assoc_in_place(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args.len() < 3 || args.len() % 2 == 0 {
+        return Err(type_error(String::from("assoc! expects a transient map followed by keys and values")))
+    }
+    let mut map = try!(transient_arg("assoc!", &args[0])).lock().unwrap();
+    for pair in args[1..].chunks(2) {
+        map.insert(pair[0].map_key(), (pair[0].clone(), pair[1].clone()));
+    }
+    Ok(args[0].clone())
+}
+
+// Removes the further keys from a transient map and returns it
+fn dissoc_in_place(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args.is_empty() {
+        return Err(arity_error(String::from("Arity mismatch: dissoc! expects at least 1, got 0")))
+    }
+    let mut map = try!(transient_arg("dissoc!", &args[0])).lock().unwrap();
+    for key in &args[1..] {
+        map.remove(&key.map_key());
+    }
+    Ok(args[0].clone())
+}
+
+// A map with the current entries of a transient map. Later changes to the
+// transient map do not show up in it.
+fn persistent(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("persistent!", args, 1));
+    let map = try!(transient_arg("persistent!", &args[0]));
+    Ok(Value::Map(map.lock().unwrap().clone()))
+}
+
+// The entries of a map for the given keys, in the order of the keys
+fn select_keys(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("select-keys", args, 2));
+    let keys = try!(seq_items("select-keys", &args[1]));
+    let mut selected = OrderedMap::new();
+    if let Value::Nil = args[0] {
+        return Ok(Value::Map(selected))
+    }
+    let map = try!(map_arg("select-keys", &args[0]));
+    for key in keys {
+        let key = key.map_key();
+        if let Some(entry) = map.get(&key) {
+            selected.insert(key, entry.clone());
+        }
+    }
+    Ok(Value::Map(selected))
+}
+
+// Renamed keys stay where they were and win over keys already called what
+// they are renamed to
+fn rename_keys(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("rename-keys", args, 2));
+    let map = try!(map_arg("rename-keys", &args[0]));
+    let renames = try!(map_arg("rename-keys", &args[1]));
+    let targets: Vec<String> = renames.iter()
+        .filter(|(old, _)| map.get(old).is_some())
+        .map(|(_, (_, new))| new.map_key())
+        .collect();
+    let mut renamed = OrderedMap::new();
+    for (key, (k, v)) in map.iter() {
+        match renames.get(key) {
+            Some((_, new)) => renamed.insert(new.map_key(), (new.clone(), v.clone())),
+            None if targets.contains(key) => (),
+            None => renamed.insert(key.clone(), (k.clone(), v.clone())),
+        }
+    }
+    Ok(Value::Map(renamed))
+}
+
+fn with_meta(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("with-meta", args, 2));
+    let value = match &args[0] {
+        Value::WithMeta(v, _) => v.clone(),
+        v => Box::new(v.clone()),
+    };
+    match &args[1] {
+        Value::Map(_) => Ok(Value::WithMeta(value, Box::new(args[1].clone()))),
+        v => Err(type_error(format!("with-meta expects a map, got {:?}", v))),
+    }
+}
+
+fn meta(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("meta", args, 1));
+    match &args[0] {
+        Value::WithMeta(_, m) => Ok((**m).clone()),
+        _ => Ok(Value::Nil),
+    }
+}
+
+// The docstring given to `defn`, nil if there is none
+fn doc(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("doc", args, 1));
+    match &args[0] {
+        Value::WithMeta(_, m) => Ok(try!(lookup("doc", m, &Value::Keyword(String::from("doc")))).unwrap_or(Value::Nil)),
+        _ => Ok(Value::Nil),
+    }
+}
+
+// `(make-error kind message data cause)`, the data and the cause are optional
+fn make_error(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity_range("make-error", args, 2, 4));
+    let kind = try!(string_arg("make-error", &args[0]));
+    let message = try!(string_arg("make-error", &args[1]));
+    Ok(Value::Error{
+        kind: kind.clone(),
+        message: message.clone(),
+        data: Box::new(args.get(2).cloned().unwrap_or(Value::Nil)),
+        cause: Box::new(args.get(3).cloned().unwrap_or(Value::Nil)),
+    })
+}
+
+// Any value can be raised, `catch` receives it unchanged
+fn raise(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("raise", args, 1));
+    Err(RuntimeError::Raised(args[0].clone()))
+}
+
+// Raises a runtime error for a failed `:pre` or `:post` condition
+fn condition_failed(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("condition-failed", args, 1));
+    Err(runtime_error(try!(string_arg("condition-failed", &args[0])).clone()))
+}
+
+fn error_arg<'a>(name: &str, value: &'a Value) -> Result<(&'a String, &'a String, &'a Value, &'a Value), RuntimeError> {
+    match value {
+        Value::Error{kind, message, data, cause} => Ok((kind, message, data, cause)),
+        _ => Err(type_error(format!("{} expects an error, got {:?}", name, value))),
+    }
+}
+
+fn error_kind(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("error-kind", args, 1));
+    let (kind, _, _, _) = try!(error_arg("error-kind", &args[0]));
+    Ok(Value::String(kind.clone()))
+}
+
+fn error_message(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("error-message", args, 1));
+    let (_, message, _, _) = try!(error_arg("error-message", &args[0]));
+    Ok(Value::String(message.clone()))
+}
+
+fn error_data(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("error-data", args, 1));
+    let (_, _, data, _) = try!(error_arg("error-data", &args[0]));
+    Ok(data.clone())
+}
+
+fn error_cause(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("error-cause", args, 1));
+    let (_, _, _, cause) = try!(error_arg("error-cause", &args[0]));
+    Ok(cause.clone())
+}
+
+fn is_error(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("error?", args, 1));
+    match &args[0] {
+        Value::Error{..} => Ok(Value::Bool(true)),
+        _ => Ok(Value::Bool(false)),
+    }
+}
+
+fn string_to_list(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("string->list", args, 1));
+    let s = try!(string_arg("string->list", &args[0]));
+    if s.is_empty() {
+        Ok(Value::Nil)
+    } else {
+        Ok(Value::List(s.chars().map(Value::Char).collect()))
+    }
+}
+
+// Accepts chars as well as strings of one character
+fn list_to_string(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("list->string", args, 1));
+    let items = try!(seq_items("list->string", &args[0]));
+    let mut s = String::new();
+    for item in items.iter() {
+        match item {
+            Value::Char(c) => s.push(*c),
+            Value::String(c) if c.chars().count() == 1 => s.push_str(c),
+            v => return Err(type_error(format!("list->string expects characters, got {:?}", v))),
+        }
+    }
+    Ok(Value::String(s))
+}
+
+// The string, the number of padding characters needed to reach the width,
+// and the padding character, which defaults to a space
+fn padding_args(name: &str, args: &[Value]) -> Result<(String, usize, char), RuntimeError> {
+    try!(check_arity_range(name, args, 2, 3));
+    let s = try!(string_arg(name, &args[0]));
+    let width = try!(index_arg(name, &args[1]));
+    let pad = match args.get(2) {
+        None => ' ',
+        Some(Value::Char(c)) => *c,
+        Some(Value::String(c)) if c.chars().count() == 1 => c.chars().next().unwrap(),
+        Some(v) => return Err(type_error(format!("{} expects a padding character, got {:?}", name, v))),
+    };
+    Ok((s.clone(), width.saturating_sub(s.chars().count()), pad))
+}
+
+fn padding(pad: char, n: usize) -> String {
+    std::iter::repeat(pad).take(n).collect()
+}
+
+fn string_pad_left(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let (s, n, pad) = try!(padding_args("string-pad-left", args));
+    Ok(Value::String(padding(pad, n) + &s))
+}
+
+fn string_pad_right(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let (s, n, pad) = try!(padding_args("string-pad-right", args));
+    Ok(Value::String(s + &padding(pad, n)))
+}
+
+// Odd padding puts the extra character on the right
+fn string_center(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let (s, n, pad) = try!(padding_args("string-center", args));
+    Ok(Value::String(padding(pad, n / 2) + &s + &padding(pad, n - n / 2)))
+}
+
+fn string_contains(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("string-contains?", args, 2));
+    let s = try!(string_arg("string-contains?", &args[0]));
+    let part = try!(string_arg("string-contains?", &args[1]));
+    Ok(Value::Bool(s.contains(part.as_str())))
+}
+
+fn string_repeat(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("string-repeat", args, 2));
+    let s = try!(string_arg("string-repeat", &args[0]));
+    match args[1] {
+        Value::Int(n) if 0 <= n => Ok(Value::String(s.repeat(n as usize))),
+        ref v => Err(type_error(format!("string-repeat expects a non-negative count, got {:?}", v))),
+    }
+}
+
+// Reverses characters, not bytes
+fn string_reverse(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("string-reverse", args, 1));
+    let s = try!(string_arg("string-reverse", &args[0]));
+    Ok(Value::String(s.chars().rev().collect()))
+}
+
+// Indices count characters, not bytes. The search can start at a later
+// index, and finding nothing is nil.
+fn string_index_of(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity_range("string-index-of", args, 2, 3));
+    let s = try!(string_arg("string-index-of", &args[0]));
+    let part = try!(string_arg("string-index-of", &args[1]));
+    let start = match args.get(2) {
+        Some(v) => try!(index_arg("string-index-of", v)),
+        None => 0,
+    };
+    let offset = match s.char_indices().map(|(i, _)| i).chain(Some(s.len())).nth(start) {
+        Some(offset) => offset,
+        None => return Ok(Value::Nil),
+    };
+    Ok(match s[offset..].find(part.as_str()) {
+        Some(found) => Value::Int((start + s[offset..offset + found].chars().count()) as i64),
+        None => Value::Nil,
+    })
+}
+
+// A char, or a string holding exactly one
+fn char_arg(name: &str, value: &Value) -> Result<char, RuntimeError> {
+    match value {
+        Value::Char(c) => Ok(*c),
+        Value::String(s) if s.chars().count() == 1 => Ok(s.chars().next().unwrap()),
+        _ => Err(type_error(format!("{} expects a char, got {:?}", name, value))),
+    }
+}
+
+fn char_predicate(name: &str, args: &[Value], predicate: fn(char) -> bool) -> Result<Value, RuntimeError> {
+    try!(check_arity(name, args, 1));
+    let c = try!(char_arg(name, &args[0]));
+    Ok(Value::Bool(predicate(c)))
+}
+
+fn is_char_alphabetic(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    char_predicate("char-alphabetic?", args, char::is_alphabetic)
+}
+
+fn is_char_numeric(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    char_predicate("char-numeric?", args, char::is_numeric)
+}
+
+fn is_char_whitespace(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    char_predicate("char-whitespace?", args, char::is_whitespace)
+}
+
+fn is_char_upper_case(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    char_predicate("char-upper-case?", args, char::is_uppercase)
+}
+
+fn is_char_lower_case(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    char_predicate("char-lower-case?", args, char::is_lowercase)
+}
+
+// Parses source into a list of forms, see compiler::read_all
+fn read_all_native(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("read-all", args, 1));
+    let source = try!(string_arg("read-all", &args[0]));
+    let forms = try!(read_all(source).map_err(runtime_error));
+    if forms.is_empty() {
+        Ok(Value::Nil)
+    } else {
+        Ok(Value::List(forms))
+    }
+}
+
+// Evaluates a string of source, or a form as returned by quote or read-all
+fn eval(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("eval", args, 1));
+    let config = CompilerConfig::new().with_debug(vm.debug);
+    let chunk = match &args[0] {
+        Value::String(source) => compile_eval(source, &config),
+        form => compile_form(form, &config),
+    };
+    vm.eval_chunk(try!(chunk.map_err(runtime_error)))
+}
+
+// Concatenates the display forms, skipping nil
+fn str_native(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let mut s = String::new();
+    for arg in args {
+        if let Value::Nil = arg {
+            continue
+        }
+        s.push_str(&arg.to_string());
+    }
+    Ok(Value::String(s))
+}
+
+fn type_of(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("type-of", args, 1));
+    Ok(Value::Symbol(intern(args[0].type_name())))
+}
+
+// Nil for functions not defined in a file, like builtins and those typed
+// into the repl
+fn fn_file(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("fn-file", args, 1));
+    match &args[0] {
+        Value::Function{file, ..} => Ok(file.clone().map_or(Value::Nil, Value::String)),
+        Value::MultiArity{clauses, ..} => fn_file(_vm, &clauses[..1]),
+        Value::Native(_) => Ok(Value::Nil),
+        v => Err(type_error(format!("fn-file expects a function, got {:?}", v))),
+    }
+}
+
+fn fn_line(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("fn-line", args, 1));
+    match &args[0] {
+        Value::Function{line, ..} => Ok(Value::Int(*line as i64)),
+        Value::MultiArity{clauses, ..} => fn_line(_vm, &clauses[..1]),
+        Value::Native(_) => Ok(Value::Nil),
+        v => Err(type_error(format!("fn-line expects a function, got {:?}", v))),
+    }
+}
+
+// Symbols and keywords with their name split from the namespace
+fn qualified_arg<'a>(name: &str, value: &'a Value) -> Result<(Option<&'a str>, &'a str), RuntimeError> {
+    let full: &str = match value {
+        Value::Symbol(s) => s,
+        Value::Keyword(k) => k,
+        _ => return Err(type_error(format!("{} expects a symbol or keyword, got {:?}", name, value))),
+    };
+    Ok(match split_qualified(full) {
+        Some((namespace, local)) => (Some(namespace), local),
+        None => (None, full),
+    })
+}
+
+// Nil for unqualified names
+fn namespace(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("namespace", args, 1));
+    match try!(qualified_arg("namespace", &args[0])) {
+        (Some(namespace), _) => Ok(Value::String(String::from(namespace))),
+        (None, _) => Ok(Value::Nil),
+    }
+}
+
+fn name(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("name", args, 1));
+    let (_, local) = try!(qualified_arg("name", &args[0]));
+    Ok(Value::String(String::from(local)))
+}
+
+// `(symbol "ns" "name")` is `ns/name`
+fn symbol(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity_range("symbol", args, 1, 2));
+    let first = try!(string_arg("symbol", &args[0]));
+    match args.get(1) {
+        Some(local) => {
+            let local = try!(string_arg("symbol", local));
+            Ok(Value::Symbol(intern(&format!("{}/{}", first, local))))
+        }
+        None => Ok(Value::Symbol(intern(first))),
+    }
+}
+
+// Called by protocol methods with the protocol and method names, then the
+// arguments, the first of which picks the implementation
+fn protocol_dispatch(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args.len() < 3 {
+        return Err(arity_error(format!("Arity mismatch: protocol-dispatch expects at least 3, got {}", args.len())))
+    }
+    let type_name = args[2].type_name();
+    let implementation = format!("{}:{}", args[1], type_name);
+    let f = vm.globals.read().unwrap().get(&implementation).cloned();
+    match f {
+        Some(f) => vm.call_value(f, args[2..].to_vec()),
+        None => Err(runtime_error(format!("No implementation of {} from protocol {} for {}", args[1], args[0], type_name))),
+    }
+}
+
+// The compiled clauses of a multi-arity `defn`, after its name
+fn make_multi_arity(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args.len() < 2 {
+        return Err(arity_error(format!("Arity mismatch: make-multi-arity expects at least 2, got {}", args.len())))
+    }
+    Ok(Value::MultiArity{
+        name: args[0].to_string(),
+        clauses: args[1..].to_vec(),
+    })
+}
+
+// A multimethod keeps its dispatch function in `name:dispatch` and its
+// methods in a map in `name:methods`, keyed by dispatch value
+fn make_multimethod(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("make-multimethod", args, 2));
+    let name = args[0].to_string();
+    let (params, file, line) = match &args[1] {
+        Value::Function{params, file, line, ..} => (params.clone(), file.clone(), *line),
+        v => return Err(type_error(format!("defmulti expects a dispatch function, got {:?}", v))),
+    };
+    {
+        let mut globals = vm.globals.write().unwrap();
+        globals.insert(format!("{}:dispatch", name), args[1].clone());
+        globals.entry(format!("{}:methods", name)).or_insert(Value::Map(OrderedMap::new()));
+    }
+    // Takes the same arguments as the dispatch function and passes them on
+    // to multimethod-dispatch, like a protocol method
+    let mut chunk = Chunk{
+        code: vec![],
+        constants: vec![],
+        lines: vec![],
+        chunks: vec![],
+        shared_constants: None,
+        label_map: vec![],
+        file: file.clone(),
+        repl_last_value: false,
+    };
+    let name_idx = chunk.write_constant(args[0].clone());
+    chunk.write_code(OpCode::Constant(name_idx), line);
+    for slot in 0..params.len() {
+        chunk.write_code(OpCode::GetLocal(slot), line);
+    }
+    let dispatch = find_native("multimethod-dispatch").unwrap();
+    chunk.write_code(OpCode::CallNative(dispatch, params.len() + 1), line);
+    chunk.write_code(OpCode::Return, line);
+    let chunk_idx = vm.load_chunk(chunk);
+    Ok(Value::Function{
+        name: name,
+        params: params,
+        keywords: vec![],
+        rest: None,
+        chunk_idx: chunk_idx,
+        file: file,
+        line: line,
+        upvalues: Arc::new(vec![]),
+    })
+}
+
+// Registers a method for a dispatch value, `:default` is used when no other
+// method matches
+fn add_method(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("add-method", args, 3));
+    let name = args[0].to_string();
+    let mut globals = vm.globals.write().unwrap();
+    match globals.get_mut(&format!("{}:methods", name)) {
+        Some(Value::Map(methods)) => {
+            methods.insert(args[1].map_key(), (args[1].clone(), args[2].clone()));
+            Ok(args[2].clone())
+        }
+        _ => Err(runtime_error(format!("{} is not a multimethod", name))),
+    }
+}
+
+fn multimethod_dispatch(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args.is_empty() {
+        return Err(arity_error(String::from("Arity mismatch: multimethod-dispatch expects at least 1, got 0")))
+    }
+    let name = args[0].to_string();
+    let dispatch = vm.globals.read().unwrap().get(&format!("{}:dispatch", name)).cloned();
+    let dispatch = match dispatch {
+        Some(f) => f,
+        None => return Err(runtime_error(format!("{} is not a multimethod", name))),
+    };
+    let value = try!(vm.call_value(dispatch, args[1..].to_vec()));
+    let method = match vm.globals.read().unwrap().get(&format!("{}:methods", name)) {
+        Some(Value::Map(methods)) => methods.get(&value.map_key())
+            .or_else(|| methods.get(&Value::Keyword(String::from("default")).map_key()))
+            .map(|(_, f)| f.clone()),
+        _ => None,
+    };
+    match method {
+        Some(f) => vm.call_value(f, args[1..].to_vec()),
+        None => Err(runtime_error(format!("No method in multimethod {} for dispatch value {:?}", name, value))),
+    }
+}
+
+// Every global, keyed by symbol
+fn global_bindings(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("global-bindings", args, 0));
+    let globals = vm.globals.read().unwrap();
+    let mut names: Vec<&String> = globals.keys().collect();
+    names.sort();
+    let mut map = OrderedMap::new();
+    for name in names {
+        let key = Value::Symbol(intern(name));
+        map.insert(key.map_key(), (key, globals[name].clone()));
+    }
+    Ok(Value::Map(map))
+}
+
+fn string_builder_arg<'a>(name: &str, value: &'a Value) -> Result<&'a Arc<Mutex<String>>, RuntimeError> {
+    match value {
+        Value::StringBuilder(sb) => Ok(sb),
+        _ => Err(type_error(format!("{} expects a string builder, got {:?}", name, value))),
+    }
+}
+
+fn make_string_builder(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("make-string-builder", args, 0));
+    Ok(Value::StringBuilder(Arc::new(Mutex::new(String::new()))))
+}
+
+// Appends the display form of every further argument
+fn sb_append(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args.is_empty() {
+        return Err(arity_error(String::from("Arity mismatch: sb-append! expects at least 1, got 0")))
+    }
+    let sb = try!(string_builder_arg("sb-append!", &args[0]));
+    for arg in &args[1..] {
+        let s = arg.to_string();
+        sb.lock().unwrap().push_str(&s);
+    }
+    Ok(args[0].clone())
+}
+
+// Returns the built string and resets the builder
+fn sb_build(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("sb-build", args, 1));
+    let sb = try!(string_builder_arg("sb-build", &args[0]));
+    Ok(Value::String(std::mem::replace(&mut *sb.lock().unwrap(), String::new())))
+}
+
+fn re_compile(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("re-compile", args, 1));
+    let pattern = try!(string_arg("re-compile", &args[0]));
+    Ok(Value::Regex(try!(Regex::new(pattern).map_err(runtime_error))))
+}
+
+fn re_find(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("re-find", args, 2));
+    let regex = try!(regex_arg("re-find", &args[0]));
+    let text = try!(string_arg("re-find", &args[1]));
+    match regex.find(text) {
+        Some(s) => Ok(Value::String(s)),
+        None => Ok(Value::Nil),
+    }
+}
+
+fn re_match(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("re-match", args, 2));
+    let regex = try!(regex_arg("re-match", &args[0]));
+    let text = try!(string_arg("re-match", &args[1]));
+    match regex.captures(text) {
+        Some(groups) => Ok(Value::List(groups.into_iter().map(|g| match g {
+            Some(s) => Value::String(s),
+            None => Value::Nil,
+        }).collect())),
+        None => Ok(Value::Nil),
+    }
+}
+
+fn re_split(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("re-split", args, 2));
+    let regex = try!(regex_arg("re-split", &args[0]));
+    let text = try!(string_arg("re-split", &args[1]));
+    Ok(Value::List(regex.split(text).into_iter().map(Value::String).collect()))
+}
+
+fn replace(name: &str, args: &[Value], all: bool) -> Result<Value, RuntimeError> {
+    try!(check_arity(name, args, 3));
+    let regex = try!(regex_arg(name, &args[0]));
+    let text = try!(string_arg(name, &args[1]));
+    let replacement = try!(string_arg(name, &args[2]));
+    Ok(Value::String(regex.replace(text, replacement, all)))
+}
+
+fn re_replace(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    replace("re-replace", args, false)
+}
+
+fn re_replace_all(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    replace("re-replace-all", args, true)
+}
+
+// Replaces every match with the result of calling f with the list of capture
+// groups, as returned by re-match
+fn re_replace_fn(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("re-replace-fn", args, 3));
+    let regex = try!(regex_arg("re-replace-fn", &args[0]));
+    let text = try!(string_arg("re-replace-fn", &args[1]));
+    let f = args[2].clone();
+    let result = try!(regex.replace_with(text, true, &mut |groups| {
+        let groups = Value::List(groups.iter().map(|g| match g {
+            Some(s) => Value::String(s.clone()),
+            None => Value::Nil,
+        }).collect());
+        match try!(vm.call_value(f.clone(), vec![groups])) {
+            Value::String(s) => Ok(s),
+            v => Err(type_error(format!("re-replace-fn expects the function to return a string, got {:?}", v))),
+        }
+    }));
+    Ok(Value::String(result))
+}
+
+// Port arguments are optional and default to standard input or output
+fn input_port_arg(vm: &VM, name: &str, args: &[Value], idx: usize) -> Result<InputPort, RuntimeError> {
+    match args.get(idx) {
+        Some(Value::Port(p)) => Ok(p.clone()),
+        Some(v) => Err(type_error(format!("{} expects an input port, got {:?}", name, v))),
+        None => Ok(vm.input_port.clone()),
+    }
+}
+
+fn output_port_arg(vm: &VM, name: &str, args: &[Value], idx: usize) -> Result<OutputPort, RuntimeError> {
+    match args.get(idx) {
+        Some(Value::OutPort(p)) => Ok(p.clone()),
+        Some(v) => Err(type_error(format!("{} expects an output port, got {:?}", name, v))),
+        None => Ok(vm.output_port.clone()),
+    }
+}
+
+fn open_input_file(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("open-input-file", args, 1));
+    let path = try!(string_arg("open-input-file", &args[0]));
+    match File::open(path) {
+        Ok(file) => Ok(Value::Port(Arc::new(Mutex::new(Box::new(BufReader::new(file)))))),
+        Err(e) => Err(runtime_error(format!("Cannot open {}: {}", path, e))),
+    }
+}
+
+fn open_output_file(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("open-output-file", args, 1));
+    let path = try!(string_arg("open-output-file", &args[0]));
+    match File::create(path) {
+        Ok(file) => Ok(Value::OutPort(Arc::new(Mutex::new(Box::new(BufWriter::new(file)))))),
+        Err(e) => Err(runtime_error(format!("Cannot open {}: {}", path, e))),
+    }
+}
+
+// Closed ports behave like an empty file or a sink
+fn close_port(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("close-port", args, 1));
+    match &args[0] {
+        Value::Port(p) => {
+            *p.lock().unwrap() = Box::new(io::empty());
+        }
+        Value::OutPort(p) => {
+            let mut port = p.lock().unwrap();
+            try!(port.flush().map_err(|e| runtime_error(e.to_string())));
+            *port = Box::new(io::sink());
+        }
+        v => return Err(type_error(format!("close-port expects a port, got {:?}", v))),
+    }
+    Ok(Value::Nil)
+}
+
+fn current_input_port(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("current-input-port", args, 0));
+    Ok(Value::Port(vm.input_port.clone()))
+}
+
+fn current_output_port(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("current-output-port", args, 0));
+    Ok(Value::OutPort(vm.output_port.clone()))
+}
+
+fn current_error_port(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("current-error-port", args, 0));
+    Ok(Value::OutPort(vm.error_port.clone()))
+}
+
+// Decodes the next character and its width in bytes, without consuming it
+fn next_char(port: &InputPort) -> Result<Option<(char, usize)>, RuntimeError> {
+    let mut reader = port.lock().unwrap();
+    let buffer = try!(reader.fill_buf().map_err(|e| runtime_error(e.to_string())));
+    if buffer.is_empty() {
+        return Ok(None)
+    }
+    let width = match buffer[0] {
+        b if b < 0x80 => 1,
+        b if b >= 0xf0 => 4,
+        b if b >= 0xe0 => 3,
+        _ => 2,
+    };
+    match std::str::from_utf8(&buffer[..width.min(buffer.len())]).ok().and_then(|s| s.chars().next()) {
+        Some(c) => Ok(Some((c, width))),
+        None => Err(runtime_error(String::from("Invalid UTF-8 in input port"))),
+    }
+}
+
+// Returns nil at the end of the input
+fn read_char(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity_range("read-char", args, 0, 1));
+    let port = try!(input_port_arg(vm, "read-char", args, 0));
+    match try!(next_char(&port)) {
+        Some((c, width)) => {
+            port.lock().unwrap().consume(width);
+            Ok(Value::Char(c))
+        }
+        None => Ok(Value::Nil),
+    }
+}
+
+fn peek_char(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity_range("peek-char", args, 0, 1));
+    let port = try!(input_port_arg(vm, "peek-char", args, 0));
+    match try!(next_char(&port)) {
+        Some((c, _)) => Ok(Value::Char(c)),
+        None => Ok(Value::Nil),
+    }
+}
+
+// Returns the line without its line ending, or nil at the end of the input
+fn read_line(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity_range("read-line", args, 0, 1));
+    let port = try!(input_port_arg(vm, "read-line", args, 0));
+    let mut line = String::new();
+    let read = try!(port.lock().unwrap().read_line(&mut line).map_err(|e| runtime_error(e.to_string())));
+    if read == 0 {
+        return Ok(Value::Nil)
+    }
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    Ok(Value::String(line))
+}
+
+fn write_to(port: &OutputPort, s: &str) -> Result<Value, RuntimeError> {
+    try!(port.lock().unwrap().write_all(s.as_bytes()).map_err(|e| runtime_error(e.to_string())));
+    Ok(Value::Nil)
+}
+
+fn write_char(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity_range("write-char", args, 1, 2));
+    let port = try!(output_port_arg(vm, "write-char", args, 1));
+    match &args[0] {
+        Value::Char(c) => write_to(&port, &c.to_string()),
+        v => Err(type_error(format!("write-char expects a char, got {:?}", v))),
+    }
+}
+
+fn write_string(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity_range("write-string", args, 1, 2));
+    let port = try!(output_port_arg(vm, "write-string", args, 1));
+    let s = try!(string_arg("write-string", &args[0]));
+    write_to(&port, s)
+}
+
+// Like print, which compiles to this when given a port
+fn write_line(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity_range("write-line", args, 1, 2));
+    let port = try!(output_port_arg(vm, "write-line", args, 1));
+    write_to(&port, &format!("{}\n", args[0]))
+}
+
+// Calls a function without arguments n times and prints statistics of how
+// long the calls took to stderr. Returns what the last call returned.
+fn run_bench(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("run-bench", args, 2));
+    let n = match &args[0] {
+        Value::Int(n) if *n > 0 => *n as usize,
+        v => return Err(type_error(format!("bench expects a positive number of runs, got {:?}", v))),
+    };
+    let mut times = Vec::with_capacity(n);
+    let mut result = Value::Nil;
+    for _ in 0..n {
+        let start = Instant::now();
+        result = try!(vm.call_value(args[1].clone(), vec![]));
+        times.push(start.elapsed().as_secs_f64() * 1e6);
+    }
+    eprintln!("{}", bench_stats(&times));
+    Ok(result)
+}
+
+// Times are in microseconds
+fn bench_stats(times: &[f64]) -> String {
+    let n = times.len() as f64;
+    let mean = times.iter().sum::<f64>() / n;
+    let variance = times.iter().map(|t| (t - mean) * (t - mean)).sum::<f64>() / n;
+    let min = times.iter().cloned().fold(std::f64::INFINITY, f64::min);
+    let max = times.iter().cloned().fold(std::f64::NEG_INFINITY, f64::max);
+    format!("min={:.1}µs max={:.1}µs mean={:.1}µs stddev={:.1}µs", min, max, mean, variance.sqrt())
+}
+
+// Calls a function without arguments on a new thread with its own VM
+fn promise(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("promise", args, 1));
+    let promise: Promise = Arc::new((Mutex::new(PromiseState::Pending), Condvar::new()));
+    let result = promise.clone();
+    let mut thread_vm = vm.spawn();
+    let f = args[0].clone();
+    thread::spawn(move || {
+        let outcome = settle(|| thread_vm.call_value(f, vec![]));
+        let (state, settled) = &*result;
+        *state.lock().unwrap() = outcome;
+        settled.notify_all();
+    });
+    Ok(Value::Promise(promise))
+}
+
+// A panic rejects the promise instead of leaving it pending forever
+fn settle<F: FnOnce() -> Result<Value, RuntimeError>>(f: F) -> PromiseState {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(v)) => PromiseState::Resolved(v),
+        Ok(Err(e)) => PromiseState::Rejected(e),
+        Err(panic) => {
+            let message = match panic.downcast_ref::<&str>() {
+                Some(s) => String::from(*s),
+                None => panic.downcast_ref::<String>().cloned().unwrap_or_default(),
+            };
+            PromiseState::Rejected(runtime_error(format!("Promise panicked: {}", message)))
+        }
+    }
+}
+
+fn promise_arg<'a>(name: &str, value: &'a Value) -> Result<&'a Promise, RuntimeError> {
+    match value {
+        Value::Promise(p) => Ok(p),
+        _ => Err(type_error(format!("{} expects a promise, got {:?}", name, value))),
+    }
+}
+
+// Blocks until the promise settles, raising its error if it was rejected
+fn wait_for(promise: &Promise) -> Result<Value, RuntimeError> {
+    let (state, settled) = &**promise;
+    let mut state = state.lock().unwrap();
+    while let PromiseState::Pending = *state {
+        state = settled.wait(state).unwrap();
+    }
+    match &*state {
+        PromiseState::Resolved(v) => Ok(v.clone()),
+        PromiseState::Rejected(e) => Err(e.clone()),
+        PromiseState::Pending => unreachable!(),
+    }
+}
+
+fn deref_promise(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("deref-promise", args, 1));
+    wait_for(try!(promise_arg("deref-promise", &args[0])))
+}
+
+fn is_resolved(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("resolved?", args, 1));
+    let promise = try!(promise_arg("resolved?", &args[0]));
+    match *promise.0.lock().unwrap() {
+        PromiseState::Pending => Ok(Value::Bool(false)),
+        _ => Ok(Value::Bool(true)),
+    }
+}
+
+// Waits for every promise in a list, returning a list of their values
+fn all_promises(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("all-promises", args, 1));
+    let mut values = vec![];
+    for p in try!(seq_items("all-promises", &args[0])).iter() {
+        values.push(try!(wait_for(try!(promise_arg("all-promises", p)))));
+    }
+    Ok(list_or_nil(values))
+}
+
+// The integers from start up to, but not including, end
+fn range(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("range", args, 2));
+    match (&args[0], &args[1]) {
+        (Value::Int(start), Value::Int(end)) => Ok(list_or_nil((*start..*end).map(Value::Int).collect())),
+        _ => Err(type_error(format!("range expects two ints, got {:?} and {:?}", args[0], args[1]))),
+    }
+}
+
+fn stream_arg<'a>(name: &str, value: &'a Value) -> Result<&'a Stream, RuntimeError> {
+    match value {
+        Value::Stream(s) => Ok(s),
+        _ => Err(type_error(format!("{} expects a stream, got {:?}", name, value))),
+    }
+}
+
+// Pulls the next value, the lock is not held while calling functions so they
+// may use other streams
+fn stream_next(vm: &mut VM, stream: &Stream) -> Result<Option<Value>, RuntimeError> {
+    enum Pull {
+        // The function, its source, and whether it filters
+        Apply(Value, Stream, bool),
+        Concat(Stream, Stream),
+    }
+    // The lock is only held while reading the state, as pulling from the
+    // sources can call functions that use this stream again
+    let pull = match &mut *stream.lock().unwrap() {
+        StreamState::Items(items) => return Ok(items.next()),
+        StreamState::Map(f, source) => Pull::Apply(f.clone(), source.clone(), false),
+        StreamState::Filter(f, source) => Pull::Apply(f.clone(), source.clone(), true),
+        StreamState::Concat(first, second) => Pull::Concat(first.clone(), second.clone()),
+    };
+    let (f, source, is_filter) = match pull {
+        Pull::Apply(f, source, is_filter) => (f, source, is_filter),
+        Pull::Concat(first, second) => return match try!(stream_next(vm, &first)) {
+            Some(v) => Ok(Some(v)),
+            None => stream_next(vm, &second),
+        },
+    };
+    loop {
+        let v = match try!(stream_next(vm, &source)) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let result = try!(vm.call_value(f.clone(), vec![v.clone()]));
+        if !is_filter {
+            return Ok(Some(result))
+        } else if result.truthy() {
+            return Ok(Some(v))
+        }
+    }
+}
+
+fn stream_from_list(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("stream-from-list", args, 1));
+    let items = try!(seq_items("stream-from-list", &args[0]));
+    Ok(Value::Stream(Arc::new(Mutex::new(StreamState::Items(items.into_iter())))))
+}
+
+// Nil once the stream is exhausted
+fn stream_next_native(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("stream-next!", args, 1));
+    let stream = try!(stream_arg("stream-next!", &args[0]));
+    Ok(try!(stream_next(vm, stream)).unwrap_or(Value::Nil))
+}
+
+fn stream_take(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("stream-take", args, 2));
+    let n = try!(index_arg("stream-take", &args[0]));
+    let stream = try!(stream_arg("stream-take", &args[1]));
+    let mut items = vec![];
+    while items.len() < n {
+        match try!(stream_next(vm, stream)) {
+            Some(v) => items.push(v),
+            None => break,
+        }
+    }
+    Ok(list_or_nil(items))
+}
+
+fn stream_map(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("stream-map", args, 2));
+    let source = try!(stream_arg("stream-map", &args[1])).clone();
+    Ok(Value::Stream(Arc::new(Mutex::new(StreamState::Map(args[0].clone(), source)))))
+}
+
+fn stream_filter(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("stream-filter", args, 2));
+    let source = try!(stream_arg("stream-filter", &args[1])).clone();
+    Ok(Value::Stream(Arc::new(Mutex::new(StreamState::Filter(args[0].clone(), source)))))
+}
+
+// Streams are used as they are, other sequences become finite streams
+fn stream_or_seq(name: &str, value: &Value) -> Result<Stream, RuntimeError> {
+    match value {
+        Value::Stream(s) => Ok(s.clone()),
+        v => Ok(Arc::new(Mutex::new(StreamState::Items(try!(seq_items(name, v)).into_iter())))),
+    }
+}
+
+// A stream of the first argument followed by the second, without pulling
+// anything from either
+fn lazy_concat(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("lazy-concat", args, 2));
+    let first = try!(stream_or_seq("lazy-concat", &args[0]));
+    let second = try!(stream_or_seq("lazy-concat", &args[1]));
+    Ok(Value::Stream(Arc::new(Mutex::new(StreamState::Concat(first, second)))))
+}
+
+fn stream_to_list(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    try!(check_arity("stream->list", args, 1));
+    let stream = try!(stream_arg("stream->list", &args[0]));
+    let mut items = vec![];
+    while let Some(v) = try!(stream_next(vm, stream)) {
+        items.push(v);
+    }
+    Ok(list_or_nil(items))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compiler::vm::init_vm;
+
+    #[test]
+    fn a_panicking_promise_is_rejected() {
+        match settle(|| panic!("boom")) {
+            PromiseState::Rejected(e) => assert_eq!(e.to_string(), "Promise panicked: boom"),
+            _ => panic!("expected the promise to be rejected"),
+        }
+    }
+
+    #[test]
+    fn waiting_wakes_up_when_the_promise_settles() {
+        let mut vm = init_vm();
+        let promise = promise(&mut vm, &[Value::Native(find_native("vector").unwrap())]).unwrap();
+        assert!(deref_promise(&mut vm, &[promise.clone()]).unwrap().equal(&Value::Vector(vec![])).truthy());
+        assert!(is_resolved(&mut vm, &[promise]).unwrap().truthy());
+    }
+}
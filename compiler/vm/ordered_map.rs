@@ -0,0 +1,69 @@
+use std::cmp::Ordering;
+
+use super::hamt::Hamt;
+
+// A map from strings that iterates in insertion order, or sorted by its
+// values if it was made with sorted_by. Replacing the value of a key keeps
+// the key where it was. Entries live in a persistent trie, so a changed copy
+// of a map shares most of its structure with the original, and keeps its
+// order.
+#[derive(Clone)]
+pub struct OrderedMap<V> {
+    // Each value is stored with the position its key was first added at
+    entries: Hamt<(u64, V)>,
+    next: u64,
+    compare: Option<fn(&V, &V) -> Ordering>,
+}
+
+impl<V: Clone> OrderedMap<V> {
+    pub fn new() -> OrderedMap<V> {
+        OrderedMap{
+            entries: Hamt::new(),
+            next: 0,
+            compare: None,
+        }
+    }
+
+    pub fn sorted_by(compare: fn(&V, &V) -> Ordering) -> OrderedMap<V> {
+        OrderedMap{
+            compare: Some(compare),
+            ..OrderedMap::new()
+        }
+    }
+
+    pub fn insert(&mut self, key: String, value: V) {
+        let position = match self.entries.get(&key) {
+            Some((position, _)) => *position,
+            None => {
+                self.next += 1;
+                self.next
+            }
+        };
+        self.entries.insert(key, (position, value));
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.entries.get(key).map(|(_, v)| v)
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.entries.remove(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &V)> {
+        let mut entries = self.entries.entries();
+        match self.compare {
+            Some(compare) => entries.sort_by(|(_, (_, a)), (_, (_, b))| compare(a, b)),
+            None => entries.sort_by_key(|(_, (position, _))| *position),
+        }
+        entries.into_iter().map(|(k, (_, v))| (k, v))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, v)| v)
+    }
+}
@@ -0,0 +1,590 @@
+// A small regular expression engine, supporting literals, `.`, character
+// classes, `\d \w \s` and their negations, anchors, capturing and
+// non-capturing groups, alternation, and greedy or lazy quantifiers. Matching
+// takes time linear in the length of the text for a given regex.
+
+#[derive(Clone, Debug)]
+enum ClassItem {
+    Range(char, char),
+    Digit(bool),
+    Word(bool),
+    Space(bool),
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Char(char),
+    Any,
+    Class(Vec<ClassItem>, bool),
+    Start,
+    End,
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alternation(Vec<Node>),
+    Repeat(Box<Node>, usize, Option<usize>, bool),
+}
+
+// Start and end offsets of each group, group 0 is the whole match
+pub type Captures = Vec<Option<(usize, usize)>>;
+
+#[derive(Clone, Debug)]
+pub struct Regex {
+    source: String,
+    program: Vec<Inst>,
+    group_count: usize,
+}
+
+struct Parser {
+    chars: Vec<char>,
+    offset: usize,
+    group_count: usize,
+}
+
+impl ClassItem {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            ClassItem::Range(from, to) => *from <= c && c <= *to,
+            ClassItem::Digit(negated) => c.is_ascii_digit() != *negated,
+            ClassItem::Word(negated) => (c.is_alphanumeric() || c == '_') != *negated,
+            ClassItem::Space(negated) => c.is_whitespace() != *negated,
+        }
+    }
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.offset).cloned()
+    }
+
+    fn next(&mut self) -> Result<char, String> {
+        match self.peek() {
+            Some(c) => {
+                self.offset += 1;
+                Ok(c)
+            }
+            None => Err(String::from("Unexpected end of regex")),
+        }
+    }
+
+    fn parse_alternation(&mut self) -> Result<Node, String> {
+        let mut branches = vec![try!(self.parse_concat())];
+        while self.peek() == Some('|') {
+            self.offset += 1;
+            branches.push(try!(self.parse_concat()));
+        }
+        if branches.len() == 1 {
+            Ok(branches.pop().unwrap())
+        } else {
+            Ok(Node::Alternation(branches))
+        }
+    }
+
+    fn parse_concat(&mut self) -> Result<Node, String> {
+        let mut nodes = vec![];
+        loop {
+            match self.peek() {
+                None | Some('|') | Some(')') => break,
+                _ => {
+                    let atom = try!(self.parse_atom());
+                    let node = try!(self.parse_quantifier(atom));
+                    nodes.push(node);
+                }
+            }
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    // Reads a number for `{n,m}`, or None if there are no digits
+    fn parse_count(&mut self) -> Option<usize> {
+        let start = self.offset;
+        while self.peek().map_or(false, |c| c.is_ascii_digit()) {
+            self.offset += 1;
+        }
+        self.chars[start..self.offset].iter().collect::<String>().parse().ok()
+    }
+
+    fn parse_quantifier(&mut self, atom: Node) -> Result<Node, String> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                let start = self.offset;
+                self.offset += 1;
+                let min = self.parse_count();
+                let max = if self.peek() == Some(',') {
+                    self.offset += 1;
+                    self.parse_count()
+                } else {
+                    min
+                };
+                match (min, self.peek()) {
+                    (Some(min), Some('}')) => (min, max),
+                    _ => {
+                        // Not a quantifier after all, `{` is a literal
+                        self.offset = start;
+                        return Ok(atom)
+                    }
+                }
+            }
+            _ => return Ok(atom),
+        };
+        self.offset += 1;
+        let greedy = if self.peek() == Some('?') {
+            self.offset += 1;
+            false
+        } else {
+            true
+        };
+        Ok(Node::Repeat(Box::new(atom), min, max, greedy))
+    }
+
+    fn parse_escape(&mut self) -> Result<ClassItem, String> {
+        let c = try!(self.next());
+        Ok(match c {
+            'd' => ClassItem::Digit(false),
+            'D' => ClassItem::Digit(true),
+            'w' => ClassItem::Word(false),
+            'W' => ClassItem::Word(true),
+            's' => ClassItem::Space(false),
+            'S' => ClassItem::Space(true),
+            'n' => ClassItem::Range('\n', '\n'),
+            't' => ClassItem::Range('\t', '\t'),
+            _ => ClassItem::Range(c, c),
+        })
+    }
+
+    fn parse_class(&mut self) -> Result<Node, String> {
+        let negated = if self.peek() == Some('^') {
+            self.offset += 1;
+            true
+        } else {
+            false
+        };
+        let mut items = vec![];
+        loop {
+            let c = try!(self.next());
+            let item = match c {
+                ']' => break,
+                '\\' => try!(self.parse_escape()),
+                _ => ClassItem::Range(c, c),
+            };
+            match item {
+                ClassItem::Range(from, _)
+                    if self.peek() == Some('-')
+                    && self.chars.get(self.offset + 1).map_or(false, |c| *c != ']') => {
+                    self.offset += 1;
+                    let to = try!(self.next());
+                    items.push(ClassItem::Range(from, to));
+                }
+                _ => items.push(item),
+            }
+        }
+        Ok(Node::Class(items, negated))
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, String> {
+        let c = try!(self.next());
+        match c {
+            '(' => {
+                let index = if self.chars[self.offset..].starts_with(&['?', ':']) {
+                    self.offset += 2;
+                    None
+                } else {
+                    self.group_count += 1;
+                    Some(self.group_count)
+                };
+                let inner = try!(self.parse_alternation());
+                if try!(self.next()) != ')' {
+                    return Err(String::from("Unclosed group in regex"))
+                }
+                Ok(Node::Group(Box::new(inner), index))
+            }
+            '[' => self.parse_class(),
+            '.' => Ok(Node::Any),
+            '^' => Ok(Node::Start),
+            '$' => Ok(Node::End),
+            '\\' => match try!(self.parse_escape()) {
+                ClassItem::Range(c, _) => Ok(Node::Char(c)),
+                item => Ok(Node::Class(vec![item], false)),
+            },
+            '*' | '+' | '?' => Err(format!("Nothing to repeat at {} in regex", self.offset - 1)),
+            _ => Ok(Node::Char(c)),
+        }
+    }
+}
+
+// The parsed regex is compiled to a program for a Pike VM, which runs all
+// alternatives in step over the text instead of backtracking. Every
+// instruction runs at most once per position, and the threads are kept in
+// priority order, so the first one to reach Match wins the way it would with
+// backtracking.
+#[derive(Clone, Debug)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Vec<ClassItem>, bool),
+    Start,
+    End,
+    // Records the position in a capture slot, group n uses slots 2n and 2n+1
+    Save(usize),
+    // Prefers the first target over the second one
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+// Repeats with large counts are expanded into copies of their body
+const MAX_PROGRAM: usize = 100000;
+
+struct Program {
+    insts: Vec<Inst>,
+}
+
+type Slots = Vec<Option<usize>>;
+
+// The threads waiting to match at one position, in priority order, and the
+// instructions that already ran there
+struct Threads {
+    threads: Vec<(usize, Slots)>,
+    seen: Vec<usize>,
+    generation: usize,
+}
+
+// Following the empty transitions from an instruction, without recursing
+enum Step {
+    Add(usize),
+    RestoreSlot(usize, Option<usize>),
+}
+
+impl Threads {
+    fn new(size: usize) -> Threads {
+        Threads{threads: vec![], seen: vec![0; size], generation: 1}
+    }
+
+    fn clear(&mut self) {
+        self.threads.clear();
+        self.generation += 1;
+    }
+
+    // Whether the instruction ran here before, marking it if not
+    fn visit(&mut self, pc: usize) -> bool {
+        let seen = self.seen[pc] == self.generation;
+        self.seen[pc] = self.generation;
+        seen
+    }
+}
+
+impl Program {
+    fn emit(&mut self, inst: Inst) -> Result<usize, String> {
+        if MAX_PROGRAM <= self.insts.len() {
+            return Err(String::from("Regex is too large"))
+        }
+        self.insts.push(inst);
+        Ok(self.insts.len() - 1)
+    }
+
+    // Points a Split or Jump at a target that was not known yet
+    fn patch(&mut self, at: usize, target: usize, second: bool) {
+        match &mut self.insts[at] {
+            Inst::Split(_, b) if second => *b = target,
+            Inst::Split(a, _) => *a = target,
+            Inst::Jump(a) => *a = target,
+            _ => unreachable!(),
+        }
+    }
+
+    // A split that prefers the body over skipping it if greedy
+    fn emit_split(&mut self, greedy: bool) -> Result<usize, String> {
+        let next = self.insts.len() + 1;
+        if greedy {
+            self.emit(Inst::Split(next, 0))
+        } else {
+            self.emit(Inst::Split(0, next))
+        }
+    }
+
+    fn compile(&mut self, node: &Node) -> Result<(), String> {
+        match node {
+            Node::Char(c) => { try!(self.emit(Inst::Char(*c))); }
+            Node::Any => { try!(self.emit(Inst::Any)); }
+            Node::Class(items, negated) => { try!(self.emit(Inst::Class(items.clone(), *negated))); }
+            Node::Start => { try!(self.emit(Inst::Start)); }
+            Node::End => { try!(self.emit(Inst::End)); }
+            Node::Concat(nodes) => for node in nodes {
+                try!(self.compile(node));
+            },
+            Node::Alternation(branches) => {
+                let mut jumps = vec![];
+                for (i, branch) in branches.iter().enumerate() {
+                    if i + 1 == branches.len() {
+                        try!(self.compile(branch));
+                        break
+                    }
+                    let split = try!(self.emit_split(true));
+                    try!(self.compile(branch));
+                    jumps.push(try!(self.emit(Inst::Jump(0))));
+                    let next = self.insts.len();
+                    self.patch(split, next, true);
+                }
+                let end = self.insts.len();
+                for jump in jumps {
+                    self.patch(jump, end, false);
+                }
+            }
+            Node::Group(inner, None) => try!(self.compile(inner)),
+            Node::Group(inner, Some(index)) => {
+                try!(self.emit(Inst::Save(2 * index)));
+                try!(self.compile(inner));
+                try!(self.emit(Inst::Save(2 * index + 1)));
+            }
+            Node::Repeat(inner, min, max, greedy) => {
+                for _ in 0..*min {
+                    try!(self.compile(inner));
+                }
+                match max {
+                    // Each optional iteration can be skipped, which skips
+                    // the ones after it as well
+                    Some(max) => {
+                        let mut skips = vec![];
+                        for _ in *min..*max {
+                            skips.push(try!(self.emit_split(*greedy)));
+                            try!(self.compile(inner));
+                        }
+                        let end = self.insts.len();
+                        for skip in skips {
+                            self.patch(skip, end, *greedy);
+                        }
+                    }
+                    None => {
+                        let split = try!(self.emit_split(*greedy));
+                        try!(self.compile(inner));
+                        try!(self.emit(Inst::Jump(split)));
+                        let end = self.insts.len();
+                        self.patch(split, end, *greedy);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, String> {
+        let mut parser = Parser{
+            chars: pattern.chars().collect(),
+            offset: 0,
+            group_count: 0,
+        };
+        let node = try!(parser.parse_alternation());
+        if parser.offset < parser.chars.len() {
+            return Err(format!("Unmatched ) at {} in regex", parser.offset))
+        }
+        let mut program = Program{insts: vec![]};
+        try!(program.compile(&node));
+        try!(program.emit(Inst::Match));
+        Ok(Regex{
+            source: String::from(pattern),
+            program: program.insts,
+            group_count: parser.group_count,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    // Finds the leftmost match starting at or after start
+    pub fn captures_at(&self, text: &[char], start: usize) -> Option<Captures> {
+        let mut current = Threads::new(self.program.len());
+        let mut next = Threads::new(self.program.len());
+        let mut steps = vec![];
+        let mut matched: Option<Slots> = None;
+        let mut pos = start;
+        loop {
+            if matched.is_none() {
+                // A match starting here, behind all those that started
+                // further left
+                let mut slots = vec![None; 2 * (self.group_count + 1)];
+                slots[0] = Some(pos);
+                self.add_thread(&mut current, &mut steps, 0, pos, text, &mut slots);
+            }
+            if current.threads.is_empty() {
+                break
+            }
+            for (pc, mut slots) in std::mem::replace(&mut current.threads, vec![]) {
+                let consumed = match &self.program[pc] {
+                    Inst::Char(c) => pos < text.len() && text[pos] == *c,
+                    Inst::Any => pos < text.len() && text[pos] != '\n',
+                    Inst::Class(items, negated) => {
+                        pos < text.len() && items.iter().any(|i| i.matches(text[pos])) != *negated
+                    }
+                    Inst::Match => {
+                        // Threads after this one have a lower priority
+                        slots[1] = Some(pos);
+                        matched = Some(slots);
+                        break
+                    }
+                    _ => unreachable!(),
+                };
+                if consumed {
+                    self.add_thread(&mut next, &mut steps, pc + 1, pos + 1, text, &mut slots);
+                }
+            }
+            if text.len() <= pos {
+                break
+            }
+            std::mem::swap(&mut current, &mut next);
+            next.clear();
+            pos += 1;
+        }
+        matched.map(|slots| slots.chunks(2).map(|s| match (s[0], s[1]) {
+            (Some(from), Some(to)) => Some((from, to)),
+            _ => None,
+        }).collect())
+    }
+
+    // Follows jumps, splits, saves and anchors from pc, adding a thread for
+    // every instruction that consumes a character or matches
+    fn add_thread(&self, threads: &mut Threads, steps: &mut Vec<Step>, pc: usize, pos: usize,
+                  text: &[char], slots: &mut Slots) {
+        steps.push(Step::Add(pc));
+        while let Some(step) = steps.pop() {
+            let pc = match step {
+                Step::Add(pc) => pc,
+                Step::RestoreSlot(slot, value) => {
+                    slots[slot] = value;
+                    continue
+                }
+            };
+            if threads.visit(pc) {
+                continue
+            }
+            match &self.program[pc] {
+                Inst::Jump(target) => steps.push(Step::Add(*target)),
+                Inst::Split(first, second) => {
+                    steps.push(Step::Add(*second));
+                    steps.push(Step::Add(*first));
+                }
+                Inst::Save(slot) => {
+                    steps.push(Step::RestoreSlot(*slot, slots[*slot]));
+                    slots[*slot] = Some(pos);
+                    steps.push(Step::Add(pc + 1));
+                }
+                Inst::Start => if pos == 0 {
+                    steps.push(Step::Add(pc + 1));
+                },
+                Inst::End => if pos == text.len() {
+                    steps.push(Step::Add(pc + 1));
+                },
+                _ => threads.threads.push((pc, slots.clone())),
+            }
+        }
+    }
+
+    pub fn find(&self, text: &str) -> Option<String> {
+        let chars: Vec<char> = text.chars().collect();
+        self.captures_at(&chars, 0)
+            .map(|c| c[0].map(|(s, e)| chars[s..e].iter().collect()).unwrap())
+    }
+
+    pub fn captures(&self, text: &str) -> Option<Vec<Option<String>>> {
+        let chars: Vec<char> = text.chars().collect();
+        self.captures_at(&chars, 0).map(|captures| {
+            captures.iter()
+                .map(|c| c.map(|(s, e)| chars[s..e].iter().collect()))
+                .collect()
+        })
+    }
+
+    // All non-overlapping matches, left to right
+    fn all_matches(&self, chars: &[char]) -> Vec<Captures> {
+        let mut matches = vec![];
+        let mut start = 0;
+        while start <= chars.len() {
+            match self.captures_at(chars, start) {
+                Some(captures) => {
+                    let (s, e) = captures[0].unwrap();
+                    // Step over empty matches so we make progress
+                    start = if s == e { e + 1 } else { e };
+                    matches.push(captures);
+                }
+                None => break,
+            }
+        }
+        matches
+    }
+
+    pub fn split(&self, text: &str) -> Vec<String> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut parts = vec![];
+        let mut last = 0;
+        for captures in self.all_matches(&chars) {
+            let (s, e) = captures[0].unwrap();
+            parts.push(chars[last..s].iter().collect());
+            last = e;
+        }
+        parts.push(chars[last..].iter().collect());
+        parts
+    }
+
+    // $n in the replacement stands for the nth capture group, with $0 being
+    // the whole match, and $$ for a literal $. Groups that did not take part
+    // in the match are replaced with nothing.
+    pub fn replace(&self, text: &str, replacement: &str, all: bool) -> String {
+        let result: Result<String, ()> = self.replace_with(text, all, &mut |groups| {
+            Ok(expand(replacement, groups))
+        });
+        result.unwrap()
+    }
+
+    // Replaces matches with what f returns for their capture groups
+    pub fn replace_with<E>(&self, text: &str, all: bool,
+                           f: &mut dyn FnMut(&[Option<String>]) -> Result<String, E>) -> Result<String, E> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut result = String::new();
+        let mut last = 0;
+        for captures in self.all_matches(&chars) {
+            let (s, e) = captures[0].unwrap();
+            result.extend(&chars[last..s]);
+            let groups: Vec<Option<String>> = captures.iter()
+                .map(|c| c.map(|(s, e)| chars[s..e].iter().collect()))
+                .collect();
+            result.push_str(&try!(f(&groups)));
+            last = e;
+            if !all {
+                break
+            }
+        }
+        result.extend(&chars[last..]);
+        Ok(result)
+    }
+}
+
+fn expand(replacement: &str, groups: &[Option<String>]) -> String {
+    let mut result = String::new();
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            result.push(c);
+            continue
+        }
+        if chars.peek() == Some(&'$') {
+            chars.next();
+            result.push('$');
+            continue
+        }
+        let mut digits = String::new();
+        while let Some(d) = chars.peek().cloned().filter(|d| d.is_ascii_digit()) {
+            digits.push(d);
+            chars.next();
+        }
+        if digits.is_empty() {
+            result.push('$');
+        } else if let Some(Some(group)) = digits.parse::<usize>().ok().and_then(|n| groups.get(n)) {
+            result.push_str(group);
+        }
+    }
+    result
+}
@@ -0,0 +1,269 @@
+use compiler::scanner::{scan_with_trivia, Line, Token, TokenType};
+use compiler::SourceCode;
+
+// Forms longer than this are broken over several lines
+const MAX_WIDTH: usize = 80;
+
+enum Node {
+    // Literals, symbols, keywords and block comments, as written
+    Atom(String),
+    LineComment(String),
+    // The opening and closing delimiter, and the elements
+    Seq(char, char, Vec<Item>),
+    // Quotes, unquotes and datum comments in front of a form
+    Prefixed(String, Box<Node>),
+}
+
+struct Item {
+    node: Node,
+    // Only kept to tell whether a comment trails the line before it, and for
+    // blank lines between top-level forms
+    newlines_before: usize,
+}
+
+// Formats source code in a canonical layout. Forms that fit within the line
+// width go on one line, longer ones get one element per line, with list
+// elements indented by two spaces, and the closing delimiter on a line of
+// its own. Blank lines between top-level forms are kept. Symbols and
+// keywords are lowercased unless case matters, see
+// CompilerConfig::case_sensitive.
+pub fn format_source(source: &str, case_sensitive: bool) -> Result<String, String> {
+    // The scanner expects a trailing character after the last token
+    let source: SourceCode = format!("{}\n", source).chars().collect();
+    let tokens = scan_with_trivia(&source);
+    let mut offset = 0;
+    let items = try!(parse_items(&tokens, &mut offset, &source, case_sensitive, None));
+    let mut result = String::new();
+    for (i, item) in items.iter().enumerate() {
+        if 0 < i {
+            result.push_str(separator(item));
+        }
+        result.push_str(&render(&item.node, 0));
+    }
+    if !result.is_empty() {
+        result.push('\n');
+    }
+    Ok(result)
+}
+
+// Reads items until the closing delimiter, or the end of the source at the
+// top level
+fn parse_items(tokens: &[Token], offset: &mut usize, source: &SourceCode, case_sensitive: bool,
+               close: Option<(char, Line)>) -> Result<Vec<Item>, String> {
+    let mut items = vec![];
+    let mut newlines = 0;
+    loop {
+        let token = &tokens[*offset];
+        match token.token_type {
+            TokenType::Whitespace => {
+                newlines += text(token, source).matches('\n').count();
+                *offset += 1;
+                continue
+            }
+            TokenType::EOF => return match close {
+                Some((_, line)) => Err(format!("Unclosed delimiter from line {}", line)),
+                None => Ok(items),
+            },
+            TokenType::CloseParenthesis | TokenType::CloseBracket | TokenType::CloseBrace => {
+                let c = source[token.start];
+                return match close {
+                    Some((expected, _)) if expected == c => {
+                        *offset += 1;
+                        Ok(items)
+                    }
+                    _ => Err(format!("Unexpected {} on line {}", c, token.line)),
+                }
+            }
+            _ => (),
+        }
+        let node = try!(parse_node(tokens, offset, source, case_sensitive));
+        items.push(Item{node: node, newlines_before: newlines});
+        newlines = 0;
+    }
+}
+
+fn parse_node(tokens: &[Token], offset: &mut usize, source: &SourceCode, case_sensitive: bool)
+              -> Result<Node, String> {
+    let token = &tokens[*offset];
+    *offset += 1;
+    let close = match token.token_type {
+        TokenType::OpenParenthesis => ')',
+        TokenType::OpenBracket => ']',
+        TokenType::OpenBrace => '}',
+        TokenType::Quote | TokenType::Quasiquote | TokenType::Unquote
+            | TokenType::UnquoteSplicing | TokenType::DatumComment => {
+            while tokens[*offset].token_type == TokenType::Whitespace {
+                *offset += 1;
+            }
+            return match tokens[*offset].token_type {
+                TokenType::EOF | TokenType::LineComment(_) | TokenType::CloseParenthesis
+                    | TokenType::CloseBracket | TokenType::CloseBrace =>
+                    Err(format!("Expected a form after {} on line {}", text(token, source), token.line)),
+                _ => {
+                    let node = try!(parse_node(tokens, offset, source, case_sensitive));
+                    Ok(Node::Prefixed(text(token, source), Box::new(node)))
+                }
+            }
+        }
+        TokenType::LineComment(_) => return Ok(Node::LineComment(String::from(text(token, source).trim_end()))),
+        TokenType::Symbol | TokenType::Keyword if !case_sensitive && source[token.start] != '|' =>
+            return Ok(Node::Atom(text(token, source).to_lowercase())),
+        TokenType::Error(_) => return Err(format!("Lexing error on line {}: {}", token.line, token.token_type)),
+        _ => return Ok(Node::Atom(text(token, source))),
+    };
+    let open = source[token.start];
+    let items = try!(parse_items(tokens, offset, source, case_sensitive, Some((close, token.line))));
+    Ok(Node::Seq(open, close, items))
+}
+
+fn text(token: &Token, source: &SourceCode) -> String {
+    let (start, end) = token.span();
+    source[start..end].iter().collect()
+}
+
+// What goes before an item that is not the first at the top level
+fn separator(item: &Item) -> &'static str {
+    match (&item.node, item.newlines_before) {
+        (Node::LineComment(_), 0) => " ",
+        (_, 0) | (_, 1) => "\n",
+        _ => "\n\n",
+    }
+}
+
+// The node on a single line, if it can go on one
+fn flat(node: &Node) -> Option<String> {
+    match node {
+        Node::Atom(s) if !s.contains('\n') => Some(s.clone()),
+        Node::Atom(_) | Node::LineComment(_) => None,
+        Node::Seq(open, close, items) => items.iter()
+            .map(|item| flat(&item.node))
+            .collect::<Option<Vec<String>>>()
+            .map(|parts| format!("{}{}{}", open, parts.join(" "), close)),
+        Node::Prefixed(prefix, node) => flat(node).map(|s| format!("{}{}", prefix, s)),
+    }
+}
+
+// The node starting at the given column
+fn render(node: &Node, column: usize) -> String {
+    match node {
+        Node::Atom(s) | Node::LineComment(s) => s.clone(),
+        Node::Prefixed(prefix, node) => format!("{}{}", prefix, render(node, column + prefix.chars().count())),
+        Node::Seq(open, close, items) => match flat(node) {
+            Some(s) if items.is_empty() || column + s.chars().count() <= MAX_WIDTH => s,
+            _ => render_broken(*open, *close, items, column),
+        },
+    }
+}
+
+fn render_broken(open: char, close: char, items: &[Item], column: usize) -> String {
+    // Vector and map elements line up with the first one
+    let indent = if open == '(' { column + 2 } else { column + 1 };
+    let mut result = open.to_string();
+    let mut i = 0;
+    while i < items.len() {
+        let item = &items[i];
+        if is_comment(&item.node) && item.newlines_before == 0 {
+            // Comments stay at the end of the line they were on
+            if 0 < i {
+                result.push(' ');
+            }
+        } else if 0 < i {
+            result.push('\n');
+            result.push_str(&" ".repeat(indent));
+        }
+        let start = last_line_width(&result, column);
+        result.push_str(&render(&item.node, start));
+        i += 1;
+        // The head of a list and a simple first argument, like the name of a
+        // definition, stay on the opening line, and maps keep each key and
+        // value together
+        let joined = match (open, &item.node, items.get(i)) {
+            (_, _, None) => false,
+            (_, _, Some(next)) if is_comment(&item.node) || is_comment(&next.node) => false,
+            ('(', Node::Atom(_), Some(Item{node: Node::Atom(_), ..})) => i == 1,
+            ('{', _, _) => true,
+            _ => false,
+        };
+        if joined {
+            result.push(' ');
+            let start = last_line_width(&result, column);
+            result.push_str(&render(&items[i].node, start));
+            i += 1;
+        }
+    }
+    result.push('\n');
+    result.push_str(&" ".repeat(column));
+    result.push(close);
+    result
+}
+
+fn is_comment(node: &Node) -> bool {
+    match node {
+        Node::LineComment(_) => true,
+        _ => false,
+    }
+}
+
+// The column after the last line of a rendering that started at the given
+// column
+fn last_line_width(s: &str, column: usize) -> usize {
+    match s.rfind('\n') {
+        Some(i) => s[i + 1..].chars().count(),
+        None => column + s.chars().count(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fmt(source: &str) -> String {
+        format_source(source, true).unwrap()
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let source = "(defn foo (x) ; trailing\n  #| block |# (+ x 1) #_ (ignored 1) bar)\n\n\n\
+                      (def m {:a 1 :b [1 2 3]}) '(a `(b ~c ~@d))\n\
+                      (def long-name (list \"aaaaaaaaaaaaaaaaaaaa\" \"bbbbbbbbbbbbbbbbbbbbbbbb\" \"cccccccccccccccccccccccc\"))";
+        let once = fmt(source);
+        assert_eq!(fmt(&once), once);
+    }
+
+    #[test]
+    fn long_forms_are_broken_at_the_line_width() {
+        let short = format!("(list {})", "a".repeat(MAX_WIDTH - 7));
+        assert_eq!(fmt(&short), format!("{}\n", short));
+        let long = format!("(list {} b)", "a".repeat(MAX_WIDTH - 8));
+        assert_eq!(fmt(&long), format!("(list {}\n  b\n)\n", "a".repeat(MAX_WIDTH - 8)));
+        assert_eq!(fmt(&format!("(def x (f \"{}\" 1))", "a".repeat(70))),
+                   format!("(def x\n  (f \"{}\" 1)\n)\n", "a".repeat(70)));
+    }
+
+    #[test]
+    fn trailing_comments_stay_on_their_line() {
+        assert_eq!(fmt("(def x ; the x\n  1) ; done\n; next\n(def y 2)"),
+                   "(def x ; the x\n  1\n) ; done\n; next\n(def y 2)\n");
+    }
+
+    #[test]
+    fn block_and_datum_comments_are_kept() {
+        assert_eq!(fmt("(+ 1 #| two |# 3)"), "(+ 1 #| two |# 3)\n");
+        assert_eq!(fmt("(+ 1 #_  2 3)"), "(+ 1 #_2 3)\n");
+        assert_eq!(fmt("#| a\nb |#\n(f)"), "#| a\nb |#\n(f)\n");
+    }
+
+    #[test]
+    fn symbols_are_only_lowercased_without_case_sensitivity() {
+        let source = "(DEFN Foo [X] \"Bar\" :Baz)";
+        assert_eq!(format_source(source, true).unwrap(), "(DEFN Foo [X] \"Bar\" :Baz)\n");
+        assert_eq!(format_source(source, false).unwrap(), "(defn foo [x] \"Bar\" :baz)\n");
+    }
+
+    #[test]
+    fn unbalanced_delimiters_are_errors() {
+        assert_eq!(format_source("(f\n  (g [1 2)", true), Err(String::from("Unexpected ) on line 2")));
+        assert_eq!(format_source("\n(f\n  (g 1)", true), Err(String::from("Unclosed delimiter from line 2")));
+        assert_eq!(format_source("(f))", true), Err(String::from("Unexpected ) on line 1")));
+    }
+}
@@ -5,12 +5,17 @@ use std::io::Write;
 use std::fs::File;
 
 mod compiler;
+mod formatter;
+mod repl;
 
-use compiler::interpret;
+use compiler::{interpret, CompilerConfig};
+use compiler::scanner::tokenise;
 use compiler::vm::init_vm;
+use formatter::format_source;
+use repl::Repl;
 
-fn repl(debug: bool) -> Result<()> {
-    let mut vm = init_vm();
+fn repl(config: CompilerConfig, debug: bool) -> Result<()> {
+    let mut repl = Repl::with_config(config.with_debug(debug).with_optimisation(true));
     loop {
         print!("> ");
         let _ = std::io::stdout().flush();
@@ -20,21 +25,36 @@ fn repl(debug: bool) -> Result<()> {
             println!("");
             break;
         }
-        match interpret(&mut vm, input, debug) {
+        // `:doc foo` shows the docstring of foo
+        let input = if input.trim().starts_with(":doc ") {
+            format!("(print (doc {}))", &input.trim()[5..])
+        } else {
+            input
+        };
+        match repl.eval_str(&input) {
+            Ok(value) => println!("{:?}", value),
             Err(msg) => println!("{}", msg),
-            _ => (),
         }
     }
     Ok(())
 }
 
-fn run_file(path: &String, debug: bool) -> Result<()> {
+fn run_file(config: CompilerConfig, path: &String, debug: bool, profile: bool) -> Result<()> {
     let file = File::open(path)?;
     let mut buf_reader = BufReader::new(file);
     let mut source = String::new();
     buf_reader.read_to_string(&mut source)?;
+    let config = config
+        .with_debug(debug)
+        .with_optimisation(true)
+        .with_file(path);
     let mut vm = init_vm();
-    match interpret(&mut vm, source, debug) {
+    vm.set_profile_mode(profile);
+    let result = interpret(&mut vm, source, &config);
+    if profile {
+        vm.print_profile();
+    }
+    match result {
         Ok(_) => Ok(()),
         Err(msg) => {
             println!("{}", msg);
@@ -43,38 +63,73 @@ fn run_file(path: &String, debug: bool) -> Result<()> {
     }
 }
 
-fn usage() -> Result<()> {
+fn format_file(config: CompilerConfig, path: &String) -> Result<()> {
+    let mut source = String::new();
+    File::open(path)?.read_to_string(&mut source)?;
+    match format_source(&source, config.case_sensitive) {
+        Ok(formatted) => {
+            print!("{}", formatted);
+            Ok(())
+        }
+        Err(msg) => {
+            println!("{}", msg);
+            std::process::exit(65);
+        }
+    }
+}
+
+// One token per line, with its line number and span, for debugging the scanner
+fn print_tokens(path: &String) -> Result<()> {
+    let mut source = String::new();
+    File::open(path)?.read_to_string(&mut source)?;
+    let chars: Vec<char> = source.chars().collect();
+    for token in tokenise(&source) {
+        let (start, end) = token.span();
+        let text: String = chars[start.min(chars.len())..end.min(chars.len())].iter().collect();
+        println!("{:4} {:5}..{:<5} {:20} {:?}", token.line, start, end, token.token_type.to_string(), text);
+    }
+    Ok(())
+}
+
+fn usage() -> ! {
     let name = "losp";
     println!("usage:");
-    println!("{} repl         - start repl", name);
-    println!("{} depl         - start debug repl", name);
-    println!("{} run <file>   - run file", name);
-    println!("{} debug <file> - debug file", name);
+    println!("{} repl           - start repl", name);
+    println!("{} depl           - start debug repl", name);
+    println!("{} run <file>     - run file", name);
+    println!("{} debug <file>   - debug file", name);
+    println!("{} profile <file> - run file and count executed opcodes", name);
+    println!("{} fmt <file>     - print file formatted", name);
+    println!("{} tokens <file>  - print the tokens of a file", name);
+    println!("options:");
+    println!("--ignore-case         - read symbols and keywords in lowercase");
+    println!("--max-constants <n>   - limit the constants per function");
     std::process::exit(64)
 }
 
 fn main() -> Result<()> {
-    let mut opts = std::env::args();
-    if opts.len() < 2 {
-        let _ = usage();
-    };
-    match opts.nth(1).unwrap().as_str() {
-        "repl" => repl(false),
-        "depl" => repl(true),
-        "run" => {
-            if opts.len() == 1 {
-                run_file(&opts.last().unwrap(), false)
-            } else {
-                usage()
-            }
-        }
-        "debug" => {
-            if opts.len() == 1 {
-                run_file(&opts.last().unwrap(), true)
-            } else {
-                usage()
-            }
+    // Options can go anywhere, like `losp run --ignore-case file.losp`
+    let mut config = CompilerConfig::new();
+    let mut args = vec![];
+    let mut opts = std::env::args().skip(1);
+    while let Some(arg) = opts.next() {
+        match arg.as_str() {
+            "--ignore-case" => config = config.with_case_sensitivity(false),
+            "--max-constants" => match opts.next().and_then(|n| n.parse().ok()) {
+                Some(max) => config = config.with_max_constants(max),
+                None => usage(),
+            },
+            _ => args.push(arg),
         }
+    }
+    match (args.get(0).map(String::as_str), args.len()) {
+        (Some("repl"), _) => repl(config, false),
+        (Some("depl"), _) => repl(config, true),
+        (Some("run"), 2) => run_file(config, &args[1], false, false),
+        (Some("debug"), 2) => run_file(config, &args[1], true, false),
+        (Some("profile"), 2) => run_file(config, &args[1], false, true),
+        (Some("fmt"), 2) => format_file(config, &args[1]),
+        (Some("tokens"), 2) => print_tokens(&args[1]),
         _ => usage(),
     }
 }
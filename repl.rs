@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+
+use compiler::{compile_str, read_all, CompilerConfig, CompilerMode};
+use compiler::vm::{init_vm, Value, VM};
+
+// An evaluator for embedding, which keeps its globals between evaluations
+pub struct Repl {
+    vm: VM,
+    config: CompilerConfig,
+}
+
+impl Repl {
+    #[allow(dead_code)]
+    pub fn new() -> Repl {
+        Repl::with_config(CompilerConfig::new())
+    }
+
+    pub fn with_config(config: CompilerConfig) -> Repl {
+        let mut vm = init_vm();
+        vm.set_debug(config.debug);
+        Repl{
+            vm: vm,
+            config: config.with_mode(CompilerMode::Repl),
+        }
+    }
+
+    // Evaluates a single expression
+    #[allow(dead_code)]
+    pub fn eval_line(&mut self, input: &str) -> Result<Value, String> {
+        let forms = try!(read_all(input)).len();
+        if forms != 1 {
+            return Err(format!("Expected a single expression, got {}", forms))
+        }
+        self.eval_str(input)
+    }
+
+    // Evaluates any number of expressions, returning the value of the last
+    pub fn eval_str(&mut self, input: &str) -> Result<Value, String> {
+        let debug = self.config.debug;
+        // The scanner expects a trailing character after the last token
+        let result = compile_str(&format!("{}\n", input), &self.config)
+            .and_then(|chunk| self.vm.interpret(chunk, debug))
+            .map(|value| value.unwrap_or(Value::Nil));
+        if result.is_err() {
+            // Don't let a failed evaluation leak into the next one
+            self.vm.reset_stack();
+        }
+        result
+    }
+
+    #[allow(dead_code)]
+    pub fn get_globals(&self) -> HashMap<String, Value> {
+        self.vm.globals()
+    }
+
+    // Forgets all globals
+    #[allow(dead_code)]
+    pub fn reset(&mut self) {
+        self.vm.reset_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(value: Result<Value, String>) -> i64 {
+        match value {
+            Ok(Value::Int(i)) => i,
+            other => panic!("expected an integer, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_failed_evaluation_leaves_a_clean_stack() {
+        let mut repl = Repl::new();
+        assert!(repl.eval_str("(defn double [x] (* x 2))").is_ok());
+        let error = repl.eval_str("(+ 1 (double :a))").unwrap_err();
+        assert!(error.contains("at double"), "{}", error);
+        assert!(repl.vm.is_idle());
+        let error = repl.eval_str("(try (+ 1 (double (raise :oops))) (catch e (double :a)))").unwrap_err();
+        assert!(error.contains("at double"), "{}", error);
+        assert!(repl.vm.is_idle());
+    }
+
+    #[test]
+    fn an_unfinished_if_is_an_error() {
+        let mut repl = Repl::new();
+        assert!(repl.eval_str("(if 1").is_err());
+        assert!(repl.eval_str("(if 1 2").is_err());
+        assert!(repl.eval_str("(if 1 2 3").is_err());
+        assert_eq!(int(repl.eval_str("(if 1 2 3)")), 2);
+    }
+
+    #[test]
+    fn definitions_survive_a_failed_evaluation() {
+        let mut repl = Repl::new();
+        assert!(repl.eval_str("(defn double [x] (* x 2))").is_ok());
+        assert!(repl.eval_str("(double :a)").is_err());
+        assert!(repl.eval_line("(double").is_err());
+        assert_eq!(int(repl.eval_line("(double 21)")), 42);
+        assert_eq!(int(repl.eval_str("(def x (double 1)) (double x)")), 4);
+    }
+}